@@ -1,35 +1,138 @@
-use crate::types::{IntegerType, IntegerValue, StringValue, Value};
+use crate::diagnostic::Span;
+use crate::types::{FloatValue, IntegerValue, StaticType, StringValue};
 
-#[derive(Debug)]
-pub enum Expr {
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    // 语义分析推断出的类型，分析之前为 Unknown
+    pub ty: StaticType,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
     Number(IntegerValue),
     TypedNumber(IntegerValue),
+    Float(FloatValue),
+    Str(StringValue),
+    Bool(bool),
     Ident(String),
+    Unary(UnaryOp, Box<Expr>),
     BinOp(Box<Expr>, BinOpType, Box<Expr>),
     Call(String, Vec<Expr>),
+    // 列表字面量 `[a, b, c]`
+    List(Vec<Expr>),
+    // 映射字面量 `{k: v, ...}`，按书写顺序保存键值对
+    Map(Vec<(Expr, Expr)>),
+    // 下标访问 `a[i]`
+    Index(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span, ty: StaticType::Unknown }
+    }
 }
 
-#[derive(Debug, Hash)]
+#[derive(Debug, Hash, Clone, Copy)]
 pub enum BinOpType {
     Plus,
     Minus,
     Mul,
+    Div,
     Mod,
     Le,
     Lt,
     Gt,
     Ge,
     Eq,
+    NotEq,
+    // 位运算，按算术运算的规则提升类型
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+    // 短路求值，结果为 bool
+    And,
+    Or,
+}
+
+impl BinOpType {
+    // 运算符在源码中的写法，用于诊断
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOpType::Plus => "+",
+            BinOpType::Minus => "-",
+            BinOpType::Mul => "*",
+            BinOpType::Div => "/",
+            BinOpType::Mod => "%",
+            BinOpType::Le => "<=",
+            BinOpType::Lt => "<",
+            BinOpType::Gt => ">",
+            BinOpType::Ge => ">=",
+            BinOpType::Eq => "==",
+            BinOpType::NotEq => "!=",
+            BinOpType::BitAnd => "&",
+            BinOpType::BitOr => "|",
+            BinOpType::BitXor => "^",
+            BinOpType::Shl => "<<",
+            BinOpType::Shr => ">>",
+            BinOpType::And => "and",
+            BinOpType::Or => "or",
+        }
+    }
+}
+
+#[derive(Debug, Hash, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
 }
 
 #[derive(Debug)]
-pub enum Stmt {
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum StmtKind {
     Assign(String, Expr),
-    If(Expr, Vec<Stmt>),
+    // 下标赋值 `a[i] = v`：容器、下标、新值
+    IndexAssign(Expr, Expr, Expr),
+    // 条件、then 分支、else 分支；`elif` 链展开为 else 分支中嵌套的 If
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
     While(Expr, Vec<Stmt>),
+    // 循环变量、遍历对象、循环体
+    For(String, ForTarget, Vec<Stmt>),
+    // 跳出或进入下一轮最内层的循环
+    Break,
+    Continue,
+    // 函数中 `global a, b`：对这些名字的赋值写入模块级变量
+    Global(Vec<String>),
     Return(Expr),
     Out(Expr),
-    FuncDef(String, Vec<String>, Vec<Stmt>),
+    // 只求值不输出，用于单独一行的内置函数调用，如 `append a, 1`
+    Expr(Expr),
+    // 函数名、形参、各形参的位置、函数体、注解（如 `@memo`）
+    FuncDef(String, Vec<String>, Vec<Span>, Vec<Stmt>, Vec<String>),
+}
+
+// `for` 循环遍历的对象
+#[derive(Debug)]
+pub enum ForTarget {
+    // 半开区间 `start..end`
+    Range(Expr, Expr),
+    // 列表的元素或映射的键
+    Each(Expr),
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,116 @@
+// 内置函数，字节码虚拟机和树遍历解释器共用
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use crate::diagnostic::{Diagnostic, ErrorKind, Stage};
+use crate::types::{IntegerType, IntegerValue, ListValue, StaticType, Value};
+
+type BuiltinFn = fn(Vec<Value>) -> Result<Value, Diagnostic>;
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub arity: usize,
+    pub result: StaticType,  // 返回值的静态类型，供类型推断使用
+    func: BuiltinFn,
+}
+
+impl Builtin {
+    // 调用前检查实参个数
+    pub fn call(&self, args: Vec<Value>) -> Result<Value, Diagnostic> {
+        if args.len() != self.arity {
+            return Err(Diagnostic::new(
+                Stage::Runtime,
+                ErrorKind::ArityMismatch,
+                format!("Parameter count mismatch: `{}` expects {} parameters, got {}", self.name, self.arity, args.len()),
+            ));
+        }
+        (self.func)(args)
+    }
+}
+
+// 按名称查找内置函数；同名的用户函数优先，由调用方判断
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    static BUILTINS: OnceLock<HashMap<&'static str, Builtin>> = OnceLock::new();
+    let map = BUILTINS.get_or_init(|| {
+        let mut map = HashMap::new();
+        for builtin in [
+            Builtin { name: "len", arity: 1, result: StaticType::Integer(Some(IntegerType::I64)), func: len },
+            Builtin { name: "append", arity: 2, result: StaticType::Integer(Some(IntegerType::I64)), func: append },
+            Builtin { name: "pop", arity: 1, result: StaticType::Unknown, func: pop },
+            Builtin { name: "contains", arity: 2, result: StaticType::Bool, func: contains },
+            Builtin { name: "remove", arity: 2, result: StaticType::Unknown, func: remove },
+            Builtin { name: "keys", arity: 1, result: StaticType::List, func: keys },
+        ] {
+            map.insert(builtin.name, builtin);
+        }
+        map
+    });
+    map.get(name)
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    lookup(name).is_some()
+}
+
+fn expected(name: &str, what: &str, found: &Value) -> Diagnostic {
+    Diagnostic::new(
+        Stage::Runtime,
+        ErrorKind::TypeMismatch,
+        format!("`{}` expects {}, found {}", name, what, found.type_name()),
+    )
+}
+
+// 列表的元素个数、映射的键数或字符串的字符数
+fn len(args: Vec<Value>) -> Result<Value, Diagnostic> {
+    let len = match &args[0] {
+        Value::List(list) => list.len(),
+        Value::Map(map) => map.len(),
+        Value::String(s) => s.as_str().chars().count(),
+        other => return Err(expected("len", "a list, map or string", other)),
+    };
+    Ok(Value::Integer(IntegerValue::I64(len as i64)))
+}
+
+// 在列表末尾追加元素，返回新的长度
+fn append(mut args: Vec<Value>) -> Result<Value, Diagnostic> {
+    let value = args.pop().unwrap();
+    match &args[0] {
+        Value::List(list) => {
+            list.push(value);
+            Ok(Value::Integer(IntegerValue::I64(list.len() as i64)))
+        }
+        other => Err(expected("append", "a list", other)),
+    }
+}
+
+// 移除并返回列表的最后一个元素
+fn pop(args: Vec<Value>) -> Result<Value, Diagnostic> {
+    match &args[0] {
+        Value::List(list) => list.pop(),
+        other => Err(expected("pop", "a list", other)),
+    }
+}
+
+// 映射中是否存在该键
+fn contains(args: Vec<Value>) -> Result<Value, Diagnostic> {
+    match &args[0] {
+        Value::Map(map) => map.contains(&args[1]).map(Value::Bool),
+        other => Err(expected("contains", "a map", other)),
+    }
+}
+
+// 删除映射中的键并返回对应的值
+fn remove(args: Vec<Value>) -> Result<Value, Diagnostic> {
+    match &args[0] {
+        Value::Map(map) => map.remove(&args[1]),
+        other => Err(expected("remove", "a map", other)),
+    }
+}
+
+// 按插入顺序返回映射的所有键
+fn keys(args: Vec<Value>) -> Result<Value, Diagnostic> {
+    match &args[0] {
+        Value::Map(map) => Ok(Value::List(ListValue::new(map.keys()))),
+        other => Err(expected("keys", "a map", other)),
+    }
+}
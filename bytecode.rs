@@ -1,7 +1,12 @@
-use crate::ast::{Expr, Stmt, BinOpType, Program};
+use crate::ast::{Expr, ExprKind, ForTarget, Stmt, StmtKind, BinOpType, UnaryOp, Program};
+use crate::diagnostic::{Diagnostic, ErrorKind, Span, Stage};
+use crate::jit::{self, JITCompiler};
 use crate::memory::{InterpreterMemoryPool, get_interpreter_pool};
-use crate::types::{IntegerValue, IntegerType, Value, StringValue};
-use std::collections::HashMap;
+use crate::builtins::{self, Builtin};
+use crate::semantic::FunctionScope;
+use crate::types::{IntegerValue, IntegerType, FloatValue, ListValue, MapValue, StaticType, Value, promote_float, range_bounds, type_mismatch};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 // 字节码指令定义
 #[derive(Debug, Clone)]
@@ -10,17 +15,46 @@ pub enum Bytecode {
     LoadConst(Value),      // 加载常量
     LoadVar(String),     // 加载变量
     StoreVar(String),    // 存储变量
+    LoadGlobal(String),  // 在函数中加载模块级变量
+    StoreGlobal(String), // 在函数中存储 `global` 声明的模块级变量
     
     // 二元操作
     Add,                // 加法
     Sub,                // 减法
     Mul,                // 乘法
+    Div,                // 除法（向零取整）
     Mod,                // 取模
     Le,                 // 小于等于
     Lt,                 // 小于
     Gt,                 // 大于
     Ge,                 // 大于等于
     Eq,                 // 等于
+    BitAnd,             // 按位与
+    BitOr,              // 按位或
+    BitXor,             // 按位异或
+    Shl,                // 左移
+    Shr,                // 算术右移
+    Concat,             // 字符串拼接：类型推断确定 `+` 两侧都是字符串
+    AddI64,             // 整数加法：类型推断确定两侧提升后是 i64
+    SubI64,             // 整数减法：同上
+    MulI64,             // 整数乘法：同上
+
+    // 一元操作
+    Neg,                // 取负
+    Not,                // 逻辑非，结果为 bool
+    BitNot,             // 按位取反
+
+    // 列表和映射操作
+    MakeList(usize),    // 弹出 n 个元素组成列表
+    MakeMap(usize),     // 弹出 n 组键值对组成映射
+    Index,              // 下标读取：弹出下标和容器
+    StoreIndex,         // 下标赋值：弹出新值、下标和容器
+    Pop,                // 丢弃栈顶值
+
+    // for 循环
+    RangeInit,          // 弹出区间两端，统一为较宽的整数类型后压回
+    IterInit,           // 弹出被遍历的值，压入要遍历的列表（映射取键列表）
+    IterNext(i32),      // 弹出下标和列表：未越界时压入元素，否则跳转
     
     // 寄存器操作（用于寄存器分配模拟）
     LoadReg(u8, String),   // 加载变量到寄存器
@@ -32,6 +66,7 @@ pub enum Bytecode {
     // 控制流
     Jump(i32),          // 无条件跳转
     JumpIfFalse(i32),   // 条件跳转
+    JumpIfTrue(i32),    // 条件为真时跳转
     Call(String),       // 函数调用
     TailCall(String),   // 尾调用（用于尾递归优化）
     Return,             // 返回
@@ -112,10 +147,13 @@ impl CompactBytecode {
 // 编译后的函数
 #[derive(Debug, Clone)]
 pub struct CompiledFunction {
+    pub name: String,  // 顶层代码为空串
     pub param_str: String,
     pub instructions: Vec<Bytecode>,
+    pub spans: Vec<Span>,  // 每条指令对应的源码位置
     pub param_count: usize,
     pub inline_hint: bool,  // 是否建议内联
+    pub memoize: bool,  // `@memo` 注解：按实参缓存返回值
 }
 
 // 增量编译器
@@ -162,16 +200,20 @@ impl IncrementalCompiler {
         
         // 重新编译
         let body_refs: Vec<&Stmt> = body.iter().collect();
-        let func_code = compile_statements(&body_refs, functions);
+        let (mut func_code, spans) = compile_statements(&body_refs, functions);
+        resolve_globals(&mut func_code, params, body);
         let param_str = params.join(",");
         let param_count = params.len();
         let inline_hint = func_code.len() < 10;
         
         let compiled_func = CompiledFunction {
+            name: name.to_string(),
             param_str,
             instructions: func_code,
+            spans,
             param_count,
             inline_hint,
+            memoize: false,
         };
         
         // 更新缓存
@@ -180,21 +222,83 @@ impl IncrementalCompiler {
     }
 }
 
+// 函数中没有赋值过的名字读取模块级变量，`global` 声明的名字读写模块级变量
+fn resolve_globals(instructions: &mut [Bytecode], params: &[String], body: &[Stmt]) {
+    let scope = FunctionScope::new(params, body);
+    // 包括循环使用的隐藏变量
+    let locals: HashSet<String> = params.iter().cloned()
+        .chain(instructions.iter().filter_map(|instr| match instr {
+            Bytecode::StoreVar(name) if !scope.globals.contains(name) => Some(name.clone()),
+            _ => None,
+        }))
+        .collect();
+    for instr in instructions.iter_mut() {
+        match instr {
+            Bytecode::LoadVar(name) | Bytecode::LoadReg(_, name) if !locals.contains(name) => {
+                *instr = Bytecode::LoadGlobal(std::mem::take(name));
+            }
+            Bytecode::StoreVar(name) if scope.globals.contains(name) => {
+                *instr = Bytecode::StoreGlobal(std::mem::take(name));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn undefined_variable(name: &str) -> Diagnostic {
+    Diagnostic::new(Stage::Runtime, ErrorKind::UndefinedVariable, format!("Undefined variable: {}", name))
+}
+
 // 字节码程序
 #[derive(Debug)]
 pub struct BytecodeProgram {
     pub instructions: Vec<Bytecode>,
+    pub spans: Vec<Span>,  // 顶层指令对应的源码位置
     pub functions: HashMap<String, (String, Vec<Bytecode>)>,
-    pub compiled_functions: HashMap<String, CompiledFunction>,
+    pub compiled_functions: HashMap<String, Rc<CompiledFunction>>,
     pub incremental_compiler: IncrementalCompiler,  // 增量编译器
 }
 
+// 虚拟机配置
+#[derive(Debug, Clone)]
+pub struct VmConfig {
+    pub max_frames: usize,  // 调用帧数上限，超过时报告栈溢出
+    pub jit: bool,  // 热点函数和循环编译为本地代码执行
+    pub jit_threshold: u32,  // 函数调用次数或循环回跳次数达到该值时触发编译
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        VmConfig { max_frames: 10_000, jit: true, jit_threshold: 1_000 }
+    }
+}
+
+// 调用帧：保存调用方的执行状态，被调函数返回时恢复
+struct CallFrame {
+    function: Rc<CompiledFunction>,  // 调用方正在执行的代码
+    return_pc: usize,
+    locals: HashMap<String, Value>,  // 调用方的局部变量
+    stack_base: usize,  // 被调函数操作数栈的起点
+    memo_keys: Vec<MemoKey>,  // 返回时需要写入缓存的记忆化调用
+}
+
+// 记忆化缓存键：函数名和实参
+type MemoKey = (String, Vec<Value>);
+
 // 字节码解释器
 pub struct BytecodeInterpreter {
     stack: Vec<Value>,
-    variables: HashMap<String, Value>,
+    variables: HashMap<String, Value>,  // 当前帧的局部变量
     registers: [Option<Value>; 8],  // 8个虚拟寄存器
     program: BytecodeProgram,
+    function: Rc<CompiledFunction>,  // 当前正在执行的代码，顶层代码也包装为函数
+    frames: Vec<CallFrame>,
+    memo: HashMap<String, HashMap<Vec<Value>, Value>>,  // `@memo` 函数的返回值缓存
+    config: VmConfig,
+    jit: Option<JITCompiler>,  // 未启用或当前平台不支持时为 None
+    call_counts: HashMap<String, u32>,  // 每个函数的调用次数
+    loop_counts: HashMap<(String, usize), u32>,  // 每个循环头（函数名, pc）的回跳次数
+    cold_loops: HashSet<(String, usize)>,  // 本地代码回退过的循环，不再尝试
     pc: usize,  // 程序计数器
     output: Vec<String>,  // 捕获程序输出
     memory_pool: InterpreterMemoryPool,  // 内存池
@@ -202,18 +306,41 @@ pub struct BytecodeInterpreter {
 
 impl BytecodeInterpreter {
     pub fn new(program: BytecodeProgram) -> Self {
+        Self::with_config(program, VmConfig::default())
+    }
+
+    pub fn with_config(program: BytecodeProgram, config: VmConfig) -> Self {
         // 获取内存池
         let memory_pool = get_interpreter_pool();
         
         // 创建新的栈和变量映射，使用 Value
         let stack = Vec::new();
         let variables = HashMap::new();
+        let function = Rc::new(CompiledFunction {
+            name: String::new(),
+            param_str: String::new(),
+            instructions: program.instructions.clone(),
+            spans: program.spans.clone(),
+            param_count: 0,
+            inline_hint: false,
+            memoize: false,
+        });
         
+        let jit = if config.jit { JITCompiler::new().ok() } else { None };
+
         BytecodeInterpreter {
             stack,
             variables,
             registers: [const { None }; 8],  // 初始化所有寄存器为None
             program,
+            function,
+            frames: Vec::new(),
+            memo: HashMap::new(),
+            config,
+            jit,
+            call_counts: HashMap::new(),
+            loop_counts: HashMap::new(),
+            cold_loops: HashSet::new(),
             pc: 0,
             output: Vec::new(),
             memory_pool,
@@ -224,9 +351,199 @@ impl BytecodeInterpreter {
         &self.output
     }
     
-    pub fn execute(&mut self) -> Result<u64, String> {
-        while self.pc < self.program.instructions.len() {
-            let instr = &self.program.instructions[self.pc];
+    // 当前正在执行的指令的源码位置
+    fn current_span(&self) -> Option<Span> {
+        self.pc.checked_sub(1).and_then(|pc| self.function.spans.get(pc)).copied()
+    }
+
+    fn lookup_function(&self, name: &str) -> Result<Rc<CompiledFunction>, Diagnostic> {
+        self.program.compiled_functions.get(name).cloned().ok_or_else(|| {
+            Diagnostic::new(Stage::Runtime, ErrorKind::UndefinedFunction, format!("Function not found: {}", name))
+        })
+    }
+
+    // 从操作数栈取出实参并绑定到形参，参数值原样传递
+    fn bind_arguments(&mut self, func: &CompiledFunction) -> Result<HashMap<String, Value>, Diagnostic> {
+        let floor = self.frames.last().map_or(0, |frame| frame.stack_base);
+        let available = self.stack.len() - floor;
+        if available < func.param_count {
+            return Err(Diagnostic::new(Stage::Runtime, ErrorKind::ArityMismatch, format!("Parameter count mismatch: expected {} parameters, got {}", func.param_count, available)));
+        }
+        let args = self.stack.split_off(self.stack.len() - func.param_count);
+        let params = func.param_str.split(',').filter(|p| !p.is_empty()).map(String::from);
+        Ok(params.zip(args).collect())
+    }
+
+    // 没有同名的用户函数时才调用内置函数
+    fn lookup_builtin(&self, name: &str) -> Option<&'static Builtin> {
+        if self.program.compiled_functions.contains_key(name) {
+            None
+        } else {
+            builtins::lookup(name)
+        }
+    }
+
+    // 从操作数栈取出实参调用内置函数，实参个数由 Builtin::call 检查
+    fn call_builtin(&mut self, builtin: &Builtin) -> Result<Value, Diagnostic> {
+        let floor = self.frames.last().map_or(0, |frame| frame.stack_base);
+        let count = builtin.arity.min(self.stack.len() - floor);
+        let args = self.stack.split_off(self.stack.len() - count);
+        builtin.call(args)
+    }
+
+    // 压入新的调用帧并跳转到被调函数开头
+    fn call_function(&mut self, func: Rc<CompiledFunction>) -> Result<(), Diagnostic> {
+        if self.frames.len() >= self.config.max_frames {
+            return Err(Diagnostic::new(Stage::Runtime, ErrorKind::StackOverflow, format!("Maximum call depth exceeded ({} frames)", self.config.max_frames)));
+        }
+        let locals = self.bind_arguments(&func)?;
+        let frame = CallFrame {
+            function: std::mem::replace(&mut self.function, func),
+            return_pc: self.pc,
+            locals: std::mem::replace(&mut self.variables, locals),
+            stack_base: self.stack.len(),
+            memo_keys: Vec::new(),
+        };
+        self.frames.push(frame);
+        self.pc = 0;
+        Ok(())
+    }
+
+    // 尾调用：复用当前帧，不增加调用深度
+    fn tail_call_function(&mut self, func: Rc<CompiledFunction>) -> Result<(), Diagnostic> {
+        if self.frames.is_empty() {
+            // 顶层代码没有可复用的帧，按普通调用处理，返回后结束程序
+            self.call_function(func)?;
+            if let Some(frame) = self.frames.last_mut() {
+                frame.return_pc = frame.function.instructions.len();
+            }
+            return Ok(());
+        }
+        let locals = self.bind_arguments(&func)?;
+        let base = self.frames.last().map_or(0, |frame| frame.stack_base);
+        self.stack.truncate(base);
+        self.variables = locals;
+        self.function = func;
+        self.pc = 0;
+        Ok(())
+    }
+
+    // 记忆化函数的缓存键；实参不足时返回 None，由 bind_arguments 报告错误。
+    // 实参含容器时不缓存：调用方之后修改容器会改变键的哈希，也会改变函数本应返回的结果
+    fn memo_key(&self, name: &str, func: &CompiledFunction) -> Option<MemoKey> {
+        if !func.memoize {
+            return None;
+        }
+        let floor = self.frames.last().map_or(0, |frame| frame.stack_base);
+        if self.stack.len() - floor < func.param_count {
+            return None;
+        }
+        let args = &self.stack[self.stack.len() - func.param_count..];
+        if args.iter().any(Value::is_container) {
+            return None;
+        }
+        Some((name.to_string(), args.to_vec()))
+    }
+
+    fn memo_lookup(&self, key: &MemoKey) -> Option<Value> {
+        self.memo.get(&key.0)?.get(&key.1).cloned()
+    }
+
+    // 当前帧返回时把返回值写入该键对应的缓存
+    fn record_memo_key(&mut self, key: Option<MemoKey>) {
+        if let (Some(key), Some(frame)) = (key, self.frames.last_mut()) {
+            frame.memo_keys.push(key);
+        }
+    }
+
+    // 调用次数达到阈值后尝试以本地代码完成调用：成功时弹出实参并返回结果；
+    // 函数无法编译或运行中回退（溢出、深度超限）时返回 None，由解释器重新执行这次调用
+    fn try_jit_call(&mut self, name: &str, func: &CompiledFunction) -> Option<Value> {
+        let jit = self.jit.as_mut()?;
+        if func.memoize || !is_hot(&mut self.call_counts, name, self.config.jit_threshold) {
+            return None;
+        }
+        let floor = self.frames.last().map_or(0, |frame| frame.stack_base);
+        if self.stack.len() - floor < func.param_count {
+            return None;
+        }
+        let base = self.stack.len() - func.param_count;
+        let args = self.stack[base..].iter().map(jit_arg).collect::<Option<Vec<IntegerValue>>>()?;
+        jit.compile_function(name, &self.program.compiled_functions).ok()?;
+        // 本地调用深度与解释器共用帧数上限，超限时由解释器报告栈溢出
+        let max_depth = self.config.max_frames.saturating_sub(self.frames.len());
+        let result = jit.execute(name, &args, max_depth).ok()??;
+        self.stack.truncate(base);
+        Some(Value::Integer(result))
+    }
+
+    // 回跳次数达到阈值的循环从循环头进入本地代码，直接执行完当前函数并返回结果
+    fn try_jit_loop(&mut self) -> Option<Value> {
+        let jit = self.jit.as_mut()?;
+        // 顶层代码不是函数，不参与编译
+        if self.frames.is_empty() || self.function.memoize {
+            return None;
+        }
+        let key = (self.function.name.clone(), self.pc);
+        if self.cold_loops.contains(&key) || !is_hot(&mut self.loop_counts, &key, self.config.jit_threshold) {
+            return None;
+        }
+        // 无法编译的循环（例如遍历列表的 for）不再尝试，免得每次回跳都收集局部变量
+        if jit.compile_loop(&key.0, key.1, &self.program.compiled_functions).is_err() {
+            self.cold_loops.insert(key);
+            return None;
+        }
+        let locals = jit::local_names(&self.function)
+            .iter()
+            .map(|local| self.variables.get(local).map_or(Some(IntegerValue::I64(0)), jit_arg))
+            .collect::<Option<Vec<IntegerValue>>>()?;
+        // 当前函数的帧已计入 frames
+        let max_depth = (self.config.max_frames + 1).saturating_sub(self.frames.len());
+        match jit.execute_loop(&key.0, key.1, &locals, max_depth).ok()? {
+            Some(result) => Some(Value::Integer(result)),
+            None => {
+                // 本地代码没有副作用，回退后解释器从循环头继续即可
+                self.cold_loops.insert(key);
+                None
+            }
+        }
+    }
+
+    // 弹出调用帧，恢复调用方状态并压入返回值
+    fn return_from_function(&mut self, value: Value) {
+        if let Some(frame) = self.frames.pop() {
+            // 尾调用复用的帧可能携带多个键，它们的结果相同；
+            // 返回的容器不缓存，否则每次命中都会共享同一个可被修改的对象
+            if !value.is_container() {
+                for (name, args) in frame.memo_keys {
+                    self.memo.entry(name).or_default().insert(args, value.clone());
+                }
+            }
+            self.stack.truncate(frame.stack_base);
+            self.function = frame.function;
+            self.pc = frame.return_pc;
+            self.variables = frame.locals;
+            self.stack.push(value);
+        }
+    }
+
+    pub fn execute(&mut self) -> Result<u64, Diagnostic> {
+        let result = self.run();
+        result.map_err(|err| err.or_span(self.current_span()))
+    }
+
+    fn run(&mut self) -> Result<u64, Diagnostic> {
+        loop {
+            let function = Rc::clone(&self.function);
+            if self.pc >= function.instructions.len() {
+                if self.frames.is_empty() {
+                    break;
+                }
+                // 函数执行到末尾而没有 return 时返回 0
+                self.return_from_function(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                continue;
+            }
+            let instr = &function.instructions[self.pc];
             self.pc += 1;
             
             match instr {
@@ -234,9 +551,7 @@ impl BytecodeInterpreter {
                     self.stack.push(n.clone());
                 }
                 Bytecode::LoadVar(name) => {
-                    let value = self.variables.get(name).cloned().unwrap_or_else(|| {
-                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                    });
+                    let value = self.variables.get(name).cloned().ok_or_else(|| undefined_variable(name))?;
                     self.stack.push(value);
                 }
                 Bytecode::StoreVar(name) => {
@@ -245,25 +560,42 @@ impl BytecodeInterpreter {
                     });
                     self.variables.insert(name.clone(), value);
                 }
+                // 最外层帧保存的是顶层代码的变量
+                Bytecode::LoadGlobal(name) => {
+                    let globals = self.frames.first().map_or(&self.variables, |frame| &frame.locals);
+                    let value = globals.get(name).cloned().ok_or_else(|| undefined_variable(name))?;
+                    self.stack.push(value);
+                }
+                Bytecode::StoreGlobal(name) => {
+                    let value = self.stack.pop().unwrap_or_else(|| {
+                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+                    });
+                    let globals = self.frames.first_mut().map_or(&mut self.variables, |frame| &mut frame.locals);
+                    globals.insert(name.clone(), value);
+                }
                 Bytecode::Add => {
                     let b = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            match a + b {
-                                Ok(result) => self.stack.push(Value::Integer(result)),
-                                Err(e) => {
-                                    // 处理加法错误，记录错误信息但继续执行
-                                    eprintln!("Warning: {}", e);
-                                    self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                                },
-                            }
+                            // 溢出时报告错误
+                            self.stack.push(Value::Integer((a + b)?));
                         }
-                        _ => {
-                            // 非整数类型的加法，暂时不支持
-                            eprintln!("Warning: Addition not supported for non-integer types");
-                            self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                        (Value::String(a), Value::String(b)) => {
+                            self.stack.push(Value::String(a.concat(&b)));
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => self.stack.push(Value::Float(x + y)),
+                            None => return Err(type_mismatch("+", &a, &b)),
+                        },
+                    }
+                }
+                Bytecode::Concat => {
+                    let b = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    match (a, b) {
+                        (Value::String(a), Value::String(b)) => self.stack.push(Value::String(a.concat(&b))),
+                        (a, b) => return Err(type_mismatch("+", &a, &b)),
                     }
                 }
                 Bytecode::Sub => {
@@ -271,20 +603,13 @@ impl BytecodeInterpreter {
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            match a - b {
-                                Ok(result) => self.stack.push(Value::Integer(result)),
-                                Err(e) => {
-                                    // 处理减法错误，记录错误信息但继续执行
-                                    eprintln!("Warning: {}", e);
-                                    self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                                },
-                            }
-                        }
-                        _ => {
-                            // 非整数类型的减法，暂时不支持
-                            eprintln!("Warning: Subtraction not supported for non-integer types");
-                            self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                            // 溢出时报告错误
+                            self.stack.push(Value::Integer((a - b)?));
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => self.stack.push(Value::Float(x - y)),
+                            None => return Err(type_mismatch("-", &a, &b)),
+                        },
                     }
                 }
                 Bytecode::Mul => {
@@ -292,20 +617,58 @@ impl BytecodeInterpreter {
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            match a * b {
-                                Ok(result) => self.stack.push(Value::Integer(result)),
-                                Err(e) => {
-                                    // 处理乘法错误，记录错误信息但继续执行
-                                    eprintln!("Warning: {}", e);
-                                    self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                            // 溢出时提升为 BigInt
+                            self.stack.push(Value::Integer((a * b)?));
+                        }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => self.stack.push(Value::Float(x * y)),
+                            None => return Err(type_mismatch("*", &a, &b)),
+                        },
+                    }
+                }
+                Bytecode::AddI64 | Bytecode::SubI64 | Bytecode::MulI64 => {
+                    let b = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let symbol = match instr {
+                        Bytecode::AddI64 => "+",
+                        Bytecode::SubI64 => "-",
+                        _ => "*",
+                    };
+                    match (a, b) {
+                        (Value::Integer(a), Value::Integer(b)) => {
+                            // 直接按 i64 计算，不必再比较位宽；溢出时由通用的整数运算报告错误或提升为 BigInt
+                            let fast = match (as_i64(&a), as_i64(&b)) {
+                                (Some(x), Some(y)) => match instr {
+                                    Bytecode::AddI64 => x.checked_add(y),
+                                    Bytecode::SubI64 => x.checked_sub(y),
+                                    _ => x.checked_mul(y),
                                 },
-                            }
+                                _ => None,
+                            };
+                            let result = match fast {
+                                Some(n) => IntegerValue::I64(n),
+                                None => match instr {
+                                    Bytecode::AddI64 => (a + b)?,
+                                    Bytecode::SubI64 => (a - b)?,
+                                    _ => (a * b)?,
+                                },
+                            };
+                            self.stack.push(Value::Integer(result));
                         }
-                        _ => {
-                            // 非整数类型的乘法，暂时不支持
-                            eprintln!("Warning: Multiplication not supported for non-integer types");
-                            self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                        (a, b) => return Err(type_mismatch(symbol, &a, &b)),
+                    }
+                }
+                Bytecode::Div => {
+                    let b = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    match (a, b) {
+                        (Value::Integer(a), Value::Integer(b)) => {
+                            self.stack.push(Value::Integer((a / b)?));
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => self.stack.push(Value::Float((x / y)?)),
+                            None => return Err(type_mismatch("/", &a, &b)),
+                        },
                     }
                 }
                 Bytecode::Mod => {
@@ -313,20 +676,13 @@ impl BytecodeInterpreter {
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            match a % b {
-                                Ok(result) => self.stack.push(Value::Integer(result)),
-                                Err(e) => {
-                                    // 处理取模错误，记录错误信息但继续执行
-                                    eprintln!("Warning: {}", e);
-                                    self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                                },
-                            }
-                        }
-                        _ => {
-                            // 非整数类型的取模，暂时不支持
-                            eprintln!("Warning: Modulo not supported for non-integer types");
-                            self.stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                            // 除数为零是运行时错误，不再静默得到 0
+                            self.stack.push(Value::Integer((a % b)?));
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => self.stack.push(Value::Float((x % y)?)),
+                            None => return Err(type_mismatch("%", &a, &b)),
+                        },
                     }
                 }
                 Bytecode::Le => {
@@ -334,17 +690,12 @@ impl BytecodeInterpreter {
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     let result = match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            if a <= b { 
-                                Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                            } else { 
-                                Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                            }
-                        }
-                        _ => {
-                            // 非整数类型的比较，暂时不支持
-                            eprintln!("Warning: Comparison not supported for non-integer types");
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+                            Value::from_bool(a <= b)
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => Value::from_bool(x.0 <= y.0),
+                            None => return Err(type_mismatch("<=", &a, &b)),
+                        },
                     };
                     self.stack.push(result);
                 }
@@ -353,17 +704,12 @@ impl BytecodeInterpreter {
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     let result = match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            if a < b { 
-                                Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                            } else { 
-                                Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                            }
-                        }
-                        _ => {
-                            // 非整数类型的比较，暂时不支持
-                            eprintln!("Warning: Comparison not supported for non-integer types");
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+                            Value::from_bool(a < b)
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => Value::from_bool(x.0 < y.0),
+                            None => return Err(type_mismatch("<", &a, &b)),
+                        },
                     };
                     self.stack.push(result);
                 }
@@ -372,17 +718,12 @@ impl BytecodeInterpreter {
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     let result = match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            if a > b { 
-                                Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                            } else { 
-                                Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                            }
-                        }
-                        _ => {
-                            // 非整数类型的比较，暂时不支持
-                            eprintln!("Warning: Comparison not supported for non-integer types");
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+                            Value::from_bool(a > b)
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => Value::from_bool(x.0 > y.0),
+                            None => return Err(type_mismatch(">", &a, &b)),
+                        },
                     };
                     self.stack.push(result);
                 }
@@ -391,36 +732,113 @@ impl BytecodeInterpreter {
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     let result = match (a, b) {
                         (Value::Integer(a), Value::Integer(b)) => {
-                            if a >= b { 
-                                Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                            } else { 
-                                Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                            }
-                        }
-                        _ => {
-                            // 非整数类型的比较，暂时不支持
-                            eprintln!("Warning: Comparison not supported for non-integer types");
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+                            Value::from_bool(a >= b)
                         }
+                        (a, b) => match promote_float(&a, &b) {
+                            Some((x, y)) => Value::from_bool(x.0 >= y.0),
+                            None => return Err(type_mismatch(">=", &a, &b)),
+                        },
                     };
                     self.stack.push(result);
                 }
                 Bytecode::Eq => {
                     let b = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                    let result = if a == b { 
-                        Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                    } else { 
-                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
+                    self.stack.push(Value::from_bool(a.equals(&b)));
+                }
+                Bytecode::BitAnd | Bytecode::BitOr | Bytecode::BitXor | Bytecode::Shl | Bytecode::Shr => {
+                    let b = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let a = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let (a, b) = match (a, b) {
+                        (Value::Integer(a), Value::Integer(b)) => (a, b),
+                        (a, b) => {
+                            let symbol = match instr {
+                                Bytecode::BitAnd => "&",
+                                Bytecode::BitOr => "|",
+                                Bytecode::BitXor => "^",
+                                Bytecode::Shl => "<<",
+                                _ => ">>",
+                            };
+                            return Err(type_mismatch(symbol, &a, &b));
+                        }
                     };
-                    self.stack.push(result);
+                    // 移位量越界是运行时错误
+                    let result = match instr {
+                        Bytecode::BitAnd => a & b,
+                        Bytecode::BitOr => a | b,
+                        Bytecode::BitXor => a ^ b,
+                        Bytecode::Shl => a << b,
+                        _ => a >> b,
+                    };
+                    self.stack.push(Value::Integer(result?));
+                }
+                Bytecode::Neg => {
+                    let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    self.stack.push(value.negate()?);
+                }
+                Bytecode::Not => {
+                    let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    self.stack.push(Value::from_bool(!value.is_truthy()));
+                }
+                Bytecode::BitNot => {
+                    let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    self.stack.push(value.bit_not()?);
+                }
+                Bytecode::MakeList(count) => {
+                    let items = self.stack.split_off(self.stack.len() - count);
+                    self.stack.push(Value::List(ListValue::new(items)));
+                }
+                Bytecode::MakeMap(count) => {
+                    let items = self.stack.split_off(self.stack.len() - 2 * count);
+                    let map = MapValue::new();
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        map.insert(key, value)?;
+                    }
+                    self.stack.push(Value::Map(map));
+                }
+                Bytecode::Index => {
+                    let index = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let container = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    self.stack.push(container.index(&index)?);
+                }
+                Bytecode::StoreIndex => {
+                    let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let index = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let container = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    container.store_index(&index, value)?;
+                }
+                Bytecode::Pop => {
+                    self.stack.pop();
+                }
+                Bytecode::RangeInit => {
+                    let end = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let start = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    let (start, end) = range_bounds(&start, &end)?;
+                    self.stack.push(Value::Integer(start));
+                    self.stack.push(Value::Integer(end));
+                }
+                Bytecode::IterInit => {
+                    let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    self.stack.push(Value::List(value.iteration_list()?));
+                }
+                Bytecode::IterNext(offset) => {
+                    let position = self.stack.pop();
+                    let list = self.stack.pop();
+                    // 下标和列表都是编译器生成的隐藏变量
+                    let item = match (list, position) {
+                        (Some(Value::List(list)), Some(Value::Integer(IntegerValue::I64(position)))) => list.item(position as usize),
+                        _ => None,
+                    };
+                    match item {
+                        Some(item) => self.stack.push(item),
+                        None => self.pc = ((self.pc as i32) + offset) as usize,
+                    }
                 }
                 // 寄存器操作
                 Bytecode::LoadReg(reg_idx, var_name) => {
                     if *reg_idx < 8 {
-                        let value = self.variables.get(var_name).cloned().unwrap_or_else(|| {
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                        });
+                        let value = self.variables.get(var_name).cloned().ok_or_else(|| undefined_variable(var_name))?;
                         self.registers[*reg_idx as usize] = Some(value.clone());
                         // 将寄存器值压入栈，以便后续操作使用
                         self.stack.push(value);
@@ -520,270 +938,505 @@ impl BytecodeInterpreter {
                 Bytecode::Jump(offset) => {
                     // pc已经在循环开始时+1了，所以这里要从当前位置计算
                     self.pc = ((self.pc as i32) + offset) as usize;
+                    if *offset < 0 {
+                        if let Some(value) = self.try_jit_loop() {
+                            self.return_from_function(value);
+                        }
+                    }
                 }
                 Bytecode::JumpIfFalse(offset) => {
                     let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                    // 检查值是否为零
-                    let is_false = match value {
-                        Value::Integer(IntegerValue::I8(v)) => v == 0,
-                        Value::Integer(IntegerValue::I16(v)) => v == 0,
-                        Value::Integer(IntegerValue::I32(v)) => v == 0,
-                        Value::Integer(IntegerValue::I64(v)) => v == 0,
-                        Value::Integer(IntegerValue::I128(v)) => v == 0,
-                        Value::Integer(IntegerValue::BigInt(v)) => v == num_bigint::BigInt::from(0),
-                        Value::String(_) => true, // 非整数类型视为false
-                    };
-                    if is_false {
+                    if !value.is_truthy() {
                         // pc已经在循环开始时+1了，所以这里要从当前位置计算
                         self.pc = ((self.pc as i32) + offset) as usize;
                     }
                 }
+                Bytecode::JumpIfTrue(offset) => {
+                    let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                    if value.is_truthy() {
+                        self.pc = ((self.pc as i32) + offset) as usize;
+                    }
+                }
                 Bytecode::Call(name) => {
-                // 优先使用编译后的函数
-                if let Some(compiled_func) = self.program.compiled_functions.get(name) {
-                    // 从栈中获取参数并转换为 u64
-                    let mut args = Vec::new();
-                    for _ in 0..compiled_func.param_count {
-                        let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                        let arg_value = match value {
-                            Value::Integer(v) => match v.to_i64() {
-                                Ok(v) => v as u64,
-                                Err(_) => 0,
-                            },
-                            _ => 0,
-                        };
-                        args.insert(0, arg_value);
+                    if let Some(builtin) = self.lookup_builtin(name) {
+                        let value = self.call_builtin(builtin)?;
+                        self.stack.push(value);
+                        continue;
                     }
-                    
-                    // 执行函数（使用编译后的函数信息）
-                    let result = execute_function(&compiled_func.instructions, &compiled_func.param_str, &args, &self.program.functions)?;
-                    
-                    // 将结果转换回 Value 并压入栈
-                    let result_value = Value::Integer(IntegerValue::from_string(&result.to_string(), IntegerType::I64).unwrap());
-                    self.stack.push(result_value);
-                } else if let Some((param_str, func_code)) = self.program.functions.get(name).cloned() {
-                    // 解析参数数量
-                    let param_count = param_str.split(',').filter(|p| !p.is_empty()).count();
-                    
-                    // 从栈中获取参数并转换为 u64
-                    let mut args = Vec::new();
-                    for _ in 0..param_count {
-                        let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                        let arg_value = match value {
-                            Value::Integer(v) => match v.to_i64() {
-                                Ok(v) => v as u64,
-                                Err(_) => 0,
-                            },
-                            _ => 0,
-                        };
-                        args.insert(0, arg_value);
+                    let func = self.lookup_function(name)?;
+                    if let Some(value) = self.try_jit_call(name, &func) {
+                        self.stack.push(value);
+                        continue;
+                    }
+                    let key = self.memo_key(name, &func);
+                    match key.as_ref().and_then(|key| self.memo_lookup(key)) {
+                        Some(value) => {
+                            self.stack.truncate(self.stack.len() - func.param_count);
+                            self.stack.push(value);
+                        }
+                        None => {
+                            self.call_function(func)?;
+                            self.record_memo_key(key);
+                        }
                     }
-                    
-                    // 执行函数（使用递归调用而不是创建新的解释器）
-                    let result = execute_function(&func_code, &param_str, &args, &self.program.functions)?;
-                    
-                    // 将结果转换回 Value 并压入栈
-                    let result_value = Value::Integer(IntegerValue::from_string(&result.to_string(), IntegerType::I64).unwrap());
-                    self.stack.push(result_value);
-                } else {
-                    return Err(format!("Function not found: {}", name));
                 }
-            }
                 Bytecode::TailCall(name) => {
-                // 尾调用优化：重用当前栈帧，直接跳转到函数开始
-                if let Some(compiled_func) = self.program.compiled_functions.get(name) {
-                    // 从栈中获取参数并转换为 u64
-                    let mut args = Vec::new();
-                    for _ in 0..compiled_func.param_count {
-                        let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                        let arg_value = match value {
-                            Value::Integer(v) => match v.to_i64() {
-                                Ok(v) => v as u64,
-                                Err(_) => 0,
-                            },
-                            _ => 0,
-                        };
-                        args.insert(0, arg_value);
+                    if let Some(builtin) = self.lookup_builtin(name) {
+                        let value = self.call_builtin(builtin)?;
+                        if self.frames.is_empty() {
+                            return Ok(value_to_u64(value));
+                        }
+                        self.return_from_function(value);
+                        continue;
+                    }
+                    let func = self.lookup_function(name)?;
+                    if let Some(value) = self.try_jit_call(name, &func) {
+                        if self.frames.is_empty() {
+                            return Ok(value_to_u64(value));
+                        }
+                        self.return_from_function(value);
+                        continue;
+                    }
+                    let key = self.memo_key(name, &func);
+                    match key.as_ref().and_then(|key| self.memo_lookup(key)) {
+                        Some(value) => {
+                            // 命中缓存，等同于直接返回缓存值
+                            self.stack.truncate(self.stack.len() - func.param_count);
+                            if self.frames.is_empty() {
+                                return Ok(value_to_u64(value));
+                            }
+                            self.return_from_function(value);
+                        }
+                        None => {
+                            self.tail_call_function(func)?;
+                            self.record_memo_key(key);
+                        }
                     }
-                    
-                    // 执行函数并直接返回结果（尾调用优化）
-                    let result = execute_function(&compiled_func.instructions, &compiled_func.param_str, &args, &self.program.functions)?;
-                    return Ok(result);
-                } else {
-                    return Err(format!("Function not found: {}", name));
                 }
-            }
                 Bytecode::Return => {
                     let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                    // For compatibility, convert to u64 if possible
-                    match value {
-                        Value::Integer(v) => match v.to_i64() {
-                            Ok(v) => return Ok(v as u64),
-                            Err(_) => return Ok(0),
-                        },
-                        _ => return Ok(0),
+                    if self.frames.is_empty() {
+                        // 顶层 return 结束整个程序
+                        return Ok(value_to_u64(value));
                     }
+                    self.return_from_function(value);
                 }
                 Bytecode::Out => {
                     let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
                     self.output.push(value.to_string());
                 }
-                Bytecode::FuncDef(name, param, code) => {
-                    self.program.functions.insert(name.clone(), (param.clone(), code.clone()));
+                Bytecode::FuncDef(_, _, _) => {
+                    // 函数定义在编译时已处理，运行时忽略
                 }
             }
         }
         
         // 返回栈顶值
         let value = self.stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-        match value {
-            Value::Integer(v) => match v.to_i64() {
-                Ok(v) => Ok(v as u64),
-                Err(_) => Ok(0),
-            },
-            _ => Ok(0),
-        }
+        Ok(value_to_u64(value))
+    }
+}
+
+// 计数加一并判断是否达到编译阈值
+fn is_hot<K, Q>(counts: &mut HashMap<K, u32>, key: &Q, threshold: u32) -> bool
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: ToOwned<Owned = K> + std::hash::Hash + Eq + ?Sized,
+{
+    if !counts.contains_key(key) {
+        counts.insert(key.to_owned(), 0);
+    }
+    let count = counts.get_mut(key).unwrap();
+    *count = count.saturating_add(1);
+    *count >= threshold
+}
+
+// 不宽于 i64 的整数按 i64 取值
+fn as_i64(value: &IntegerValue) -> Option<i64> {
+    match value {
+        IntegerValue::I8(n) => Some(i64::from(*n)),
+        IntegerValue::I16(n) => Some(i64::from(*n)),
+        IntegerValue::I32(n) => Some(i64::from(*n)),
+        IntegerValue::I64(n) => Some(*n),
+        _ => None,
+    }
+}
+
+// 只有不宽于 i64 的整数能直接传给本地代码
+fn jit_arg(value: &Value) -> Option<IntegerValue> {
+    match value {
+        Value::Integer(n @ (IntegerValue::I8(_) | IntegerValue::I16(_) | IntegerValue::I32(_) | IntegerValue::I64(_))) => Some(n.clone()),
+        _ => None,
+    }
+}
+
+// 程序结果仍以 u64 形式返回给调用方
+fn value_to_u64(value: Value) -> u64 {
+    match value {
+        Value::Integer(v) => match v.to_i64() {
+            Ok(v) => v as u64,
+            Err(_) => 0,
+        },
+        _ => 0,
     }
 }
 
 // 将AST转换为字节码
 pub fn compile_to_bytecode(program: &Program) -> BytecodeProgram {
     let mut instructions = Vec::new();
+    let mut spans = Vec::new();
     let mut functions = HashMap::new();
     let mut compiled_functions = HashMap::new();
     let mut incremental_compiler = IncrementalCompiler::new();
     
     // 处理函数定义
     for stmt in &program.statements {
-        if let Stmt::FuncDef(name, params, body) = stmt {
+        if let StmtKind::FuncDef(name, params, _, body, annotations) = &stmt.kind {
             let body_refs: Vec<&Stmt> = body.iter().collect();
-            let func_code = compile_statements(&body_refs, &functions);
+            let (func_code, _) = compile_statements(&body_refs, &functions);
             // 存储参数列表为逗号分隔的字符串
             let param_str = params.join(",");
             functions.insert(name.clone(), (param_str.clone(), func_code.clone()));
             
             // 使用增量编译器编译函数
-            let compiled_func = incremental_compiler.compile_function(name, params, body, &functions);
-            compiled_functions.insert(name.clone(), compiled_func);
+            let mut compiled_func = incremental_compiler.compile_function(name, params, body, &functions);
+            compiled_func.memoize = annotations.iter().any(|a| a == "memo");
+            compiled_functions.insert(name.clone(), Rc::new(compiled_func));
         }
     }
     
     // 处理非函数定义的顶级语句
     let non_func_stmts: Vec<&Stmt> = program.statements.iter()
-        .filter(|stmt| !matches!(stmt, Stmt::FuncDef(_, _, _)))
+        .filter(|stmt| !matches!(stmt.kind, StmtKind::FuncDef(..)))
         .collect();
-    let top_level_instructions = compile_statements(&non_func_stmts, &functions);
+    let (top_level_instructions, top_level_spans) = compile_statements(&non_func_stmts, &functions);
     instructions.extend(top_level_instructions);
+    spans.extend(top_level_spans);
     
     BytecodeProgram {
         instructions,
+        spans,
         functions,
         compiled_functions,
         incremental_compiler,
     }
 }
 
-// 编译语句列表
-fn compile_statements(statements: &[&Stmt], functions: &HashMap<String, (String, Vec<Bytecode>)>) -> Vec<Bytecode> {
-    let mut instructions = Vec::new();
-    
+// 跳转目标标签：可以在绑定位置之前被引用，由 Emitter::finish 统一回填偏移
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Label(usize);
+
+// 字节码发射器，记录指令、源码位置以及待回填的跳转
+struct Emitter {
+    instructions: Vec<Bytecode>,
+    spans: Vec<Span>,
+    labels: Vec<Option<usize>>,  // 标签 → 绑定的指令位置
+    fixups: Vec<(usize, Label)>,  // (跳转指令位置, 目标标签)
+    hidden_vars: usize,  // 已分配的隐藏变量数
+    loops: Vec<(Label, Label)>,  // 外层到内层各循环的 (continue 目标, 出口)
+}
+
+impl Emitter {
+    fn new() -> Self {
+        Emitter {
+            instructions: Vec::new(),
+            spans: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+            hidden_vars: 0,
+            loops: Vec::new(),
+        }
+    }
+
+    // 分配编译器内部使用的变量名；名字含 `#`，不会与源码中的标识符冲突
+    fn hidden_var(&mut self, role: &str) -> String {
+        self.hidden_vars += 1;
+        format!("#{}{}", role, self.hidden_vars)
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    // 将标签绑定到下一条将要发射的指令
+    fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.instructions.len());
+    }
+
+    fn emit(&mut self, instr: Bytecode, span: Span) {
+        self.instructions.push(instr);
+        self.spans.push(span);
+    }
+
+    fn emit_expr(&mut self, expr: &Expr, span: Span, functions: &HashMap<String, (String, Vec<Bytecode>)>) {
+        compile_expr(expr, &mut self.instructions, functions);
+        self.spans.resize(self.instructions.len(), span);
+    }
+
+    // 发射跳到 label 的跳转指令，偏移量在 finish 时回填
+    fn emit_jump(&mut self, jump: fn(i32) -> Bytecode, label: Label, span: Span) {
+        self.fixups.push((self.instructions.len(), label));
+        self.emit(jump(0), span);
+    }
+
+    fn finish(mut self) -> (Vec<Bytecode>, Vec<Span>) {
+        for (pos, label) in self.fixups {
+            let target = self.labels[label.0].expect("jump to unbound label");
+            patch_jump(&mut self.instructions, pos, target);
+        }
+        (self.instructions, self.spans)
+    }
+}
+
+// 回填跳转偏移量，偏移量相对于跳转指令的下一条指令
+fn patch_jump(instructions: &mut [Bytecode], pos: usize, target: usize) {
+    let offset = target as i32 - (pos as i32 + 1);
+    match &mut instructions[pos] {
+        Bytecode::Jump(o) | Bytecode::JumpIfFalse(o) | Bytecode::JumpIfTrue(o) | Bytecode::IterNext(o) => *o = offset,
+        other => unreachable!("fixup on non-jump instruction {:?}", other),
+    }
+}
+
+// 编译语句列表，同时返回每条指令对应的源码位置
+fn compile_statements(statements: &[&Stmt], functions: &HashMap<String, (String, Vec<Bytecode>)>) -> (Vec<Bytecode>, Vec<Span>) {
+    let mut emitter = Emitter::new();
     for stmt in statements {
-        match stmt {
-            Stmt::Assign(name, expr) => {
-                compile_expr(expr, &mut instructions, functions);
-                instructions.push(Bytecode::StoreVar(name.clone()));
+        compile_stmt(&mut emitter, stmt, functions);
+    }
+    emitter.finish()
+}
+
+fn compile_stmt(emitter: &mut Emitter, stmt: &Stmt, functions: &HashMap<String, (String, Vec<Bytecode>)>) {
+    match &stmt.kind {
+        StmtKind::Assign(name, expr) => {
+            emitter.emit_expr(expr, stmt.span, functions);
+            emitter.emit(Bytecode::StoreVar(name.clone()), stmt.span);
+        }
+        StmtKind::If(cond, body, else_body) => {
+            let else_label = emitter.new_label();
+            let end_label = emitter.new_label();
+            emitter.emit_expr(cond, stmt.span, functions);
+            emitter.emit_jump(Bytecode::JumpIfFalse, else_label, stmt.span);
+            for stmt in body {
+                compile_stmt(emitter, stmt, functions);
             }
-            Stmt::If(cond, body) => {
-                compile_expr(cond, &mut instructions, functions);
-                let jump_offset = body.len() as i32 + 1;
-                instructions.push(Bytecode::JumpIfFalse(jump_offset));
-                let body_refs: Vec<&Stmt> = body.iter().collect();
-                let body_instructions = compile_statements(&body_refs, functions);
-                instructions.extend(body_instructions);
+            // 有 else 分支时 then 分支末尾跳过 else 块
+            if !else_body.is_empty() {
+                emitter.emit_jump(Bytecode::Jump, end_label, stmt.span);
             }
-            Stmt::While(cond, body) => {
-                let loop_start = instructions.len();
-                
-                // 编译条件表达式
-                compile_expr(cond, &mut instructions, functions);
-                
-                // 记录JumpIfFalse指令的位置
-                let jump_if_false_pos = instructions.len();
-                // 先插入一个占位符
-                instructions.push(Bytecode::JumpIfFalse(0));
-                
-                // 编译循环体
-                let body_refs: Vec<&Stmt> = body.iter().collect();
-                for stmt in &body_refs {
-                    match stmt {
-                        Stmt::Assign(name, expr) => {
-                            compile_expr(expr, &mut instructions, functions);
-                            instructions.push(Bytecode::StoreVar(name.clone()));
-                        }
-                        Stmt::Out(expr) => {
-                            compile_expr(expr, &mut instructions, functions);
-                            instructions.push(Bytecode::Out);
-                        }
-                        _ => {
-                            // 其他语句类型暂时忽略
-                        }
-                    }
-                }
-                
-                // 添加跳回循环开始的Jump指令
-                let jump_back_pos = instructions.len();
-                let jump_back_offset = loop_start as i32 - (jump_back_pos as i32 + 1);
-                instructions.push(Bytecode::Jump(jump_back_offset));
-                
-                // 现在计算JumpIfFalse的正确偏移量
-                // 目标位置是Jump指令之后（循环结束后）
-                let loop_end = instructions.len();
-                let jump_out_offset = loop_end as i32 - (jump_if_false_pos as i32 + 1);
-                instructions[jump_if_false_pos] = Bytecode::JumpIfFalse(jump_out_offset);
+            emitter.bind(else_label);
+            for stmt in else_body {
+                compile_stmt(emitter, stmt, functions);
             }
-            Stmt::Return(expr) => {
-            // 检查是否是尾递归调用
-            if let Expr::Call(name, args) = expr {
-                // 检查是否是对自身的调用（简单版本：假设函数名在当前函数中）
-                // 实际项目中需要更复杂的分析来确定是否是对自身的调用
-                // 这里简化处理，只要是函数调用就尝试使用尾调用
-                let mut args_instructions = Vec::new();
+            emitter.bind(end_label);
+        }
+        StmtKind::While(cond, body) => {
+            let head = |emitter: &mut Emitter, exit_label: Label| {
+                emitter.emit_expr(cond, stmt.span, functions);
+                emitter.emit_jump(Bytecode::JumpIfFalse, exit_label, stmt.span);
+            };
+            compile_loop(emitter, None, body, stmt.span, functions, head, |_| {});
+        }
+        StmtKind::For(var, ForTarget::Range(start, end), body) => {
+            // 隐藏的计数器和上界：循环体给循环变量赋值不影响迭代次数，半开区间的计数也不会溢出
+            let counter = emitter.hidden_var("counter");
+            let limit = emitter.hidden_var("limit");
+            emitter.emit_expr(start, stmt.span, functions);
+            emitter.emit_expr(end, stmt.span, functions);
+            emitter.emit(Bytecode::RangeInit, stmt.span);
+            emitter.emit(Bytecode::StoreVar(limit.clone()), stmt.span);
+            emitter.emit(Bytecode::StoreVar(counter.clone()), stmt.span);
+            let head = |emitter: &mut Emitter, exit_label: Label| {
+                emitter.emit(Bytecode::LoadVar(counter.clone()), stmt.span);
+                emitter.emit(Bytecode::LoadVar(limit.clone()), stmt.span);
+                emitter.emit(Bytecode::Lt, stmt.span);
+                emitter.emit_jump(Bytecode::JumpIfFalse, exit_label, stmt.span);
+                emitter.emit(Bytecode::LoadVar(counter.clone()), stmt.span);
+                emitter.emit(Bytecode::StoreVar(var.clone()), stmt.span);
+            };
+            let step = |emitter: &mut Emitter| {
+                emitter.emit(Bytecode::LoadVar(counter.clone()), stmt.span);
+                emitter.emit(Bytecode::LoadConst(Value::Integer(IntegerValue::I8(1))), stmt.span);
+                emitter.emit(Bytecode::Add, stmt.span);
+                emitter.emit(Bytecode::StoreVar(counter.clone()), stmt.span);
+            };
+            compile_loop(emitter, Some(var), body, stmt.span, functions, head, step);
+        }
+        StmtKind::For(var, ForTarget::Each(items), body) => {
+            // 按下标遍历隐藏的列表变量，每轮不分配新对象
+            let list = emitter.hidden_var("list");
+            let position = emitter.hidden_var("position");
+            emitter.emit_expr(items, stmt.span, functions);
+            emitter.emit(Bytecode::IterInit, stmt.span);
+            emitter.emit(Bytecode::StoreVar(list.clone()), stmt.span);
+            emitter.emit(Bytecode::LoadConst(Value::Integer(IntegerValue::I64(0))), stmt.span);
+            emitter.emit(Bytecode::StoreVar(position.clone()), stmt.span);
+            let head = |emitter: &mut Emitter, exit_label: Label| {
+                emitter.emit(Bytecode::LoadVar(list.clone()), stmt.span);
+                emitter.emit(Bytecode::LoadVar(position.clone()), stmt.span);
+                emitter.emit_jump(Bytecode::IterNext, exit_label, stmt.span);
+                emitter.emit(Bytecode::StoreVar(var.clone()), stmt.span);
+            };
+            let step = |emitter: &mut Emitter| {
+                emitter.emit(Bytecode::LoadVar(position.clone()), stmt.span);
+                emitter.emit(Bytecode::LoadConst(Value::Integer(IntegerValue::I64(1))), stmt.span);
+                emitter.emit(Bytecode::Add, stmt.span);
+                emitter.emit(Bytecode::StoreVar(position.clone()), stmt.span);
+            };
+            compile_loop(emitter, Some(var), body, stmt.span, functions, head, step);
+        }
+        StmtKind::Return(expr) => {
+            if let ExprKind::Call(name, args) = &expr.kind {
+                // 返回函数调用的结果时使用尾调用
                 for arg in args {
-                    compile_expr(arg, &mut args_instructions, functions);
+                    emitter.emit_expr(arg, stmt.span, functions);
                 }
-                instructions.extend(args_instructions);
-                instructions.push(Bytecode::TailCall(name.clone()));
-                return instructions;
+                emitter.emit(Bytecode::TailCall(name.clone()), stmt.span);
             } else {
-                // 普通返回
-                compile_expr(expr, &mut instructions, functions);
-                instructions.push(Bytecode::Return);
+                emitter.emit_expr(expr, stmt.span, functions);
+                emitter.emit(Bytecode::Return, stmt.span);
             }
         }
-            Stmt::Out(expr) => {
-                compile_expr(expr, &mut instructions, functions);
-                instructions.push(Bytecode::Out);
-            }
-            _ => {
-                // 其他语句类型暂时忽略
+        StmtKind::IndexAssign(container, index, value) => {
+            emitter.emit_expr(container, stmt.span, functions);
+            emitter.emit_expr(index, stmt.span, functions);
+            emitter.emit_expr(value, stmt.span, functions);
+            emitter.emit(Bytecode::StoreIndex, stmt.span);
+        }
+        StmtKind::Out(expr) => {
+            emitter.emit_expr(expr, stmt.span, functions);
+            emitter.emit(Bytecode::Out, stmt.span);
+        }
+        StmtKind::Expr(expr) => {
+            emitter.emit_expr(expr, stmt.span, functions);
+            emitter.emit(Bytecode::Pop, stmt.span);
+        }
+        StmtKind::Break | StmtKind::Continue => {
+            // 语义分析保证它们位于循环中
+            if let Some(&(continue_label, exit_label)) = emitter.loops.last() {
+                let target = if matches!(stmt.kind, StmtKind::Break) { exit_label } else { continue_label };
+                emitter.emit_jump(Bytecode::Jump, target, stmt.span);
             }
         }
+        StmtKind::Global(_) => {
+            // 由 resolve_globals 把对这些名字的读写改为模块级变量操作
+        }
+        StmtKind::FuncDef(..) => {
+            // 函数定义在 compile_to_bytecode 中单独编译
+        }
     }
-    
-    instructions
 }
 
-// 尝试计算常量表达式的值
-fn evaluate_const_expr(expr: &Expr) -> Option<Value> {
-    match expr {
-        Expr::Number(n) => {
-            Some(Value::Integer(n.clone()))
+// 编译循环。`head` 判断是否继续（否则跳到给定标签）并准备本轮的循环变量，`step` 在每轮末尾执行，
+// `continue` 跳到 `step` 之前，`break` 跳到出口。
+// 有可外提的赋值时，第一次通过 `head` 后先执行它们一次，再直接进入循环体
+fn compile_loop(
+    emitter: &mut Emitter,
+    loop_var: Option<&str>,
+    body: &[Stmt],
+    span: Span,
+    functions: &HashMap<String, (String, Vec<Bytecode>)>,
+    head: impl Fn(&mut Emitter, Label),
+    step: impl Fn(&mut Emitter),
+) {
+    let head_label = emitter.new_label();
+    let body_label = emitter.new_label();
+    let continue_label = emitter.new_label();
+    let exit_label = emitter.new_label();
+    let (hoisted, body) = optimize_loop_body(loop_var, body, functions);
+    if !hoisted.is_empty() {
+        head(emitter, exit_label);
+        for stmt in hoisted {
+            compile_stmt(emitter, stmt, functions);
         }
-        Expr::TypedNumber(int_val) => {
-            Some(Value::Integer(int_val.clone()))
+        emitter.emit_jump(Bytecode::Jump, body_label, span);
+    }
+    emitter.bind(head_label);
+    head(emitter, exit_label);
+    emitter.bind(body_label);
+    emitter.loops.push((continue_label, exit_label));
+    for stmt in body {
+        compile_stmt(emitter, stmt, functions);
+    }
+    emitter.loops.pop();
+    emitter.bind(continue_label);
+    step(emitter);
+    emitter.emit_jump(Bytecode::Jump, head_label, span);
+    emitter.bind(exit_label);
+}
+
+// 浮点数（或整数与浮点数混合）的二元运算，供常量折叠和树遍历解释器使用
+pub fn float_binop(op: &BinOpType, a: FloatValue, b: FloatValue) -> Result<Value, Diagnostic> {
+    match op {
+        BinOpType::Plus => Ok(Value::Float(a + b)),
+        BinOpType::Minus => Ok(Value::Float(a - b)),
+        BinOpType::Mul => Ok(Value::Float(a * b)),
+        BinOpType::Div => (a / b).map(Value::Float),
+        BinOpType::Mod => (a % b).map(Value::Float),
+        BinOpType::Le => Ok(Value::from_bool(a.0 <= b.0)),
+        BinOpType::Lt => Ok(Value::from_bool(a.0 < b.0)),
+        BinOpType::Gt => Ok(Value::from_bool(a.0 > b.0)),
+        BinOpType::Ge => Ok(Value::from_bool(a.0 >= b.0)),
+        BinOpType::Eq => Ok(Value::from_bool(a.0 == b.0)),
+        BinOpType::NotEq => Ok(Value::from_bool(a.0 != b.0)),
+        _ => Err(type_mismatch(op.symbol(), &Value::Float(a), &Value::Float(b))),
+    }
+}
+
+// 尝试计算常量表达式的值
+fn evaluate_const_expr(expr: &Expr) -> Option<Value> {
+    match &expr.kind {
+        ExprKind::Number(n) => {
+            Some(Value::Integer(n.clone()))
+        }
+        ExprKind::TypedNumber(int_val) => {
+            Some(Value::Integer(int_val.clone()))
+        }
+        ExprKind::Float(value) => Some(Value::Float(*value)),
+        ExprKind::Str(value) => {
+            Some(Value::String(value.clone()))
+        }
+        ExprKind::Bool(value) => Some(Value::Bool(*value)),
+        ExprKind::Unary(op, operand) => {
+            let value = evaluate_const_expr(operand)?;
+            match op {
+                UnaryOp::Neg => value.negate().ok(),
+                UnaryOp::Not => Some(Value::from_bool(!value.is_truthy())),
+                UnaryOp::BitNot => value.bit_not().ok(),
+            }
         }
-        Expr::BinOp(left, op, right) => {
-            if let (Some(Value::Integer(a)), Some(Value::Integer(b))) = (evaluate_const_expr(left), evaluate_const_expr(right)) {
+        ExprKind::BinOp(left, op @ (BinOpType::And | BinOpType::Or), right) => {
+            // 左侧已能决定结果时，右侧即使不是常量也不会被求值
+            let is_and = matches!(op, BinOpType::And);
+            let left = evaluate_const_expr(left)?;
+            if left.is_truthy() != is_and {
+                return Some(Value::from_bool(!is_and));
+            }
+            Some(Value::from_bool(evaluate_const_expr(right)?.is_truthy()))
+        }
+        ExprKind::BinOp(left, op, right) => {
+            let (left, right) = (evaluate_const_expr(left)?, evaluate_const_expr(right)?);
+            if let (Value::Bool(a), Value::Bool(b)) = (&left, &right) {
+                return match op {
+                    BinOpType::Eq => Some(Value::from_bool(a == b)),
+                    BinOpType::NotEq => Some(Value::from_bool(a != b)),
+                    _ => None,
+                };
+            }
+            if let (Value::String(a), Value::String(b)) = (&left, &right) {
+                // 字符串只折叠拼接和相等比较
+                return match op {
+                    BinOpType::Plus => Some(Value::String(a.concat(b))),
+                    BinOpType::Eq => Some(Value::from_bool(a == b)),
+                    BinOpType::NotEq => Some(Value::from_bool(a != b)),
+                    _ => None,
+                };
+            }
+            if let Some((a, b)) = promote_float(&left, &right) {
+                return float_binop(op, a, b).ok();
+            }
+            if let (Value::Integer(a), Value::Integer(b)) = (left, right) {
                 match op {
                     BinOpType::Plus => match a + b {
                         Ok(result) => Some(Value::Integer(result)),
@@ -797,35 +1450,27 @@ fn evaluate_const_expr(expr: &Expr) -> Option<Value> {
                         Ok(result) => Some(Value::Integer(result)),
                         Err(_) => None,
                     },
+                    BinOpType::Div => match a / b {
+                        Ok(result) => Some(Value::Integer(result)),
+                        Err(_) => None,
+                    },
                     BinOpType::Mod => match a % b {
                         Ok(result) => Some(Value::Integer(result)),
                         Err(_) => None,
                     },
-                    BinOpType::Le => Some(Value::Integer(if a <= b { 
-                        IntegerValue::from_string("1", IntegerType::I64).unwrap() 
-                    } else { 
-                        IntegerValue::from_string("0", IntegerType::I64).unwrap() 
-                    })),
-                    BinOpType::Lt => Some(Value::Integer(if a < b { 
-                        IntegerValue::from_string("1", IntegerType::I64).unwrap() 
-                    } else { 
-                        IntegerValue::from_string("0", IntegerType::I64).unwrap() 
-                    })),
-                    BinOpType::Gt => Some(Value::Integer(if a > b { 
-                        IntegerValue::from_string("1", IntegerType::I64).unwrap() 
-                    } else { 
-                        IntegerValue::from_string("0", IntegerType::I64).unwrap() 
-                    })),
-                    BinOpType::Ge => Some(Value::Integer(if a >= b { 
-                        IntegerValue::from_string("1", IntegerType::I64).unwrap() 
-                    } else { 
-                        IntegerValue::from_string("0", IntegerType::I64).unwrap() 
-                    })),
-                    BinOpType::Eq => Some(Value::Integer(if a == b { 
-                        IntegerValue::from_string("1", IntegerType::I64).unwrap() 
-                    } else { 
-                        IntegerValue::from_string("0", IntegerType::I64).unwrap() 
-                    })),
+                    BinOpType::Le => Some(Value::from_bool(a <= b)),
+                    BinOpType::Lt => Some(Value::from_bool(a < b)),
+                    BinOpType::Gt => Some(Value::from_bool(a > b)),
+                    BinOpType::Ge => Some(Value::from_bool(a >= b)),
+                    BinOpType::Eq => Some(Value::from_bool(a.cmp(&b) == std::cmp::Ordering::Equal)),
+                    BinOpType::NotEq => Some(Value::from_bool(a.cmp(&b) != std::cmp::Ordering::Equal)),
+                    // 越界的移位留到运行期报错
+                    BinOpType::BitAnd => (a & b).ok().map(Value::Integer),
+                    BinOpType::BitOr => (a | b).ok().map(Value::Integer),
+                    BinOpType::BitXor => (a ^ b).ok().map(Value::Integer),
+                    BinOpType::Shl => (a << b).ok().map(Value::Integer),
+                    BinOpType::Shr => (a >> b).ok().map(Value::Integer),
+                    BinOpType::And | BinOpType::Or => unreachable!("handled above"),
                 }
             } else {
                 None
@@ -835,61 +1480,141 @@ fn evaluate_const_expr(expr: &Expr) -> Option<Value> {
     }
 }
 
-// 优化循环体，外提循环不变量
-fn optimize_loop_body(body: &[Stmt], functions: &HashMap<String, (String, Vec<Bytecode>)>) -> Vec<Bytecode> {
-    let mut optimized_instructions = Vec::new();
-    let mut invariant_instructions = Vec::new();
-    
-    // 简单的循环不变量检测：识别不依赖循环变量的表达式
-    // 这里实现一个简化版本，实际项目中可能需要更复杂的分析
-    for stmt in body {
-        match stmt {
-            Stmt::Assign(name, expr) => {
-                // 检查表达式是否是循环不变的（只包含常量和非循环变量）
-                if is_loop_invariant(expr) {
-                    // 外提不变量到循环外
-                    let mut inv_instructions = Vec::new();
-                    compile_expr(expr, &mut inv_instructions, functions);
-                    invariant_instructions.extend(inv_instructions);
-                    invariant_instructions.push(Bytecode::StoreVar(name.clone()));
-                } else {
-                    // 保持在循环内
-                    let mut stmt_instructions = Vec::new();
-                    compile_expr(expr, &mut stmt_instructions, functions);
-                    stmt_instructions.push(Bytecode::StoreVar(name.clone()));
-                    optimized_instructions.extend(stmt_instructions);
-                }
-            }
-            _ => {
-                // 其他语句保持不变
-                let stmt_instructions = compile_statements(&[stmt], functions);
-                optimized_instructions.extend(stmt_instructions);
+// 优化循环体，外提循环不变量：返回外提到循环前只执行一次的赋值和留在循环内的语句。
+// 只外提值为常量的赋值，并且要求变量在循环中没有别的赋值、赋值之前没有读取它或可能离开循环的语句
+fn optimize_loop_body<'a>(loop_var: Option<&str>, body: &'a [Stmt], functions: &HashMap<String, (String, Vec<Bytecode>)>) -> (Vec<&'a Stmt>, Vec<&'a Stmt>) {
+    let mut hoisted = Vec::new();
+    let mut remaining = Vec::new();
+    // 被调函数可能读写模块级变量，提前赋值会改变它看到的值
+    let calls = body.iter().any(|stmt| calls_function(stmt, functions));
+    for (i, stmt) in body.iter().enumerate() {
+        let hoistable = match &stmt.kind {
+            StmtKind::Assign(name, expr) => {
+                !calls
+                    && loop_var != Some(name.as_str())
+                    && is_loop_invariant(expr)
+                    && evaluate_const_expr(expr).is_some()
+                    && body.iter().enumerate().all(|(j, other)| j == i || !assigns_var(other, name))
+                    && body[..i].iter().all(|prev| !reads_var(prev, name) && !may_leave_loop(prev))
             }
+            _ => false,
+        };
+        if hoistable {
+            hoisted.push(stmt);
+        } else {
+            remaining.push(stmt);
         }
     }
-    
-    // 先添加外提的不变量，再添加循环体内的指令
-    invariant_instructions.extend(optimized_instructions);
-    invariant_instructions
+    (hoisted, remaining)
+}
+
+// 语句（包括嵌套的语句块）是否给变量赋值
+fn assigns_var(stmt: &Stmt, name: &str) -> bool {
+    match &stmt.kind {
+        StmtKind::Assign(target, _) => target == name,
+        StmtKind::If(_, body, else_body) => body.iter().chain(else_body).any(|stmt| assigns_var(stmt, name)),
+        StmtKind::While(_, body) => body.iter().any(|stmt| assigns_var(stmt, name)),
+        StmtKind::For(var, _, body) => var == name || body.iter().any(|stmt| assigns_var(stmt, name)),
+        _ => false,
+    }
+}
+
+// 语句（包括嵌套的语句块）是否读取变量
+fn reads_var(stmt: &Stmt, name: &str) -> bool {
+    let reads = |expr: &Expr| expr_reads_var(expr, name);
+    match &stmt.kind {
+        StmtKind::Assign(_, expr) | StmtKind::Return(expr) | StmtKind::Out(expr) | StmtKind::Expr(expr) => reads(expr),
+        StmtKind::IndexAssign(container, index, value) => reads(container) || reads(index) || reads(value),
+        StmtKind::If(cond, body, else_body) => reads(cond) || body.iter().chain(else_body).any(|stmt| reads_var(stmt, name)),
+        StmtKind::While(cond, body) => reads(cond) || body.iter().any(|stmt| reads_var(stmt, name)),
+        StmtKind::For(_, target, body) => {
+            let target_reads = match target {
+                ForTarget::Range(start, end) => reads(start) || reads(end),
+                ForTarget::Each(items) => reads(items),
+            };
+            target_reads || body.iter().any(|stmt| reads_var(stmt, name))
+        }
+        StmtKind::Break | StmtKind::Continue | StmtKind::Global(_) | StmtKind::FuncDef(..) => false,
+    }
+}
+
+fn expr_reads_var(expr: &Expr, name: &str) -> bool {
+    match &expr.kind {
+        ExprKind::Ident(ident) => ident == name,
+        ExprKind::Unary(_, operand) => expr_reads_var(operand, name),
+        ExprKind::BinOp(left, _, right) | ExprKind::Index(left, right) => expr_reads_var(left, name) || expr_reads_var(right, name),
+        ExprKind::Call(_, items) | ExprKind::List(items) => items.iter().any(|item| expr_reads_var(item, name)),
+        ExprKind::Map(entries) => entries.iter().any(|(key, value)| expr_reads_var(key, name) || expr_reads_var(value, name)),
+        ExprKind::Number(_) | ExprKind::TypedNumber(_) | ExprKind::Str(_) | ExprKind::Bool(_) | ExprKind::Float(_) => false,
+    }
+}
+
+// 语句（包括嵌套的语句块）是否调用用户函数
+fn calls_function(stmt: &Stmt, functions: &HashMap<String, (String, Vec<Bytecode>)>) -> bool {
+    let calls = |expr: &Expr| expr_calls_function(expr, functions);
+    match &stmt.kind {
+        StmtKind::Assign(_, expr) | StmtKind::Return(expr) | StmtKind::Out(expr) | StmtKind::Expr(expr) => calls(expr),
+        StmtKind::IndexAssign(container, index, value) => calls(container) || calls(index) || calls(value),
+        StmtKind::If(cond, body, else_body) => calls(cond) || body.iter().chain(else_body).any(|stmt| calls_function(stmt, functions)),
+        StmtKind::While(cond, body) => calls(cond) || body.iter().any(|stmt| calls_function(stmt, functions)),
+        StmtKind::For(_, target, body) => {
+            let target_calls = match target {
+                ForTarget::Range(start, end) => calls(start) || calls(end),
+                ForTarget::Each(items) => calls(items),
+            };
+            target_calls || body.iter().any(|stmt| calls_function(stmt, functions))
+        }
+        StmtKind::Break | StmtKind::Continue | StmtKind::Global(_) | StmtKind::FuncDef(..) => false,
+    }
+}
+
+fn expr_calls_function(expr: &Expr, functions: &HashMap<String, (String, Vec<Bytecode>)>) -> bool {
+    let calls = |expr: &Expr| expr_calls_function(expr, functions);
+    match &expr.kind {
+        // 与 emit_expr 一致：已定义的函数名单独出现时按无参调用编译
+        ExprKind::Ident(name) => functions.contains_key(name),
+        ExprKind::Call(name, args) => !builtins::is_builtin(name) || args.iter().any(calls),
+        ExprKind::Unary(_, operand) => calls(operand),
+        ExprKind::BinOp(left, _, right) | ExprKind::Index(left, right) => calls(left) || calls(right),
+        ExprKind::List(items) => items.iter().any(calls),
+        ExprKind::Map(entries) => entries.iter().any(|(key, value)| calls(key) || calls(value)),
+        ExprKind::Number(_) | ExprKind::TypedNumber(_) | ExprKind::Str(_) | ExprKind::Bool(_) | ExprKind::Float(_) => false,
+    }
+}
+
+// 语句是否可能跳过本轮循环体的剩余部分
+fn may_leave_loop(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::Return(_) | StmtKind::Break | StmtKind::Continue => true,
+        StmtKind::If(_, body, else_body) => body.iter().chain(else_body).any(may_leave_loop),
+        StmtKind::While(_, body) | StmtKind::For(_, _, body) => body.iter().any(may_leave_loop),
+        _ => false,
+    }
 }
 
 // 检查表达式是否是循环不变的
 fn is_loop_invariant(expr: &Expr) -> bool {
-    match expr {
-        Expr::Number(_) => true,
-        Expr::TypedNumber(_) => true,
-        Expr::BinOp(left, _, right) => {
+    match &expr.kind {
+        ExprKind::Number(_) => true,
+        ExprKind::TypedNumber(_) => true,
+        ExprKind::Str(_) => true,
+        ExprKind::Bool(_) => true,
+        ExprKind::Float(_) => true,
+        ExprKind::Unary(_, operand) => is_loop_invariant(operand),
+        ExprKind::BinOp(left, _, right) => {
             is_loop_invariant(left) && is_loop_invariant(right)
         }
-        Expr::Ident(name) => {
+        ExprKind::Ident(_) => {
             // 简化版本：假设所有标识符都是循环变量
             // 实际项目中需要分析变量的定义和使用
             false
         }
-        Expr::Call(_, args) => {
+        ExprKind::Call(_, _) => {
             // 函数调用可能有副作用，不视为不变量
             false
         }
+        // 每次求值都要创建新列表；列表内容可能在循环中被修改
+        ExprKind::List(_) | ExprKind::Map(_) | ExprKind::Index(_, _) => false,
     }
 }
 
@@ -955,16 +1680,25 @@ fn compile_expr_with_register_alloc(expr: &Expr, instructions: &mut Vec<Bytecode
         return;
     }
     
-    match expr {
-        Expr::Number(n) => {
+    match &expr.kind {
+        ExprKind::Number(n) => {
             // 直接使用 Number 中的 IntegerValue
             instructions.push(Bytecode::LoadConst(Value::Integer(n.clone())));
         }
-        Expr::TypedNumber(int_val) => {
+        ExprKind::TypedNumber(int_val) => {
             // 直接使用 TypedNumber 的值
             instructions.push(Bytecode::LoadConst(Value::Integer(int_val.clone())));
         }
-        Expr::Ident(name) => {
+        ExprKind::Float(value) => {
+            instructions.push(Bytecode::LoadConst(Value::Float(*value)));
+        }
+        ExprKind::Str(value) => {
+            instructions.push(Bytecode::LoadConst(Value::String(value.clone())));
+        }
+        ExprKind::Bool(value) => {
+            instructions.push(Bytecode::LoadConst(Value::Bool(*value)));
+        }
+        ExprKind::Ident(name) => {
             // 检查标识符是否是一个函数名
             if functions.contains_key(name) {
                 // 是函数名，生成函数调用指令
@@ -980,511 +1714,459 @@ fn compile_expr_with_register_alloc(expr: &Expr, instructions: &mut Vec<Bytecode
                 }
             }
         }
-        Expr::BinOp(left, op, right) => {
+        ExprKind::Unary(op, operand) => {
+            compile_expr_with_register_alloc(operand, instructions, functions, allocator);
+            instructions.push(match op {
+                UnaryOp::Neg => Bytecode::Neg,
+                UnaryOp::Not => Bytecode::Not,
+                UnaryOp::BitNot => Bytecode::BitNot,
+            });
+        }
+        ExprKind::BinOp(left, op @ (BinOpType::And | BinOpType::Or), right) => {
+            compile_short_circuit(left, matches!(op, BinOpType::And), right, instructions, functions, allocator);
+        }
+        ExprKind::BinOp(left, op, right) => {
             // 编译左右表达式
             compile_expr_with_register_alloc(left, instructions, functions, allocator);
             compile_expr_with_register_alloc(right, instructions, functions, allocator);
             
-            // 生成相应的操作指令
+            // 生成相应的操作指令；两侧类型已知时使用专门的指令
             match op {
+                BinOpType::Plus if left.ty == StaticType::String && right.ty == StaticType::String => instructions.push(Bytecode::Concat),
+                BinOpType::Plus if promotes_to_i64(left, right) => instructions.push(Bytecode::AddI64),
+                BinOpType::Minus if promotes_to_i64(left, right) => instructions.push(Bytecode::SubI64),
+                BinOpType::Mul if promotes_to_i64(left, right) => instructions.push(Bytecode::MulI64),
                 BinOpType::Plus => instructions.push(Bytecode::Add),
                 BinOpType::Minus => instructions.push(Bytecode::Sub),
                 BinOpType::Mul => instructions.push(Bytecode::Mul),
+                BinOpType::Div => instructions.push(Bytecode::Div),
                 BinOpType::Mod => instructions.push(Bytecode::Mod),
                 BinOpType::Le => instructions.push(Bytecode::Le),
                 BinOpType::Lt => instructions.push(Bytecode::Lt),
                 BinOpType::Gt => instructions.push(Bytecode::Gt),
                 BinOpType::Ge => instructions.push(Bytecode::Ge),
                 BinOpType::Eq => instructions.push(Bytecode::Eq),
-            }
-        }
-        Expr::Call(name, args) => {
-            // 检查是否可以内联该函数
-            if let Some(compiled_func) = functions.get(name) {
-                let (param_str, func_code) = compiled_func;
-                let param_count = param_str.split(',').filter(|p| !p.is_empty()).count();
-                
-                // 只有参数数量匹配且函数体较小的函数才内联
-                if args.len() == param_count && func_code.len() < 10 {
-                    // 编译参数（按顺序压入栈）
-                    for arg in args {
-                        compile_expr_with_register_alloc(arg, instructions, functions, allocator);
-                    }
-                    
-                    // 直接内联函数体字节码
-                    for instr in func_code {
-                        instructions.push(instr.clone());
-                    }
-                    return;
+                BinOpType::BitAnd => instructions.push(Bytecode::BitAnd),
+                BinOpType::BitOr => instructions.push(Bytecode::BitOr),
+                BinOpType::BitXor => instructions.push(Bytecode::BitXor),
+                BinOpType::Shl => instructions.push(Bytecode::Shl),
+                BinOpType::Shr => instructions.push(Bytecode::Shr),
+                BinOpType::NotEq => {
+                    instructions.push(Bytecode::Eq);
+                    instructions.push(Bytecode::Not);
                 }
+                BinOpType::And | BinOpType::Or => unreachable!("short-circuit operators compiled above"),
             }
-            
+        }
+        ExprKind::Call(name, args) => {
+            // 不在调用点内联函数体：内联后参数不会绑定到形参，
+            // 函数体中的 Return 也会直接结束调用方
             // 编译参数
             for arg in args {
                 compile_expr_with_register_alloc(arg, instructions, functions, allocator);
             }
             instructions.push(Bytecode::Call(name.clone()));
         }
+        ExprKind::List(items) => {
+            for item in items {
+                compile_expr_with_register_alloc(item, instructions, functions, allocator);
+            }
+            instructions.push(Bytecode::MakeList(items.len()));
+        }
+        ExprKind::Map(entries) => {
+            for (key, value) in entries {
+                compile_expr_with_register_alloc(key, instructions, functions, allocator);
+                compile_expr_with_register_alloc(value, instructions, functions, allocator);
+            }
+            instructions.push(Bytecode::MakeMap(entries.len()));
+        }
+        ExprKind::Index(container, index) => {
+            compile_expr_with_register_alloc(container, instructions, functions, allocator);
+            compile_expr_with_register_alloc(index, instructions, functions, allocator);
+            instructions.push(Bytecode::Index);
+        }
     }
 }
 
+// 两侧都是位宽已知的整数，且提升后的类型是 i64
+fn promotes_to_i64(left: &Expr, right: &Expr) -> bool {
+    match (&left.ty, &right.ty) {
+        (StaticType::Integer(Some(a)), StaticType::Integer(Some(b))) => IntegerType::promote(a, b) == IntegerType::I64,
+        _ => false,
+    }
+}
+
+// `and` 遇到假值、`or` 遇到真值时跳过右侧；两条路径都把 bool 留在栈上
+fn compile_short_circuit(left: &Expr, is_and: bool, right: &Expr, instructions: &mut Vec<Bytecode>, functions: &HashMap<String, (String, Vec<Bytecode>)>, allocator: &mut RegisterAllocator) {
+    let jump: fn(i32) -> Bytecode = if is_and { Bytecode::JumpIfFalse } else { Bytecode::JumpIfTrue };
+    compile_expr_with_register_alloc(left, instructions, functions, allocator);
+    let left_jump = instructions.len();
+    instructions.push(jump(0));
+    compile_expr_with_register_alloc(right, instructions, functions, allocator);
+    let right_jump = instructions.len();
+    instructions.push(jump(0));
+    instructions.push(Bytecode::LoadConst(Value::from_bool(is_and)));
+    let skip = instructions.len();
+    instructions.push(Bytecode::Jump(0));
+    let short = instructions.len();
+    instructions.push(Bytecode::LoadConst(Value::from_bool(!is_and)));
+    patch_jump(instructions, left_jump, short);
+    patch_jump(instructions, right_jump, short);
+    let end = instructions.len();
+    patch_jump(instructions, skip, end);
+}
+
 // 编译表达式
 fn compile_expr(expr: &Expr, instructions: &mut Vec<Bytecode>, functions: &HashMap<String, (String, Vec<Bytecode>)>) {
     let mut allocator = RegisterAllocator::new();
     compile_expr_with_register_alloc(expr, instructions, functions, &mut allocator);
 }
 
-// 执行函数的辅助函数
-fn execute_function(
-    instructions: &[Bytecode],
-    param_str: &str,
-    args: &[u64],
-    functions: &HashMap<String, (String, Vec<Bytecode>)>,
-) -> Result<u64, String> {
-    // 对fibonacci函数使用记忆化优化
-    if !args.is_empty() {
-        // 检查是否为单参数函数（fibonacci通常只有一个参数）
-        let param_count = param_str.split(',').filter(|p| !p.is_empty()).count();
-        if param_count == 1 {
-            // 检查是否有递归调用模式
-            let mut has_recursive_calls = false;
-            for instr in instructions {
-                if let Bytecode::Call(name) = instr {
-                    // 检查是否调用了自身（函数名在functions中）
-                    if functions.contains_key(name) {
-                        has_recursive_calls = true;
-                        break;
-                    }
-                }
-            }
-            
-            // 如果是单参数且有递归调用，使用记忆化优化
-            // 这种检测方法更通用，能覆盖更多fibonacci函数定义形式
-            if has_recursive_calls {
-                return Ok(fibonacci_memoized(args[0]));
-            }
-        }
+// 执行字节码程序
+pub fn execute_bytecode(program: BytecodeProgram) -> Result<(u64, Vec<String>), Diagnostic> {
+    execute_bytecode_with_config(program, VmConfig::default())
+}
+
+pub fn execute_bytecode_with_config(program: BytecodeProgram, config: VmConfig) -> Result<(u64, Vec<String>), Diagnostic> {
+    let mut interpreter = BytecodeInterpreter::with_config(program, config);
+    let result = interpreter.execute()?;
+    Ok((result, interpreter.output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn run_with_config(source: &str, config: VmConfig) -> Result<(u64, Vec<String>), Diagnostic> {
+        let program = parse(tokenize(source).unwrap()).unwrap();
+        execute_bytecode_with_config(compile_to_bytecode(&program), config)
     }
-    
-    // 创建新的栈和变量映射，使用 Value
-    let mut stack = Vec::new();
-    let mut variables = HashMap::new();
-    
-    // 解析参数列表并分配参数值
-    let params: Vec<&str> = param_str.split(',').filter(|p| !p.is_empty()).collect();
-    
-    // 检查参数数量是否匹配
-    if args.len() != params.len() {
-        return Err(format!("Parameter count mismatch: expected {} parameters, got {}", params.len(), args.len()));
+
+    #[test]
+    fn test_recursion_limited_by_max_frames() {
+        let source = "def down n\n    return 1 + down n - 1\nend\nout down 100\n";
+        let err = run_with_config(source, VmConfig { max_frames: 50, ..VmConfig::default() }).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::StackOverflow);
+        assert_eq!(err.span.unwrap().line, 2);
     }
-    
-    // 分配参数值
-    for (i, param) in params.iter().enumerate() {
-        if i < args.len() {
-            let int_val = IntegerValue::from_string(&args[i].to_string(), IntegerType::I64).unwrap();
-            variables.insert(param.to_string(), Value::Integer(int_val));
-        }
+
+    #[test]
+    fn test_deep_recursion_does_not_use_rust_stack() {
+        let source = "def depth n\n    if n == 0\n        return 0\n    end\n    return 1i64 + depth n - 1\nend\nn = 5000i64\nout depth n\n";
+        let (_, output) = run_with_config(source, VmConfig::default()).unwrap();
+        assert_eq!(output, vec!["5000"]);
     }
-    
-    // 模拟寄存器
-    let mut registers: [Option<Value>; 8] = [const { None }; 8];
-    
-    let mut pc = 0;
-    
-    while pc < instructions.len() {
-        let instr = &instructions[pc];
-        pc += 1;
-        
-        match instr {
-            Bytecode::LoadConst(n) => {
-                stack.push(n.clone());
-            }
-            Bytecode::LoadVar(name) => {
-                let value = variables.get(name).cloned().unwrap_or_else(|| {
-                    Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                });
-                stack.push(value);
-            }
-            Bytecode::StoreVar(name) => {
-                let value = stack.pop().unwrap_or_else(|| {
-                    Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                });
-                variables.insert(name.clone(), value);
-            }
-            Bytecode::Add => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        match a + b {
-                            Ok(result) => stack.push(Value::Integer(result)),
-                            Err(_) => stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())),
-                        }
-                    }
-                    _ => {
-                        stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                    }
-                }
-            }
-            Bytecode::Sub => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        match a - b {
-                            Ok(result) => stack.push(Value::Integer(result)),
-                            Err(_) => stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())),
-                        }
-                    }
-                    _ => {
-                        stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                    }
-                }
-            }
-            Bytecode::Mul => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        match a * b {
-                            Ok(result) => stack.push(Value::Integer(result)),
-                            Err(_) => stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())),
-                        }
-                    }
-                    _ => {
-                        stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                    }
-                }
-            }
-            Bytecode::Mod => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        match a % b {
-                            Ok(result) => stack.push(Value::Integer(result)),
-                            Err(_) => stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())),
-                        }
-                    }
-                    _ => {
-                        stack.push(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                    }
-                }
-            }
-            Bytecode::Le => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let result = match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        if a <= b { 
-                            Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                        } else { 
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                        }
-                    }
-                    _ => {
-                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                    }
-                };
-                stack.push(result);
-            }
-            Bytecode::Lt => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let result = match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        if a < b { 
-                            Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                        } else { 
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                        }
-                    }
-                    _ => {
-                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                    }
-                };
-                stack.push(result);
-            }
-            Bytecode::Gt => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let result = match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        if a > b { 
-                            Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                        } else { 
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                        }
-                    }
-                    _ => {
-                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                    }
-                };
-                stack.push(result);
-            }
-            Bytecode::Ge => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let result = match (a, b) {
-                    (Value::Integer(a), Value::Integer(b)) => {
-                        if a >= b { 
-                            Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                        } else { 
-                            Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                        }
-                    }
-                    _ => {
-                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                    }
-                };
-                stack.push(result);
-            }
-            Bytecode::Eq => {
-                let b = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let a = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                let result = if a == b { 
-                    Value::Integer(IntegerValue::from_string("1", IntegerType::I64).unwrap()) 
-                } else { 
-                    Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()) 
-                };
-                stack.push(result);
-            }
-            Bytecode::Jump(offset) => {
-                pc = (pc as i32 + offset) as usize;
-            }
-            Bytecode::JumpIfFalse(offset) => {
-                let value = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                // 检查值是否为零
-                let is_false = match value {
-                    Value::Integer(IntegerValue::I8(v)) => v == 0,
-                    Value::Integer(IntegerValue::I16(v)) => v == 0,
-                    Value::Integer(IntegerValue::I32(v)) => v == 0,
-                    Value::Integer(IntegerValue::I64(v)) => v == 0,
-                    Value::Integer(IntegerValue::I128(v)) => v == 0,
-                    Value::Integer(IntegerValue::BigInt(v)) => v == num_bigint::BigInt::from(0),
-                    Value::String(_) => true, // 非整数类型视为false
-                };
-                if is_false {
-                    pc = (pc as i32 + offset) as usize;
-                }
-            }
-            Bytecode::Call(name) => {
-                if let Some((param_str, func_code)) = functions.get(name) {
-                    // 解析参数数量
-                    let param_count = param_str.split(',').filter(|p| !p.is_empty()).count();
-                    
-                    // 从栈中获取参数并转换为 u64
-                    let mut call_args = Vec::new();
-                    for _ in 0..param_count {
-                        let value = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                        let arg_value = match value {
-                            Value::Integer(v) => match v.to_i64() {
-                                Ok(v) => v as u64,
-                                Err(_) => 0,
-                            },
-                            _ => 0,
-                        };
-                        call_args.insert(0, arg_value);
-                    }
-                    
-                    let result = execute_function(func_code, param_str, &call_args, functions)?;
-                    // 将结果转换回 Value
-                    let result_value = Value::Integer(IntegerValue::from_string(&result.to_string(), IntegerType::I64).unwrap());
-                    stack.push(result_value);
-                } else {
-                    return Err(format!("Function not found: {}", name));
-                }
-            }
-            Bytecode::TailCall(name) => {
-                if let Some((param_str, func_code)) = functions.get(name) {
-                    // 解析参数数量
-                    let param_count = param_str.split(',').filter(|p| !p.is_empty()).count();
-                    
-                    // 从栈中获取参数并转换为 u64
-                    let mut call_args = Vec::new();
-                    for _ in 0..param_count {
-                        let value = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                        let arg_value = match value {
-                            Value::Integer(v) => match v.to_i64() {
-                                Ok(v) => v as u64,
-                                Err(_) => 0,
-                            },
-                            _ => 0,
-                        };
-                        call_args.insert(0, arg_value);
-                    }
-                    
-                    // 尾调用优化：直接返回函数结果
-                    let result = execute_function(func_code, param_str, &call_args, functions)?;
-                    return Ok(result);
-                } else {
-                    return Err(format!("Function not found: {}", name));
-                }
-            }
-            Bytecode::Return => {
-                let value = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                // For compatibility, convert to u64 if possible
-                match value {
-                    Value::Integer(v) => match v.to_i64() {
-                        Ok(v) => return Ok(v as u64),
-                        Err(_) => return Ok(0),
-                    },
-                    _ => return Ok(0),
-                }
-            }
-            Bytecode::Out => {
-                let value = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-                println!("{}", value);
-            }
-            Bytecode::LoadReg(reg_idx, var_name) => {
-                if *reg_idx < 8 {
-                    let value = variables.get(var_name).cloned().unwrap_or_else(|| {
-                        Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                    });
-                    registers[*reg_idx as usize] = Some(value.clone());
-                    stack.push(value);
-                }
-            }
-            Bytecode::StoreReg(var_name, reg_idx) => {
-                if *reg_idx < 8 {
-                    if let Some(value) = registers[*reg_idx as usize].clone() {
-                        variables.insert(var_name.clone(), value);
-                    }
-                }
-            }
-            Bytecode::AddReg(reg1, reg2) => {
-                if *reg1 < 8 && *reg2 < 8 {
-                    if let (Some(a), Some(b)) = (registers[*reg1 as usize].clone(), registers[*reg2 as usize].clone()) {
-                        match (a, b) {
-                            (Value::Integer(a), Value::Integer(b)) => {
-                                match a + b {
-                                    Ok(result) => {
-                                        let result_value = Value::Integer(result);
-                                        registers[*reg1 as usize] = Some(result_value.clone());
-                                        stack.push(result_value);
-                                    }
-                                    Err(_) => {
-                                        let zero = Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap());
-                                        registers[*reg1 as usize] = Some(zero.clone());
-                                        stack.push(zero);
-                                    }
-                                }
-                            }
-                            _ => {
-                                let zero = Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap());
-                                registers[*reg1 as usize] = Some(zero.clone());
-                                stack.push(zero);
-                            }
-                        }
-                    }
-                }
-            }
-            Bytecode::SubReg(reg1, reg2) => {
-                if *reg1 < 8 && *reg2 < 8 {
-                    if let (Some(a), Some(b)) = (registers[*reg1 as usize].clone(), registers[*reg2 as usize].clone()) {
-                        match (a, b) {
-                            (Value::Integer(a), Value::Integer(b)) => {
-                                match a - b {
-                                    Ok(result) => {
-                                        let result_value = Value::Integer(result);
-                                        registers[*reg1 as usize] = Some(result_value.clone());
-                                        stack.push(result_value);
-                                    }
-                                    Err(_) => {
-                                        let zero = Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap());
-                                        registers[*reg1 as usize] = Some(zero.clone());
-                                        stack.push(zero);
-                                    }
-                                }
-                            }
-                            _ => {
-                                let zero = Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap());
-                                registers[*reg1 as usize] = Some(zero.clone());
-                                stack.push(zero);
-                            }
-                        }
-                    }
-                }
-            }
-            Bytecode::MulReg(reg1, reg2) => {
-                if *reg1 < 8 && *reg2 < 8 {
-                    if let (Some(a), Some(b)) = (registers[*reg1 as usize].clone(), registers[*reg2 as usize].clone()) {
-                        match (a, b) {
-                            (Value::Integer(a), Value::Integer(b)) => {
-                                match a * b {
-                                    Ok(result) => {
-                                        let result_value = Value::Integer(result);
-                                        registers[*reg1 as usize] = Some(result_value.clone());
-                                        stack.push(result_value);
-                                    }
-                                    Err(_) => {
-                                        let zero = Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap());
-                                        registers[*reg1 as usize] = Some(zero.clone());
-                                        stack.push(zero);
-                                    }
-                                }
-                            }
-                            _ => {
-                                let zero = Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap());
-                                registers[*reg1 as usize] = Some(zero.clone());
-                                stack.push(zero);
-                            }
-                        }
-                    }
-                }
-            }
-            Bytecode::FuncDef(_, _, _) => {
-                // 函数定义在编译时已处理，运行时忽略
-            }
-        }
+
+    #[test]
+    fn test_tail_call_reuses_frame() {
+        let source = "def loop_to n, acc\n    if n == 0\n        return acc\n    end\n    return loop_to n - 1, acc + 1\nend\nn = 20000i64\nacc = 0i64\nout loop_to n, acc\n";
+        let (_, output) = run_with_config(source, VmConfig { max_frames: 4, ..VmConfig::default() }).unwrap();
+        assert_eq!(output, vec!["20000"]);
     }
-    
-    let value = stack.pop().unwrap_or_else(|| Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
-    // For compatibility, convert to u64 if possible
-    match value {
-        Value::Integer(v) => match v.to_i64() {
-            Ok(v) => Ok(v as u64),
-            Err(_) => Ok(0),
-        },
-        _ => Ok(0),
+
+    #[test]
+    fn test_memo_makes_exponential_recursion_linear() {
+        let source = "@memo\ndef fib n\n    if n < 2\n        return n\n    end\n    a = fib n - 1\n    b = fib n - 2\n    return a + b\nend\nn = 90i64\nout fib n\n";
+        let (_, output) = run_with_config(source, VmConfig::default()).unwrap();
+        assert_eq!(output, vec!["2880067194370816120"]);
     }
-}
 
-// 使用记忆化的fibonacci实现
-fn fibonacci_memoized(n: u64) -> u64 {
-    fn fib_helper(n: u64, memo: &mut HashMap<u64, u64>) -> u64 {
-        if let Some(&result) = memo.get(&n) {
-            return result;
-        }
-        
-        let result = if n <= 1 {
-            n
-        } else {
-            fib_helper(n - 1, memo) + fib_helper(n - 2, memo)
-        };
-        
-        memo.insert(n, result);
-        result
+    #[test]
+    fn test_jit_matches_interpreter() {
+        let source = "def fib n\n    if n < 2\n        return n\n    end\n    a = fib n - 1\n    b = fib n - 2\n    return a + b\nend\ndef square n\n    return n * n\nend\nn = 25i64\nout fib n\nbig = 4000000000i64\nout square big\nout fib 10\n";
+        let jit = VmConfig { jit_threshold: 1, ..VmConfig::default() };
+        let (_, interpreted) = run_with_config(source, VmConfig { jit: false, ..VmConfig::default() }).unwrap();
+        let (_, compiled) = run_with_config(source, jit).unwrap();
+        // 溢出时回退到解释器，结果同样提升为大整数
+        assert_eq!(interpreted, vec!["75025", "16000000000000000000", "55"]);
+        assert_eq!(compiled, interpreted);
     }
-    
-    let mut memo = HashMap::new();
-    fib_helper(n, &mut memo)
-}
 
-// 执行字节码程序
-pub fn execute_bytecode(program: BytecodeProgram) -> Result<(u64, Vec<String>), String> {
-    // 获取内存池
-    let memory_pool = get_interpreter_pool();
-    
-    let mut interpreter = BytecodeInterpreter {
-        stack: Vec::new(),
-        variables: HashMap::new(),
-        registers: [const { None }; 8],
-        program,
-        pc: 0,
-        output: Vec::new(),
-        memory_pool,
-    };
-    let result = interpreter.execute()?;
-    Ok((result, interpreter.output))
+    #[test]
+    fn test_jit_respects_max_frames() {
+        let source = "def down n\n    if n == 0\n        return n\n    end\n    return n + down n - 1\nend\nn = 100i64\nout down n\n";
+        let err = run_with_config(source, VmConfig { max_frames: 50, jit: true, jit_threshold: 1 }).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::StackOverflow);
+        let (_, output) = run_with_config(source, VmConfig { max_frames: 200, jit: true, jit_threshold: 1 }).unwrap();
+        assert_eq!(output, vec!["5050"]);
+    }
+
+    fn run_tiered(source: &str, threshold: u32) -> BytecodeInterpreter {
+        let program = compile_to_bytecode(&parse(tokenize(source).unwrap()).unwrap());
+        let mut interpreter = BytecodeInterpreter::with_config(program, VmConfig { jit_threshold: threshold, ..VmConfig::default() });
+        interpreter.execute().unwrap();
+        interpreter
+    }
+
+    #[test]
+    fn test_hot_loop_enters_native_code() {
+        let source = "def sum_to n\n    acc = 0i64\n    while n > 0\n        acc += n\n        n -= 1\n    end\n    return acc\nend\nn = 100000i64\nout sum_to n\n";
+        let interpreter = run_tiered(source, 100);
+        assert_eq!(interpreter.output, vec!["5000050000"]);
+        assert!(interpreter.jit.as_ref().unwrap().is_compiled("sum_to"));
+    }
+
+    #[test]
+    fn test_unsuffixed_loop_enters_native_code() {
+        let source = "def loop n\n    i = 0\n    acc = 0\n    while i < n\n        acc += i % 2\n        i += 1\n    end\n    return acc\nend\nout loop 100\n";
+        let interpreter = run_tiered(source, 10);
+        assert_eq!(interpreter.output, vec!["50"]);
+        assert!(interpreter.jit.as_ref().unwrap().is_compiled("loop"));
+
+        // 本地代码返回的 i8 结果左移时和解释器一样在 8 位内丢弃高位
+        let source = "def next n\n    return n + 1\nend\ni = 0\nwhile i < 10\n    i = next i\nend\ny = next 100\nout y << 5\n";
+        let (_, interpreted) = run_with_config(source, VmConfig { jit: false, ..VmConfig::default() }).unwrap();
+        let interpreter = run_tiered(source, 1);
+        assert_eq!(interpreted, vec!["-96"]);
+        assert_eq!(interpreter.output, interpreted);
+        assert!(interpreter.jit.as_ref().unwrap().is_compiled("next"));
+
+        // 超出 i8 时本地代码回退，由解释器报告溢出
+        let source = format!("{}out next 127\n", source);
+        let err = run_with_config(&source, VmConfig { jit_threshold: 1, ..VmConfig::default() }).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Overflow);
+        assert_eq!(err.message, "Addition overflow for i8: 127 + 1");
+    }
+
+    #[test]
+    fn test_hot_loop_deopts_on_overflow() {
+        let source = "def grow n\n    x = 1i64\n    while n > 0\n        x = x * 3\n        n -= 1\n    end\n    return x\nend\nn = 50i64\nout grow n\n";
+        let interpreter = run_tiered(source, 5);
+        assert_eq!(interpreter.output, vec!["717897987691852588770249"]);
+    }
+
+    #[test]
+    fn test_hot_function_compiled_after_threshold() {
+        let source = "def fib n\n    if n < 2\n        return n\n    end\n    a = fib n - 1\n    b = fib n - 2\n    return a + b\nend\ndef show n\n    out n\n    return n\nend\ni = 0i64\nwhile i < 20\n    x = show i\n    i += 1\nend\nn = 20i64\nout fib n\n";
+        let interpreter = run_tiered(source, 10);
+        assert_eq!(interpreter.output.len(), 21);
+        assert_eq!(interpreter.output[20], "6765");
+        let jit = interpreter.jit.as_ref().unwrap();
+        assert!(jit.is_compiled("fib"));
+        // 含 `out` 的函数留在解释器中执行
+        assert!(!jit.is_compiled("show"));
+    }
+
+    #[test]
+    fn test_division_by_zero_is_runtime_error() {
+        let err = run_with_config("x = 0\nout 10 / x\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+        assert_eq!(err.span.unwrap().line, 2);
+        let err = run_with_config("x = 0\nout 10 % x\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+
+        // 本地代码遇到除零时回退，由解释器报告同样的错误
+        let source = "def div a, b\n    return a / b\nend\na = 10i64\nb = 5i64\nout div a, b\nb = 0i64\nout div a, b\n";
+        let err = run_with_config(source, VmConfig { jit_threshold: 1, ..VmConfig::default() }).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+        assert_eq!(err.span.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_bool_arithmetic_is_type_error() {
+        let err = run_with_config("b = 1 < 2\nout b + 1\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::TypeMismatch);
+        assert_eq!(err.message, "Unsupported operand types for +: bool and integer");
+    }
+
+    #[test]
+    fn test_shift_out_of_range_is_runtime_error() {
+        let err = run_with_config("x = 1
+n = 8
+out x << n
+", VmConfig::default()).unwrap_err();
+        assert_eq!(err.message, "Shift amount 8 out of range for i8");
+        assert_eq!(err.span.unwrap().line, 3);
+
+        // 本地代码遇到越界的移位量时回退
+        let source = "def shl a, b
+    return a << b
+end
+a = 1i64
+b = 3i64
+out shl a, b
+b = 64i64
+out shl a, b
+";
+        let err = run_with_config(source, VmConfig { jit_threshold: 1, ..VmConfig::default() }).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfRange);
+        assert_eq!(err.span.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_list_errors_report_line() {
+        let err = run_with_config("a = [1, 2]\nout a[1]\nout a[2]\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfRange);
+        assert_eq!(err.span.unwrap().line, 3);
+
+        let err = run_with_config("a = []\npop a\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.message, "Cannot pop from an empty list");
+        let err = run_with_config("x = 5\nout x[0]\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.message, "Cannot index into integer");
+    }
+
+    #[test]
+    fn test_undefined_variable_is_runtime_error() {
+        let err = run_with_config("x = 1\nout y\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UndefinedVariable);
+        assert_eq!(err.message, "Undefined variable: y");
+        assert_eq!(err.span.unwrap().line, 2);
+        // 声明为 global 但从未赋值的模块级变量
+        let err = run_with_config("def f\n    global g\n    return g\nend\nout f\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.message, "Undefined variable: g");
+    }
+
+    #[test]
+    fn test_functions_using_globals_stay_interpreted() {
+        let source = "total = 0i64
+def add n
+    global total
+    total += n
+    return total
+end
+i = 0i64
+while i < 300
+    x = add i
+    i += 1
+end
+out total
+";
+        let interpreter = run_tiered(source, 100);
+        assert_eq!(interpreter.output, vec!["44850"]);
+        assert!(!interpreter.jit.as_ref().unwrap().is_compiled("add"));
+    }
+
+    #[test]
+    fn test_float_functions_stay_interpreted() {
+        // 浮点参数不会进入本地代码，热函数照常在解释器中运行
+        let source = "def half x
+    return x / 2.0
+end
+i = 0
+while i < 5
+    out half i
+    i += 1
+end
+";
+        let (_, output) = run_with_config(source, VmConfig { jit_threshold: 1, ..VmConfig::default() }).unwrap();
+        assert_eq!(output, vec!["0.0", "0.5", "1.0", "1.5", "2.0"]);
+
+        let err = run_with_config("x = 1.0\nout x / 0\n", VmConfig::default()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+        assert_eq!(err.span.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_for_range_loop_enters_native_code() {
+        // `step` 的赋值外提到循环之前，计数器是隐藏的 i64 变量
+        let source = "def evens n
+    acc = 0i64
+    for i in 0..n
+        step = 2i64
+        acc += i * step
+    end
+    return acc
+end
+n = 1000i64
+out evens n
+for i in 0..3
+    out i
+end
+";
+        let interpreter = run_tiered(source, 100);
+        assert_eq!(interpreter.output, vec!["999000", "0", "1", "2"]);
+        assert!(interpreter.jit.as_ref().unwrap().is_compiled("evens"));
+
+        let err = run_with_config("x = 1.5
+for i in 0..x
+    out i
+end
+", VmConfig::default()).unwrap_err();
+        assert_eq!(err.message, "Range bounds must be integers, found float");
+        assert_eq!(err.span.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_break_and_continue_run_natively() {
+        let source = "def count_skipping n
+    total = 0i64
+    i = 0i64
+    while true
+        i += 1
+        if i > n
+            break
+        end
+        if i % 3 == 0
+            continue
+        end
+        total += i
+    end
+    return total
+end
+n = 3000i64
+out count_skipping n
+";
+        let interpreter = run_tiered(source, 100);
+        assert_eq!(interpreter.output, vec!["3000000"]);
+        assert!(interpreter.jit.as_ref().unwrap().is_compiled("count_skipping"));
+    }
+
+    #[test]
+    fn test_inferred_strings_use_concat() {
+        let source = "def greet name
+    return \"hi \" + name
+end
+s = \"a\"
+t = s + \"b\"
+out t
+out greet t
+";
+        let program = crate::semantic::analyze(parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        let compiled = compile_to_bytecode(&program);
+        // 顶层的 `s + "b"` 两侧都是字符串；形参 `name` 的类型未知，仍用通用的加法
+        assert!(compiled.instructions.iter().any(|instr| matches!(instr, Bytecode::Concat)));
+        assert!(!compiled.compiled_functions["greet"].instructions.iter().any(|instr| matches!(instr, Bytecode::Concat)));
+        let (_, output) = execute_bytecode(compiled).unwrap();
+        assert_eq!(output, vec!["ab", "hi ab"]);
+    }
+
+    #[test]
+    fn test_inferred_integers_use_i64_instructions() {
+        let source = "def sum_to n
+    acc = 0i64
+    i = 0i64
+    while i < n
+        acc += i
+        i += 1
+    end
+    return acc
+end
+x = 4000000000i64
+out x * 3
+out x * x
+out sum_to 100000
+";
+        let compile = || compile_to_bytecode(&crate::semantic::analyze(parse(tokenize(source).unwrap()).unwrap()).unwrap());
+        let compiled = compile();
+        // `i += 1` 中的 1 是 i8，提升后仍是 i64；形参 `n` 的类型未知，比较不受影响
+        let sum_to = &compiled.compiled_functions["sum_to"].instructions;
+        assert_eq!(sum_to.iter().filter(|instr| matches!(instr, Bytecode::AddI64)).count(), 2);
+        assert!(!sum_to.iter().any(|instr| matches!(instr, Bytecode::Add)));
+        assert_eq!(compiled.instructions.iter().filter(|instr| matches!(instr, Bytecode::MulI64)).count(), 2);
+        // 乘积溢出时仍提升为 BigInt；解释器和本地代码结果一致
+        let expected = vec!["12000000000", "16000000000000000000", "4999950000"];
+        let (_, output) = execute_bytecode_with_config(compiled, VmConfig { jit: false, ..VmConfig::default() }).unwrap();
+        assert_eq!(output, expected);
+        let mut interpreter = BytecodeInterpreter::with_config(compile(), VmConfig { jit_threshold: 100, ..VmConfig::default() });
+        interpreter.execute().unwrap();
+        assert_eq!(interpreter.output, expected);
+        assert!(interpreter.jit.as_ref().unwrap().is_compiled("sum_to"));
+
+        let source = "x = 9223372036854775807i64\nout x + 1\n";
+        let program = crate::semantic::analyze(parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        let err = execute_bytecode(compile_to_bytecode(&program)).unwrap_err();
+        assert_eq!(err.message, "Addition overflow for i64: 9223372036854775807 + 1");
+    }
 }
@@ -1,11 +1,12 @@
 use crate::ast::Program;
+use crate::diagnostic::Diagnostic;
 
 #[derive(Debug)]
 pub struct IR {
     pub program: Program,
 }
 
-pub fn generate(program: Program) -> Result<IR, String> {
+pub fn generate(program: Program) -> Result<IR, Diagnostic> {
     Ok(IR {
         program,
     })
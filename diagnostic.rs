@@ -0,0 +1,267 @@
+// 源码位置与诊断信息
+
+use std::fmt;
+
+// 源码区间：字节偏移 + 起始行列（行列均从1开始）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, col: usize) -> Self {
+        Span { start, end, line, col }
+    }
+
+    // 合并两个区间，结果从 self 开始到 other 结束
+    pub fn to(&self, other: Span) -> Span {
+        if other.end <= self.start {
+            return *self;
+        }
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            col: self.col,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+// 行首偏移表，用于把字节偏移换算成行列
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let mut line_starts = vec![0];
+        for (i, b) in source.bytes().enumerate() {
+            if b == b'\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        LineIndex { line_starts }
+    }
+
+    pub fn span(&self, source: &str, range: std::ops::Range<usize>) -> Span {
+        let line_idx = match self.line_starts.binary_search(&range.start) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1,
+        };
+        let line_start = self.line_starts[line_idx];
+        // 列号按字符计算，避免多字节字符导致错位
+        let col = source[line_start..range.start].chars().count() + 1;
+        Span::new(range.start, range.end, line_idx + 1, col)
+    }
+}
+
+// 产生诊断的编译/执行阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    Lexer,
+    Parser,
+    Semantic,
+    Codegen,
+    Runtime,
+    Jit,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Lexer => "lexer",
+            Stage::Parser => "parser",
+            Stage::Semantic => "semantic",
+            Stage::Codegen => "codegen",
+            Stage::Runtime => "runtime",
+            Stage::Jit => "jit",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// 错误类别，供嵌入方和测试按类别匹配
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    UnexpectedCharacter,
+    UnexpectedToken,
+    UnexpectedEof,
+    UndefinedVariable,
+    UndefinedFunction,
+    DuplicateDefinition,
+    ArityMismatch,
+    TypeMismatch,
+    Overflow,
+    DivisionByZero,
+    StackOverflow,
+    OutOfRange,
+    KeyNotFound,
+    // `break`/`continue` 出现在循环之外
+    OutsideLoop,
+    // `global` 用在函数之外或声明了形参
+    InvalidGlobal,
+    InvalidLiteral,
+    InvalidAnnotation,
+    JitFailure,
+    Internal,
+}
+
+// 严重程度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// 诊断信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub stage: Stage,
+    pub kind: ErrorKind,
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+}
+
+// 整个流水线统一使用的错误类型
+pub type SylphError = Diagnostic;
+
+impl Diagnostic {
+    pub fn new(stage: Stage, kind: ErrorKind, message: impl Into<String>) -> Self {
+        Diagnostic {
+            stage,
+            kind,
+            severity: Severity::Error,
+            message: message.into(),
+            span: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn at(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    // 仅在尚未记录位置时补充位置信息
+    pub fn or_span(mut self, span: Option<Span>) -> Self {
+        if self.span.is_none() {
+            self.span = span;
+        }
+        self
+    }
+
+    // 渲染为 `file:line:col` 格式，并附带出错的源码行和插入符
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let mut out = match self.span {
+            Some(span) => self.render_snippet(file, source, span),
+            None => format!("{}: {}: {}", file, self.severity, self.message),
+        };
+        for note in &self.notes {
+            out.push_str(&format!("\n  = note: {}", note));
+        }
+        out
+    }
+
+    fn render_snippet(&self, file: &str, source: &str, span: Span) -> String {
+        let line_text = source.lines().nth(span.line - 1).unwrap_or("");
+        let gutter = span.line.to_string().len();
+        let line_len = line_text.chars().count();
+        let caret_start = (span.col - 1).min(line_len);
+        let span_len = source
+            .get(span.start..span.end)
+            .map(|s| s.lines().next().unwrap_or("").chars().count())
+            .unwrap_or(0);
+        let caret_len = span_len.min(line_len.saturating_sub(caret_start)).max(1);
+
+        let mut out = String::new();
+        out.push_str(&format!("{}:{}:{}: {}: {}\n", file, span.line, span.col, self.severity, self.message));
+        out.push_str(&format!("{} |\n", " ".repeat(gutter)));
+        out.push_str(&format!("{} | {}\n", span.line, line_text));
+        // 保留制表符，使插入符与源码对齐
+        let padding: String = line_text
+            .chars()
+            .take(caret_start)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("{} | {}{}", " ".repeat(gutter), padding, "^".repeat(caret_len)));
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}: {}", span, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_index_span() {
+        let source = "x = 1\nout  y\n";
+        let index = LineIndex::new(source);
+        let span = index.span(source, 11..12);
+        assert_eq!(span.line, 2);
+        assert_eq!(span.col, 6);
+    }
+
+    #[test]
+    fn test_render_with_caret() {
+        let source = "x = 1\nout foo\n";
+        let index = LineIndex::new(source);
+        let diag = Diagnostic::new(Stage::Semantic, ErrorKind::UndefinedVariable, "Undefined variable or function: foo")
+            .at(index.span(source, 10..13));
+        let rendered = diag.render("main.syl", source);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines[0], "main.syl:2:5: error: Undefined variable or function: foo");
+        assert_eq!(lines[2], "2 | out foo");
+        assert_eq!(lines[3], "  |     ^^^");
+    }
+
+    #[test]
+    fn test_render_notes() {
+        let diag = Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero")
+            .with_note("the right operand evaluated to 0");
+        assert_eq!(
+            diag.render("main.syl", ""),
+            "main.syl: error: Division by zero\n  = note: the right operand evaluated to 0"
+        );
+    }
+}
@@ -1,269 +1,610 @@
 use crate::codegen::IR;
-use crate::ast::{Program, Stmt, Expr, BinOpType};
-use crate::jit;
+use crate::diagnostic::Diagnostic;
 use crate::bytecode;
-use crate::types::{IntegerValue, IntegerType};
-use std::collections::HashMap;
 
-// 执行上下文，用于存储变量
-struct ExecutionContext {
-    variables: HashMap<String, IntegerValue>,
-    expr_cache: HashMap<*const Expr, *const u8>,
-}
-
-impl ExecutionContext {
-    fn new() -> Self {
-        ExecutionContext {
-            variables: HashMap::new(),
-            expr_cache: HashMap::new(),
-        }
-    }
-    
-    fn set_variable(&mut self, name: &str, value: IntegerValue) {
-        self.variables.insert(name.to_string(), value);
-    }
-    
-    fn get_variable(&self, name: &str) -> Option<IntegerValue> {
-        self.variables.get(name).cloned()
-    }
-    
-    fn get_cached_expr(&self, expr: &Expr) -> Option<*const u8> {
-        let expr_ptr = expr as *const Expr;
-        self.expr_cache.get(&expr_ptr).copied()
-    }
-    
-    fn cache_expr(&mut self, expr: &Expr, addr: *const u8) {
-        let expr_ptr = expr as *const Expr;
-        self.expr_cache.insert(expr_ptr, addr);
-    }
-}
-
-pub fn execute(ir: IR) -> Result<(u64, Vec<String>), String> {
-    // 检查是否是斐波那契测试程序
-    if is_fibonacci_test(&ir.program) {
-        // 优先使用JIT执行斐波那契函数
-        if let Some(fib_call) = find_fibonacci_call(&ir.program) {
-            if let Some(n) = extract_fibonacci_arg(fib_call) {
-                match jit::jit_execute_fibonacci(n) {
-                    Ok(result) => {
-                        return Ok((result, vec![result.to_string()]));
-                    }
-                    Err(_) => {
-                        // JIT执行失败，回退到字节码解释器
-                    }
-                }
-            }
-        }
-    }
-    
+pub fn execute(ir: IR) -> Result<(u64, Vec<String>), Diagnostic> {
     // 使用字节码解释器执行程序
     let bytecode_program = bytecode::compile_to_bytecode(&ir.program);
     bytecode::execute_bytecode(bytecode_program)
 }
 
-// 检查是否是斐波那契测试程序
-fn is_fibonacci_test(program: &Program) -> bool {
-    // 检查是否包含斐波那契函数调用
-    program.statements.iter().any(|stmt| {
-        matches!(stmt, Stmt::Out(expr) if contains_fibonacci_call(expr)) ||
-        matches!(stmt, Stmt::Return(expr) if contains_fibonacci_call(expr)) ||
-        matches!(stmt, Stmt::Assign(_, expr) if contains_fibonacci_call(expr))
-    })
-}
+// 树遍历解释器，只在测试中用作字节码解释器的对照
+#[cfg(test)]
+mod reference {
+    use crate::{
+        ast::{Stmt, StmtKind, Expr, ExprKind, BinOpType, ForTarget, UnaryOp},
+        builtins,
+        bytecode,
+        diagnostic::{Diagnostic, ErrorKind, Stage},
+        semantic::FunctionScope,
+        types::{IntegerValue, IntegerType, ListValue, MapValue, Value, promote_float, range_bounds, type_mismatch},
+    };
+    use std::{collections::HashMap, rc::Rc};
 
-// 检查表达式是否包含斐波那契函数调用
-fn contains_fibonacci_call(expr: &Expr) -> bool {
-    match expr {
-        Expr::Call(name, _) => name == "fibonacci",
-        Expr::BinOp(left, _, right) => contains_fibonacci_call(left) || contains_fibonacci_call(right),
-        _ => false,
+    // `break`/`continue` 触发后逐层退出语句块，直到最近的循环
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum LoopExit {
+        Break,
+        Continue,
     }
-}
 
-// 查找斐波那契函数调用
-fn find_fibonacci_call(program: &Program) -> Option<&Expr> {
-    for stmt in &program.statements {
-        match stmt {
-            Stmt::Out(expr) if contains_fibonacci_call(expr) => return Some(expr),
-            Stmt::Return(expr) if contains_fibonacci_call(expr) => return Some(expr),
-            Stmt::Assign(_, expr) if contains_fibonacci_call(expr) => return Some(expr),
-            _ => {}
-        }
-    }
-    None
-}
+    // 用户函数：形参、函数体和作用域
+    type Function<'a> = (&'a [String], &'a [Stmt], Rc<FunctionScope>);
 
-// 提取斐波那契函数的参数
-fn extract_fibonacci_arg(expr: &Expr) -> Option<u64> {
-    match expr {
-        Expr::Call(name, args) if name == "fibonacci" && !args.is_empty() => {
-            // 尝试提取常量参数
-            match &args[0] {
-                Expr::Number(int_val) => int_val.to_i64().ok().map(|v| v as u64),
-                Expr::TypedNumber(int_val) => int_val.to_i64().ok().map(|v| v as u64),
-                _ => None,
-            }
-        }
-        Expr::BinOp(_, _, right) => extract_fibonacci_arg(right),
-        _ => None,
+    // 执行上下文，用于存储变量
+    pub(super) struct ExecutionContext<'a> {
+        globals: HashMap<String, Value>,  // 模块级变量
+        variables: HashMap<String, Value>,  // 当前函数的局部变量
+        scope: Option<Rc<FunctionScope>>,  // 当前函数的作用域；顶层代码为 None
+        functions: HashMap<String, Function<'a>>,
+        pub(super) output: Vec<String>,
+        returning: bool,  // 遇到 return 后逐层退出语句块
+        loop_exit: Option<LoopExit>,
     }
-}
 
-fn execute_program(program: &Program, context: &mut ExecutionContext) -> Result<IntegerValue, String> {
-    // 查找main函数
-    for stmt in &program.statements {
-        if let Stmt::FuncDef(name, _, body) = stmt {
-            if name == "main" {
-                return execute_statements(body, context);
+    impl<'a> ExecutionContext<'a> {
+        pub(super) fn new() -> Self {
+            ExecutionContext {
+                globals: HashMap::new(),
+                variables: HashMap::new(),
+                scope: None,
+                functions: HashMap::new(),
+                output: Vec::new(),
+                returning: false,
+                loop_exit: None,
             }
         }
-    }
-    
-    // 如果没有main函数，执行所有顶级语句
-    execute_statements(&program.statements, context)
-}
 
-fn execute_statements(statements: &[Stmt], context: &mut ExecutionContext) -> Result<IntegerValue, String> {
-    let mut last_result = IntegerValue::from_string("0", IntegerType::I64).unwrap();
-    
-    for stmt in statements {
-        match stmt {
-            Stmt::Assign(name, expr) => {
-                let value = evaluate_expr(expr, context)?;
-                context.set_variable(name, value.clone());
-                last_result = value;
-            }
-            Stmt::Out(expr) => {
-                let value = evaluate_expr(expr, context)?;
-                last_result = value;
-            }
-            Stmt::Return(expr) => {
-                let value = evaluate_expr(expr, context)?;
-                return Ok(value);
+        // 预先登记顶层函数，与字节码编译器一样允许先调用后定义
+        pub(super) fn register_functions(&mut self, statements: &'a [Stmt]) {
+            for stmt in statements {
+                if let StmtKind::FuncDef(name, params, _, body, _) = &stmt.kind {
+                    let scope = Rc::new(FunctionScope::new(params, body));
+                    self.functions.insert(name.clone(), (params, body, scope));
+                }
             }
-            _ => {
-                // 忽略其他类型的语句
+        }
+        
+        // 顶层代码和 `global` 声明的名字写入模块级变量
+        fn set_variable(&mut self, name: &str, value: Value) {
+            match &self.scope {
+                Some(scope) if !scope.globals.contains(name) => self.variables.insert(name.to_string(), value),
+                _ => self.globals.insert(name.to_string(), value),
+            };
+        }
+        
+        // 函数中没有赋值过的名字读取模块级变量
+        fn get_variable(&self, name: &str) -> Option<Value> {
+            match &self.scope {
+                Some(scope) if scope.is_local(name) => self.variables.get(name).cloned(),
+                _ => self.globals.get(name).cloned(),
             }
         }
     }
-    
-    Ok(last_result)
-}
 
-fn evaluate_expr(expr: &Expr, context: &mut ExecutionContext) -> Result<IntegerValue, String> {
-    // 检查缓存中是否有已编译的表达式
-    if let Some(addr) = context.get_cached_expr(expr) {
-        // 使用JIT执行已编译的表达式
-        let jit = jit::JITCompiler::new()?;
-        match jit.execute(addr, &[]) {
-            Ok(v) => {
-                // 将结果转换为 IntegerValue
-                Ok(IntegerValue::from_string(&v.to_string(), IntegerType::I64).unwrap())
+    pub(super) fn execute_statements(statements: &[Stmt], context: &mut ExecutionContext) -> Result<Value, Diagnostic> {
+        let mut last_result = Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap());
+        
+        for stmt in statements {
+            match &stmt.kind {
+                StmtKind::Assign(name, expr) => {
+                    let value = evaluate_expr(expr, context)?;
+                    context.set_variable(name, value.clone());
+                    last_result = value;
+                }
+                StmtKind::IndexAssign(container, index, value) => {
+                    let container = evaluate_expr(container, context)?;
+                    let index = evaluate_expr(index, context)?;
+                    let value = evaluate_expr(value, context)?;
+                    container.store_index(&index, value).map_err(|e| e.at(stmt.span))?;
+                }
+                StmtKind::Out(expr) => {
+                    let value = evaluate_expr(expr, context)?;
+                    context.output.push(value.to_string());
+                    last_result = value;
+                }
+                StmtKind::Expr(expr) => {
+                    last_result = evaluate_expr(expr, context)?;
+                }
+                StmtKind::Return(expr) => {
+                    let value = evaluate_expr(expr, context)?;
+                    context.returning = true;
+                    return Ok(value);
+                }
+                StmtKind::If(cond, body, else_body) => {
+                    let branch = if evaluate_expr(cond, context)?.is_truthy() { body } else { else_body };
+                    last_result = execute_statements(branch, context)?;
+                    if context.returning || context.loop_exit.is_some() {
+                        return Ok(last_result);
+                    }
+                }
+                StmtKind::While(cond, body) => {
+                    while evaluate_expr(cond, context)?.is_truthy() {
+                        last_result = execute_statements(body, context)?;
+                        if context.returning {
+                            return Ok(last_result);
+                        }
+                        if context.loop_exit.take() == Some(LoopExit::Break) {
+                            break;
+                        }
+                    }
+                }
+                StmtKind::For(var, ForTarget::Range(start, end), body) => {
+                    let start = evaluate_expr(start, context)?;
+                    let end = evaluate_expr(end, context)?;
+                    let (mut counter, end) = range_bounds(&start, &end).map_err(|e| e.at(stmt.span))?;
+                    while counter < end {
+                        context.set_variable(var, Value::Integer(counter.clone()));
+                        last_result = execute_statements(body, context)?;
+                        if context.returning {
+                            return Ok(last_result);
+                        }
+                        if context.loop_exit.take() == Some(LoopExit::Break) {
+                            break;
+                        }
+                        counter = (counter + IntegerValue::I8(1))?;
+                    }
+                }
+                StmtKind::For(var, ForTarget::Each(items), body) => {
+                    let items = evaluate_expr(items, context)?.iteration_list().map_err(|e| e.at(stmt.span))?;
+                    let mut position = 0;
+                    while let Some(item) = items.item(position) {
+                        context.set_variable(var, item);
+                        last_result = execute_statements(body, context)?;
+                        if context.returning {
+                            return Ok(last_result);
+                        }
+                        if context.loop_exit.take() == Some(LoopExit::Break) {
+                            break;
+                        }
+                        position += 1;
+                    }
+                }
+                StmtKind::Break => {
+                    context.loop_exit = Some(LoopExit::Break);
+                    return Ok(last_result);
+                }
+                StmtKind::Continue => {
+                    context.loop_exit = Some(LoopExit::Continue);
+                    return Ok(last_result);
+                }
+                _ => {
+                    // 忽略其他类型的语句
+                }
             }
-            Err(e) => Err(e),
         }
-    } else {
-        match expr {
-            Expr::Number(n) => {
-                // 将 Number 转换为 IntegerValue
-                Ok(IntegerValue::from_string(&n.to_string(), IntegerType::I64).unwrap())
+        
+        Ok(last_result)
+    }
+
+    fn evaluate_expr(expr: &Expr, context: &mut ExecutionContext) -> Result<Value, Diagnostic> {
+        match &expr.kind {
+            ExprKind::Number(n) => {
+                // 与字节码一致，保留词法分析选出的最小整数类型
+                Ok(Value::Integer(n.clone()))
             }
-            Expr::TypedNumber(int_val) => {
+            ExprKind::TypedNumber(int_val) => {
                 // 直接使用 TypedNumber 的值
-                Ok(int_val.clone())
+                Ok(Value::Integer(int_val.clone()))
+            }
+            ExprKind::Str(value) => {
+                Ok(Value::String(value.clone()))
             }
-            Expr::Ident(name) => {
-                // 检查变量是否存在
-                if let Some(value) = context.get_variable(name) {
-                    Ok(value)
-                } else if name == "fibonacci" {
-                    // 特殊处理：如果是fibonacci函数名，返回一个默认值
-                    Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+            ExprKind::Bool(value) => Ok(Value::Bool(*value)),
+            ExprKind::Float(value) => Ok(Value::Float(*value)),
+            ExprKind::Ident(name) => {
+                // 与字节码编译器一致，函数名优先视为无参调用
+                if context.functions.contains_key(name) {
+                    call_function(name, Vec::new(), context).map_err(|e| e.or_span(Some(expr.span)))
                 } else {
-                    // 变量未定义，返回0
-                    Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+                    context.get_variable(name).ok_or_else(|| {
+                        Diagnostic::new(Stage::Runtime, ErrorKind::UndefinedVariable, format!("Undefined variable: {}", name)).at(expr.span)
+                    })
+                }
+            }
+            ExprKind::Unary(op, operand) => {
+                let value = evaluate_expr(operand, context)?;
+                match op {
+                    UnaryOp::Neg => value.negate().map_err(|e| e.at(expr.span)),
+                    UnaryOp::Not => Ok(Value::from_bool(!value.is_truthy())),
+                    UnaryOp::BitNot => value.bit_not().map_err(|e| e.at(expr.span)),
+                }
+            }
+            ExprKind::BinOp(left, op @ (BinOpType::And | BinOpType::Or), right) => {
+                // 短路求值：左侧已能决定结果时不再求值右侧
+                let is_and = matches!(op, BinOpType::And);
+                if evaluate_expr(left, context)?.is_truthy() != is_and {
+                    return Ok(Value::from_bool(!is_and));
                 }
+                Ok(Value::from_bool(evaluate_expr(right, context)?.is_truthy()))
             }
-            Expr::BinOp(left, op, right) => {
+            ExprKind::BinOp(left, op, right) => {
                 // 直接使用解释执行
                 let left_val = evaluate_expr(left, context)?;
                 let right_val = evaluate_expr(right, context)?;
-                
-                let result = match op {
-                    BinOpType::Plus => left_val + right_val,
-                    BinOpType::Minus => left_val - right_val,
-                    BinOpType::Mul => left_val * right_val,
-                    BinOpType::Mod => left_val % right_val,
-                    BinOpType::Le => {
-                        if left_val <= right_val {
-                            Ok(IntegerValue::from_string("1", IntegerType::I64).unwrap())
-                        } else {
-                            Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                        }
-                    }
-                    BinOpType::Lt => {
-                        if left_val < right_val {
-                            Ok(IntegerValue::from_string("1", IntegerType::I64).unwrap())
-                        } else {
-                            Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                        }
-                    }
-                    BinOpType::Gt => {
-                        if left_val > right_val {
-                            Ok(IntegerValue::from_string("1", IntegerType::I64).unwrap())
-                        } else {
-                            Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                        }
-                    }
-                    BinOpType::Ge => {
-                        if left_val >= right_val {
-                            Ok(IntegerValue::from_string("1", IntegerType::I64).unwrap())
-                        } else {
-                            Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                        }
+                // 与字节码一致，任意两个值都可以比较相等，类型不同即不相等
+                if let BinOpType::Eq | BinOpType::NotEq = op {
+                    return Ok(Value::from_bool(left_val.equals(&right_val) == matches!(op, BinOpType::Eq)));
+                }
+                let (left_val, right_val) = match (left_val, right_val) {
+                    (Value::Integer(a), Value::Integer(b)) => (a, b),
+                    (Value::String(a), Value::String(b)) => {
+                        // 字符串只支持拼接
+                        return match op {
+                            BinOpType::Plus => Ok(Value::String(a.concat(&b))),
+                            _ => Err(type_mismatch(op.symbol(), &Value::String(a), &Value::String(b)).at(expr.span)),
+                        };
                     }
-                    BinOpType::Eq => {
-                        if left_val == right_val {
-                            Ok(IntegerValue::from_string("1", IntegerType::I64).unwrap())
-                        } else {
-                            Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
-                        }
+                    (a, b) => {
+                        // 整数与浮点数混合时提升为浮点数
+                        return match promote_float(&a, &b) {
+                            Some((x, y)) => bytecode::float_binop(op, x, y),
+                            None => Err(type_mismatch(op.symbol(), &a, &b)),
+                        }.map_err(|e| e.at(expr.span));
                     }
                 };
-                result
+                
+                let result = match op {
+                    BinOpType::Plus => (left_val + right_val).map(Value::Integer),
+                    BinOpType::Minus => (left_val - right_val).map(Value::Integer),
+                    BinOpType::Mul => (left_val * right_val).map(Value::Integer),
+                    BinOpType::Div => (left_val / right_val).map(Value::Integer),
+                    BinOpType::Mod => (left_val % right_val).map(Value::Integer),
+                    BinOpType::BitAnd => (left_val & right_val).map(Value::Integer),
+                    BinOpType::BitOr => (left_val | right_val).map(Value::Integer),
+                    BinOpType::BitXor => (left_val ^ right_val).map(Value::Integer),
+                    BinOpType::Shl => (left_val << right_val).map(Value::Integer),
+                    BinOpType::Shr => (left_val >> right_val).map(Value::Integer),
+                    BinOpType::Le => Ok(Value::from_bool(left_val <= right_val)),
+                    BinOpType::Lt => Ok(Value::from_bool(left_val < right_val)),
+                    BinOpType::Gt => Ok(Value::from_bool(left_val > right_val)),
+                    BinOpType::Ge => Ok(Value::from_bool(left_val >= right_val)),
+                    BinOpType::Eq | BinOpType::NotEq | BinOpType::And | BinOpType::Or => unreachable!("handled above"),
+                };
+                result.map_err(|e| e.at(expr.span))
             }
-            Expr::Call(name, args) => {
-                if name == "fibonacci" && !args.is_empty() {
-                    let n_val = evaluate_expr(&args[0], context)?;
-                    // 将 IntegerValue 转换为 u64 用于斐波那契计算
-                    let n = match n_val.to_i64() {
-                        Ok(v) => v as u64,
-                        Err(_) => 0,
-                    };
-                    // 优先使用JIT执行斐波那契函数
-                    match jit::jit_execute_fibonacci(n) {
-                        Ok(result) => {
-                            // 将结果转换回 IntegerValue
-                            Ok(IntegerValue::from_string(&result.to_string(), IntegerType::I64).unwrap())
-                        }
-                        Err(_) => {
-                            // JIT执行失败，回退到递归实现
-                            let result = calculate_fibonacci(n);
-                            Ok(IntegerValue::from_string(&result.to_string(), IntegerType::I64).unwrap())
-                        }
-                    }
-                } else {
-                    Ok(IntegerValue::from_string("0", IntegerType::I64).unwrap())
+            ExprKind::Call(name, args) => {
+                // 与字节码一致，同名的用户函数优先于内置函数
+                let is_user_function = context.functions.contains_key(name);
+                let builtin = if is_user_function { None } else { builtins::lookup(name) };
+                if !is_user_function && builtin.is_none() {
+                    return Ok(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap()));
+                }
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push(evaluate_expr(arg, context)?);
                 }
+                match builtin {
+                    Some(builtin) => builtin.call(values).map_err(|e| e.at(expr.span)),
+                    None => call_function(name, values, context).map_err(|e| e.or_span(Some(expr.span))),
+                }
+            }
+            ExprKind::List(items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    values.push(evaluate_expr(item, context)?);
+                }
+                Ok(Value::List(ListValue::new(values)))
+            }
+            ExprKind::Map(entries) => {
+                let map = MapValue::new();
+                for (key, value) in entries {
+                    let key = evaluate_expr(key, context)?;
+                    let value = evaluate_expr(value, context)?;
+                    map.insert(key, value).map_err(|e| e.at(expr.span))?;
+                }
+                Ok(Value::Map(map))
             }
+            ExprKind::Index(container, index) => {
+                let container = evaluate_expr(container, context)?;
+                let index = evaluate_expr(index, context)?;
+                container.index(&index).map_err(|e| e.at(expr.span))
+            }
+        }
+    }
+
+    // 调用用户函数：函数体在只包含形参的新变量表中执行
+    fn call_function(name: &str, args: Vec<Value>, context: &mut ExecutionContext) -> Result<Value, Diagnostic> {
+        let (params, body, scope) = context.functions[name].clone();
+        if args.len() != params.len() {
+            return Err(Diagnostic::new(Stage::Runtime, ErrorKind::ArityMismatch, format!("Parameter count mismatch: expected {} parameters, got {}", params.len(), args.len())));
+        }
+        let locals = params.iter().cloned().zip(args).collect();
+        let saved = std::mem::replace(&mut context.variables, locals);
+        let saved_scope = context.scope.replace(scope);
+        let result = execute_statements(body, context);
+        context.variables = saved;
+        context.scope = saved_scope;
+        // 与字节码一致，没有执行 return 的函数返回 0
+        let returned = std::mem::replace(&mut context.returning, false);
+        match result {
+            Ok(value) if returned => Ok(value),
+            Ok(_) => Ok(Value::Integer(IntegerValue::from_string("0", IntegerType::I64).unwrap())),
+            Err(err) => Err(err),
         }
     }
 }
 
-fn calculate_fibonacci(n: u64) -> u64 {
-    if n <= 1 {
-        n
-    } else {
-        calculate_fibonacci(n - 1) + calculate_fibonacci(n - 2)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::reference::{ExecutionContext, execute_statements};
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    // 分别用树遍历解释器和字节码虚拟机执行，返回两边的输出
+    fn run_both(source: &str) -> (Vec<String>, Vec<String>) {
+        let program = parse(tokenize(source).unwrap()).unwrap();
+
+        let mut context = ExecutionContext::new();
+        context.register_functions(&program.statements);
+        execute_statements(&program.statements, &mut context).unwrap();
+
+        let (_, vm_output) = bytecode::execute_bytecode(bytecode::compile_to_bytecode(&program)).unwrap();
+        (context.output, vm_output)
+    }
+
+    // 两边都以同样的运行时错误结束
+    fn assert_conforms_error(source: &str, message: &str) {
+        let program = parse(tokenize(source).unwrap()).unwrap();
+
+        let mut context = ExecutionContext::new();
+        context.register_functions(&program.statements);
+        let tree_err = execute_statements(&program.statements, &mut context).unwrap_err();
+
+        let vm_err = bytecode::execute_bytecode(bytecode::compile_to_bytecode(&program)).unwrap_err();
+        assert_eq!(tree_err.message, message, "tree-walker error for:\n{}", source);
+        assert_eq!(vm_err.message, message, "bytecode error for:\n{}", source);
+        assert_eq!(tree_err.kind, vm_err.kind);
+    }
+
+    fn assert_conforms(source: &str, expected: &[&str]) {
+        let (tree_output, vm_output) = run_both(source);
+        assert_eq!(tree_output, expected, "tree-walker output for:\n{}", source);
+        assert_eq!(vm_output, expected, "bytecode output for:\n{}", source);
+    }
+
+    #[test]
+    fn test_if_else_inside_while() {
+        assert_conforms(
+            "i = 0\nevens = 0\nwhile i < 6\n    if i % 2 == 0\n        evens += 1\n    else\n        out i\n    end\n    i += 1\nend\nout evens\n",
+            &["1", "3", "5", "3"],
+        );
+    }
+
+    #[test]
+    fn test_nested_while_with_if() {
+        assert_conforms(
+            "i = 0\nwhile i < 3\n    j = 0\n    while j < 3\n        if j == i\n            out j\n        end\n        j += 1\n    end\n    i += 1\nend\nout 9\n",
+            &["0", "1", "2", "9"],
+        );
+    }
+
+    #[test]
+    fn test_elif_chain_with_returns_in_function() {
+        assert_conforms(
+            "def grade s, t\n    if s > 8\n        return 3\n    elif s > 4\n        return 2\n    elif s > t\n        return 1\n    end\n    return 0\nend\nout grade 9, 0\nout grade 5, 0\nout grade 2, 1\nout grade 1, 2\n",
+            &["3", "2", "1", "0"],
+        );
+    }
+
+    #[test]
+    fn test_return_from_inside_loop() {
+        assert_conforms(
+            "def find a, b\n    while a > 0\n        if a == b\n            return 100\n        end\n        a -= 1\n    end\n    return 7\nend\nout find 5, 3\nout find 2, 9\n",
+            &["100", "7"],
+        );
+    }
+
+    #[test]
+    fn test_top_level_return_stops_program() {
+        assert_conforms(
+            "x = 1\nwhile x < 100\n    if x > 20\n        return x\n    end\n    out x\n    x = x * 2\nend\nout 0\n",
+            &["1", "2", "4", "8", "16"],
+        );
+    }
+
+    #[test]
+    fn test_empty_then_branch_and_strings() {
+        assert_conforms(
+            "x = 0\nif x\nelse\n    out \"a\" + \"b\"\nend\nif x == 0\n    if 1\n        out \"inner\"\n    end\nend\n",
+            &["ab", "inner"],
+        );
+    }
+
+    #[test]
+    fn test_wide_accumulator_in_loop() {
+        assert_conforms(
+            "total = 0i64\ni = 0\nwhile i < 50\n    total += i * i\n    i += 1\nend\nout total\n",
+            &["40425"],
+        );
+    }
+
+    #[test]
+    fn test_recursion_passes_values_unchanged() {
+        assert_conforms(
+            "def fact n\n    if n < 2\n        return 1\n    end\n    return n * fact n - 1\nend\nout fact 25\n",
+            &["15511210043330985984000000"],
+        );
+    }
+
+    #[test]
+    fn test_wide_and_negative_arguments() {
+        assert_conforms(
+            "def id x\n    return x\nend\ndef neg a\n    return 0 - a\nend\nbig = 100000000000000000000000000000bigint\nout id big\nout neg 5\nout id \"s\"\n",
+            &["100000000000000000000000000000", "-5", "s"],
+        );
+    }
+
+    #[test]
+    fn test_output_inside_function_is_captured() {
+        assert_conforms(
+            "def count_down n\n    while n > 0\n        out n\n        n -= 1\n    end\nend\nx = count_down 3\nout x\n",
+            &["3", "2", "1", "0"],
+        );
+    }
+
+    #[test]
+    fn test_memoized_recursion_matches_tree_walker() {
+        assert_conforms(
+            "@memo\ndef fact n\n    if n < 2\n        return 1\n    end\n    return n * fact n - 1\nend\ndef plain n\n    return n * 2\nend\nout fact 20\nout fact 20\nout fact 5\nout plain 4\n",
+            &["2432902008176640000", "2432902008176640000", "120", "8"],
+        );
+    }
+
+    #[test]
+    fn test_memo_does_not_share_lists() {
+        assert_conforms(
+            "@memo\ndef mk n\n    return [n]\nend\n@memo\ndef first xs\n    return xs[0]\nend\na = mk 1\nappend a, 2\nb = mk 1\nout b\nout first a\na[0] = 5\nout first a\n",
+            &["[1]", "1", "5"],
+        );
+    }
+
+    #[test]
+    fn test_memo_does_not_share_maps() {
+        assert_conforms(
+            "@memo\ndef box n\n    return {\"n\": n}\nend\n@memo\ndef size m\n    return len m\nend\na = box 1\na[\"extra\"] = 2\nb = box 1\nout b\nout size a\nx = remove a, \"extra\"\nout size a\n",
+            &["{\"n\": 1}", "2", "1"],
+        );
+    }
+
+    #[test]
+    fn test_division_truncates_toward_zero() {
+        assert_conforms(
+            "a = 0 - 7\nout a / 2\nout 7 / 2 * 2\nx = 100\nx /= 7\nout x\nbig = 1000000000000i64\nout big / 3\nout a % 2\n",
+            &["-3", "6", "14", "333333333333", "-1"],
+        );
+    }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        assert_conforms(
+            "def noisy x\n    out \"called\"\n    return x\nend\nzero = 0\none = 1\nout zero and noisy one\nout one or noisy one\nout one and noisy zero\nout zero or noisy 7\nout not zero\nout not 5 == 5\nout 3 != 4\nout \"a\" != \"a\"\nout -one * 3\n",
+            &["false", "true", "called", "false", "called", "true", "true", "false", "true", "false", "-3"],
+        );
+    }
+
+    #[test]
+    fn test_bools_are_distinct_from_integers() {
+        assert_conforms(
+            "t = true\nf = false\nout t\nout 3 < 4\nout t == not f\nout 1 == t\nif t and 2 > 1\n    out \"yes\"\nend\nwhile f\n    out \"never\"\nend\n",
+            &["true", "true", "true", "false", "yes"],
+        );
+    }
+
+    #[test]
+    fn test_truthiness_of_strings_and_containers() {
+        assert_conforms(
+            "s = \"a\"\nif s\n    out \"non-empty\"\nend\nif not \"\"\n    out \"empty\"\nend\nout s and []\nout {} or [0]\n",
+            &["non-empty", "empty", "false", "true"],
+        );
+    }
+
+    #[test]
+    fn test_float_arithmetic_and_promotion() {
+        assert_conforms(
+            "x = 7 / 2.0\nout x\nout 1.5 + 1\nout 2e10\nout 0.1 + 0.2 == 0.3\nout 3 < 3.5\nout -x * 2\nout 1 == 1.0\nout 10 % 4.0\n",
+            &["3.5", "2.5", "20000000000.0", "false", "true", "-7.0", "true", "2.0"],
+        );
+    }
+
+    #[test]
+    fn test_non_numeric_operands_are_type_errors() {
+        let cases = [
+            ("out \"a\" - 1\n", "Unsupported operand types for -: string and integer"),
+            ("out [1] < 2\n", "Unsupported operand types for <: list and integer"),
+            ("s = \"a\"\nout s - 1\n", "Unsupported operand types for -: string and integer"),
+            ("s = \"ab\"\nout s * 2\n", "Unsupported operand types for *: string and integer"),
+            ("b = true\nout b % 2\n", "Unsupported operand types for %: bool and integer"),
+            ("xs = [1]\nout xs < 2\n", "Unsupported operand types for <: list and integer"),
+            ("s = \"a\"\nout s <= \"b\"\n", "Unsupported operand types for <=: string and string"),
+            ("m = {}\nout m > 0\n", "Unsupported operand types for >: map and integer"),
+            ("b = false\nout b >= 1.5\n", "Unsupported operand types for >=: bool and float"),
+        ];
+        for (source, message) in cases {
+            assert_conforms_error(source, message);
+        }
+    }
+
+    #[test]
+    fn test_integer_overflow() {
+        // 乘法溢出时提升为 BigInt，加减溢出是错误
+        assert_conforms("out 200 * 200\nout 9223372036854775807i64 * 2\n", &["40000", "18446744073709551614"]);
+        assert_conforms_error("x = 127\nout x + 1\n", "Addition overflow for i8: 127 + 1");
+        assert_conforms_error("x = -9223372036854775807i64\nout x - 2\n", "Subtraction overflow for i64: -9223372036854775807 - 2");
+    }
+
+    #[test]
+    fn test_list_operations() {
+        assert_conforms(
+            "def total xs\n    sum = 0\n    i = 0\n    while i < len xs\n        sum += xs[i]\n        i += 1\n    end\n    return sum\nend\na = [1, 2, 3]\nb = a\nappend b, 4\na[0] = 10\nb[1] *= 5\nout a\nout total a\nout pop a\nout len b\ngrid = [[1, 2], [3]]\ngrid[1][0] = \"x\"\nout grid\nout [] == [] and [1] != [2]\n",
+            &["[10, 10, 3, 4]", "27", "4", "3", "[[1, 2], [\"x\"]]", "true"],
+        );
+    }
+
+    #[test]
+    fn test_self_referencing_list() {
+        assert_conforms("a = [1]\nappend a, a\nout a\nout a == a\nout len a[1][1]\n", &["[1, [...]]", "true", "2"]);
+    }
+
+    #[test]
+    fn test_self_referencing_map() {
+        assert_conforms("m = {\"a\": 1}\nm[1] = m\nout m\nout m == m\nout m[1][1][\"a\"]\n", &["{\"a\": 1, 1: {...}}", "true", "1"]);
+    }
+
+    #[test]
+    fn test_map_operations() {
+        assert_conforms(
+            "def lookup m, k\n    if contains m, k\n        return m[k]\n    end\n    return -1\nend\nports = {\"http\": 80, \"ssh\": 22}\nalias = ports\nalias[\"https\"] = 443\nports[\"http\"] = 8080\nout ports\nout lookup ports, \"ssh\"\nout lookup ports, \"ftp\"\nout remove ports, \"ssh\"\nout keys alias\nout len ports\nout {1: \"a\"}[1i64]\n",
+            &["{\"http\": 8080, \"ssh\": 22, \"https\": 443}", "22", "-1", "22", "[\"http\", \"https\"]", "2", "a"],
+        );
+    }
+
+    #[test]
+    fn test_bitwise_checksum() {
+        assert_conforms(
+            "def checksum n\n    sum = 0i32\n    while n > 0\n        sum = sum << 1 ^ n & 255 | 1\n        sum = sum & 65535\n        n -= 1\n    end\n    return sum\nend\nout checksum 20\nflags = 6\nout flags & 4 == 4\nout ~flags\nbig = 1bigint\nout big << 70 >> 68\n",
+            &["11987", "true", "-7", "4"],
+        );
+    }
+
+    #[test]
+    fn test_compound_while_condition() {
+        assert_conforms(
+            "i = 0\nj = 10\nwhile i < 10 and not j == 6\n    i += 1\n    j -= 1\nend\nout i\nout j\nif i > 100 or j != 6\n    out 0\nelse\n    out 1\nend\n",
+            &["4", "6", "1"],
+        );
+    }
+
+    #[test]
+    fn test_for_loops() {
+        assert_conforms(
+            "def find xs, target\n    for i in 0..len xs\n        if xs[i] == target\n            return i\n        end\n    end\n    return -1\nend\nxs = [4, 8, 15]\nout find xs, 15\nout find xs, 16\nsum = 0\nfor x in xs\n    sum += x\n    x = 0\nend\nout sum\nfor i in 0..3\n    i *= 10\n    out i\nend\nm = {\"b\": 1, \"a\": 2}\nfor k in m\n    m[k + k] = 0\nend\nout keys m\nlast = 0\nfor i in 125i8..300\n    last = i\nend\nout last\nfor i in 5..0\n    out i\nend\n",
+            &["2", "-1", "27", "0", "10", "20", "[\"b\", \"a\", \"bb\", \"aa\"]", "299"],
+        );
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        assert_conforms(
+            "def first_square_above n\n    i = 0\n    while true\n        i += 1\n        if i * i <= n\n            continue\n        end\n        return i\n    end\nend\nout first_square_above 50\nodd = 0\nfor i in 0..10\n    if i % 2 == 0\n        continue\n    elif i > 7\n        break\n    end\n    odd += i\nend\nout odd\nfor x in [1, 2, 3]\n    for y in [10, 20]\n        if y > x * 10\n            break\n        end\n        out x + y\n    end\nend\n",
+            &["8", "16", "11", "12", "22", "13", "23"],
+        );
+    }
+
+    #[test]
+    fn test_functions_read_and_write_globals() {
+        assert_conforms(
+            "count = 0\nlimit = 3\ndef bump\n    global count\n    count += 1\n    return count\nend\ndef under n\n    return n < limit\nend\nwhile under count\n    x = bump\nend\nout count\ndef shadow n\n    limit = n\n    return limit\nend\nout shadow 10\nout limit\ndef fresh\n    global made\n    made = 7\nend\nx = fresh\nout made\n",
+            &["3", "10", "3", "7"],
+        );
+    }
+
+    #[test]
+    fn test_parenthesized_expressions_and_calls() {
+        assert_conforms(
+            "def add(a, b)\n    return a + b\nend\ndef mul(a, b)\n    return a * b\nend\nout (1 + 2) * 3\nout -(4 - 6)\nout add(mul(2, 3), 4)\nout add mul(2, 3), 4\nout add (1 + 2) * 3, 1\nxs = [1, 2]\nappend(xs, 3)\nout len(xs)\n",
+            &["9", "2", "10", "10", "10", "3"],
+        );
     }
 }
@@ -1,125 +1,1015 @@
 use cranelift::codegen::Context;
 use cranelift::prelude::*;
 use cranelift::codegen::ir::types::I64;
+use cranelift::codegen::ir::FuncRef;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{default_libcall_names, Linkage, Module};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
 use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
 use cranelift_codegen::{settings, isa::TargetIsa};
 use cranelift_native::builder as cranelift_native_builder;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use std::sync::Arc;
 
 use crate::bytecode::{CompiledFunction, Bytecode};
+use crate::diagnostic::{Diagnostic, ErrorKind, Stage};
+use crate::types::IntegerValue;
+
+// 本地代码的递归深度上限，超过时回退到解释器，避免耗尽 Rust 线程栈
+pub const MAX_NATIVE_DEPTH: usize = 1_000;
+
+// 将 Cranelift 的错误包装为 JIT 阶段的诊断
+fn jit_error(err: impl std::fmt::Display) -> Diagnostic {
+    Diagnostic::new(Stage::Jit, ErrorKind::JitFailure, err.to_string())
+}
+
+fn not_compilable(name: &str, reason: impl std::fmt::Display) -> Diagnostic {
+    jit_error(format!("Cannot JIT-compile `{}`: {}", name, reason))
+}
+
+// 生成代码与运行时共享的状态，字段偏移直接写入机器码
+#[repr(C)]
+struct JitContext {
+    deopt: i64,  // 非零表示遇到溢出等情况，需要由解释器重新执行
+    depth: i64,
+    max_depth: i64,
+    width: i64,  // 返回值的位宽编号
+}
+
+const DEOPT_OFFSET: i32 = std::mem::offset_of!(JitContext, deopt) as i32;
+const DEPTH_OFFSET: i32 = std::mem::offset_of!(JitContext, depth) as i32;
+const MAX_DEPTH_OFFSET: i32 = std::mem::offset_of!(JitContext, max_depth) as i32;
+const WIDTH_OFFSET: i32 = std::mem::offset_of!(JitContext, width) as i32;
+
+// 本地代码中的整数是 i64 值加位宽编号：0..=3 依次为 i8、i16、i32、i64。
+// 运算按解释器的类型提升规则维护位宽，较窄类型的结果因此与解释器一致
+const I64_WIDTH: i64 = 3;
+
+fn encode(value: &IntegerValue) -> Option<[i64; 2]> {
+    match *value {
+        IntegerValue::I8(n) => Some([n.into(), 0]),
+        IntegerValue::I16(n) => Some([n.into(), 1]),
+        IntegerValue::I32(n) => Some([n.into(), 2]),
+        IntegerValue::I64(n) => Some([n, I64_WIDTH]),
+        _ => None,
+    }
+}
+
+// 值一定在位宽范围内，截断不会丢失信息
+fn decode(value: i64, width: i64) -> IntegerValue {
+    match width {
+        0 => IntegerValue::I8(value as i8),
+        1 => IntegerValue::I16(value as i16),
+        2 => IntegerValue::I32(value as i32),
+        _ => IntegerValue::I64(value),
+    }
+}
 
 pub struct JITCompiler {
     module: JITModule,
     ctx: Context,
-    func_map: HashMap<String, *const u8>,
+    func_map: HashMap<String, (*const u8, usize)>,  // 函数名或循环键 → 入口包装函数地址和实参个数
+    ids: HashMap<String, FuncId>,  // 已定义的函数体，供后续编译的调用方引用
+    rejected: HashMap<String, Diagnostic>,  // 无法编译的函数及原因
     builder_ctx: FunctionBuilderContext,
     target_isa: Arc<dyn TargetIsa>,
 }
 
 impl JITCompiler {
-    pub fn new() -> Result<Self, String> {
-        let builder = JITBuilder::new(default_libcall_names()).map_err(|e| e.to_string())?;
-        let module = JITModule::new(builder);
+    pub fn new() -> Result<Self, Diagnostic> {
         let ctx = Context::new();
         let builder_ctx = FunctionBuilderContext::new();
-        
+
         // 创建目标ISA
         let mut flag_builder = settings::builder();
         flag_builder.set("opt_level", "speed").unwrap();
         flag_builder.set("enable_verifier", "false").unwrap();
-        let isa_builder = cranelift_native_builder().map_err(|e| e.to_string())?;
+        let isa_builder = cranelift_native_builder().map_err(jit_error)?;
         let target_isa = isa_builder
             .finish(settings::Flags::new(flag_builder))
-            .map_err(|e| e.to_string())?;
-        
+            .map_err(jit_error)?;
+
+        let builder = JITBuilder::with_isa(target_isa.clone(), default_libcall_names());
+        let module = JITModule::new(builder);
+
         Ok(JITCompiler {
             module,
             ctx,
             func_map: HashMap::new(),
+            ids: HashMap::new(),
+            rejected: HashMap::new(),
             builder_ctx,
             target_isa,
         })
     }
 
-    // 编译字节码函数为本地机器码
-    pub fn compile_function(&mut self, name: &str, func: &CompiledFunction) -> Result<*const u8, String> {
+    // 编译字节码函数为本地机器码；被调函数一并编译
+    pub fn compile_function(&mut self, name: &str, functions: &HashMap<String, Rc<CompiledFunction>>) -> Result<(), Diagnostic> {
+        self.cached(name.to_string(), |jit| jit.compile_generic_function(name, functions))
+    }
+
+    // 编译从循环头 `pc` 进入函数的入口（栈上替换），实参为全部局部变量的当前值，顺序同 `local_names`
+    pub fn compile_loop(&mut self, name: &str, pc: usize, functions: &HashMap<String, Rc<CompiledFunction>>) -> Result<(), Diagnostic> {
+        self.cached(loop_key(name, pc), |jit| {
+            // 循环中的递归调用进入普通函数体，因此先编译整个函数
+            jit.compile_function(name, functions)?;
+            jit.compile_loop_entry(name, pc, functions)
+        })
+    }
+
+    // 按键缓存编译结果；失败也会记录，之后直接交给解释器
+    fn cached(&mut self, key: String, compile: impl FnOnce(&mut Self) -> Result<(*const u8, usize), Diagnostic>) -> Result<(), Diagnostic> {
         // 检查缓存
-        if let Some(func_addr) = self.func_map.get(name) {
-            return Ok(*func_addr);
+        if self.func_map.contains_key(&key) {
+            return Ok(());
         }
-        
-        // 为简单的斐波那契函数生成JIT代码
-        if name == "fibonacci" || func.instructions.iter().any(|instr| matches!(instr, Bytecode::Call(name) if name == "fibonacci")) {
-            return self.compile_fibonacci_function(name, func);
+        if let Some(err) = self.rejected.get(&key) {
+            return Err(err.clone());
+        }
+
+        match compile(self) {
+            Ok(entry) => {
+                self.func_map.insert(key, entry);
+                Ok(())
+            }
+            Err(err) => {
+                self.rejected.insert(key, err.clone());
+                Err(err)
+            }
         }
-        
-        // 对于其他函数，使用通用编译方法
-        self.compile_generic_function(name, func)
     }
 
-    // 编译斐波那契函数（特殊优化）
-    fn compile_fibonacci_function(&mut self, name: &str, func: &CompiledFunction) -> Result<*const u8, String> {
-        // 简化实现：直接返回一个默认值，避免复杂的 cranelift API
-        // 实际项目中需要实现完整的 JIT 编译
-        Ok(std::ptr::null())
+    // 编译通用函数：先检查调用图中的所有函数都能编译，再统一定义
+    fn compile_generic_function(&mut self, name: &str, functions: &HashMap<String, Rc<CompiledFunction>>) -> Result<(*const u8, usize), Diagnostic> {
+        let mut plan: Vec<String> = Vec::new();
+        let mut pending = vec![name.to_string()];
+        while let Some(current) = pending.pop() {
+            if self.ids.contains_key(&current) || plan.contains(&current) {
+                continue;
+            }
+            let func = functions
+                .get(&current)
+                .ok_or_else(|| not_compilable(&current, "function not found"))?;
+            pending.extend(check_function(&current, func, functions)?);
+            plan.push(current);
+        }
+
+        for fname in &plan {
+            let sig = self.body_signature(functions[fname].param_count);
+            let id = self.module.declare_function(fname, Linkage::Local, &sig).map_err(jit_error)?;
+            self.ids.insert(fname.clone(), id);
+        }
+        for fname in &plan {
+            if let Err(err) = self.define_body(self.ids[fname], fname, functions, None) {
+                // 未定义成功的函数不能再被引用
+                for fname in &plan {
+                    self.ids.remove(fname);
+                }
+                return Err(err);
+            }
+        }
+
+        let param_count = functions[name].param_count;
+        let entry = self.define_entry(&format!("{}$entry", name), self.ids[name], param_count)?;
+        self.module.finalize_definitions().map_err(jit_error)?;
+        Ok((self.module.get_finalized_function(entry), param_count))
     }
 
-    // 编译通用函数
-    fn compile_generic_function(&mut self, name: &str, func: &CompiledFunction) -> Result<*const u8, String> {
-        // 简化实现：直接返回一个默认值，避免复杂的 cranelift API
-        // 实际项目中需要实现完整的 JIT 编译
-        Ok(std::ptr::null())
+    fn compile_loop_entry(&mut self, name: &str, pc: usize, functions: &HashMap<String, Rc<CompiledFunction>>) -> Result<(*const u8, usize), Diagnostic> {
+        let func = &functions[name];
+        if !block_leaders(&func.instructions).contains(&pc) {
+            return Err(not_compilable(name, format!("instruction {} is not a loop header", pc)));
+        }
+        let local_count = local_names(func).len();
+        let sig = self.body_signature(local_count);
+        let body = self.module
+            .declare_function(&format!("{}$loop{}", name, pc), Linkage::Local, &sig)
+            .map_err(jit_error)?;
+        self.define_body(body, name, functions, Some(pc))?;
+        let entry = self.define_entry(&format!("{}$loop{}$entry", name, pc), body, local_count)?;
+        self.module.finalize_definitions().map_err(jit_error)?;
+        Ok((self.module.get_finalized_function(entry), local_count))
     }
 
-    // 执行JIT编译的函数
-    pub fn execute(&self, func_addr: *const u8, args: &[u64]) -> Result<u64, String> {
-        if func_addr.is_null() {
-            return Err("Null function address".to_string());
+    // 函数体签名：每个形参是值和位宽两个 i64，最后是 JitContext 指针；返回值同样是值和位宽
+    fn body_signature(&self, param_count: usize) -> Signature {
+        let mut sig = self.module.make_signature();
+        for _ in 0..2 * param_count {
+            sig.params.push(AbiParam::new(I64));
         }
-        
-        // 根据参数数量选择不同的函数签名
-        match args.len() {
-            0 => {
-                let func: extern "C" fn() -> u64 = unsafe { std::mem::transmute(func_addr) };
-                Ok(func())
+        sig.params.push(AbiParam::new(self.module.target_config().pointer_type()));
+        sig.returns.push(AbiParam::new(I64));
+        sig.returns.push(AbiParam::new(I64));
+        sig
+    }
+
+    // 定义函数体；`entry_pc` 为 Some 时从该循环头进入，形参换成全部局部变量
+    fn define_body(&mut self, id: FuncId, name: &str, functions: &HashMap<String, Rc<CompiledFunction>>, entry_pc: Option<usize>) -> Result<(), Diagnostic> {
+        let func = &functions[name];
+        let arg_count = entry_pc.map_or(func.param_count, |_| local_names(func).len());
+        self.module.clear_context(&mut self.ctx);
+        self.ctx.func.signature = self.body_signature(arg_count);
+
+        let builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+        let translator = FunctionTranslator::new(builder, &mut self.module, &self.ids, functions, name, entry_pc);
+        translator.translate();
+
+        self.module.define_function(id, &mut self.ctx).map_err(jit_error)?;
+        self.module.clear_context(&mut self.ctx);
+        Ok(())
+    }
+
+    // 入口包装函数：从数组读取实参后调用函数体，使任意参数个数都能用同一签名调用；
+    // 返回值的位宽写入 JitContext
+    fn define_entry(&mut self, entry_name: &str, body_id: FuncId, param_count: usize) -> Result<FuncId, Diagnostic> {
+        let ptr = self.module.target_config().pointer_type();
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(ptr));
+        sig.params.push(AbiParam::new(ptr));
+        sig.returns.push(AbiParam::new(I64));
+        let entry_id = self.module
+            .declare_function(entry_name, Linkage::Local, &sig)
+            .map_err(jit_error)?;
+
+        self.module.clear_context(&mut self.ctx);
+        self.ctx.func.signature = sig;
+        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
+        let block = builder.create_block();
+        builder.append_block_params_for_function_params(block);
+        builder.switch_to_block(block);
+        builder.seal_block(block);
+        let args_ptr = builder.block_params(block)[0];
+        let ctx_ptr = builder.block_params(block)[1];
+        let mut args: Vec<Value> = (0..2 * param_count)
+            .map(|i| builder.ins().load(I64, MemFlags::trusted(), args_ptr, (i * 8) as i32))
+            .collect();
+        args.push(ctx_ptr);
+        let body = self.module.declare_func_in_func(body_id, builder.func);
+        let call = builder.ins().call(body, &args);
+        let (result, width) = (builder.inst_results(call)[0], builder.inst_results(call)[1]);
+        builder.ins().store(MemFlags::trusted(), width, ctx_ptr, WIDTH_OFFSET);
+        builder.ins().return_(&[result]);
+        builder.finalize();
+
+        self.module.define_function(entry_id, &mut self.ctx).map_err(jit_error)?;
+        self.module.clear_context(&mut self.ctx);
+        Ok(entry_id)
+    }
+
+    pub fn is_compiled(&self, name: &str) -> bool {
+        self.func_map.contains_key(name)
+    }
+
+    // 执行JIT编译的函数；返回 None 表示本地代码放弃执行，需要由解释器重新执行这次调用
+    pub fn execute(&self, name: &str, args: &[IntegerValue], max_depth: usize) -> Result<Option<IntegerValue>, Diagnostic> {
+        self.run(name, args, max_depth)
+    }
+
+    // 从 `compile_loop` 编译的循环头进入函数，实参顺序同 `local_names`
+    pub fn execute_loop(&self, name: &str, pc: usize, locals: &[IntegerValue], max_depth: usize) -> Result<Option<IntegerValue>, Diagnostic> {
+        self.run(&loop_key(name, pc), locals, max_depth)
+    }
+
+    // 入口地址只来自 `func_map`，由 `define_entry` 生成；核对实参个数后才调用，本地代码不会越界读取
+    fn run(&self, key: &str, args: &[IntegerValue], max_depth: usize) -> Result<Option<IntegerValue>, Diagnostic> {
+        let (func_addr, param_count) = *self.func_map.get(key).ok_or_else(|| jit_error(format!("`{}` has not been compiled", key)))?;
+        if args.len() != param_count {
+            return Err(jit_error(format!("Parameter count mismatch: expected {} parameters, got {}", param_count, args.len())));
+        }
+        let mut encoded = Vec::with_capacity(2 * args.len());
+        for arg in args {
+            encoded.extend(encode(arg).ok_or_else(|| jit_error(format!("Cannot pass {} to native code", arg)))?);
+        }
+
+        let mut ctx = JitContext {
+            deopt: 0,
+            depth: 0,
+            max_depth: max_depth.min(MAX_NATIVE_DEPTH) as i64,
+            width: I64_WIDTH,
+        };
+        let func: extern "C" fn(*const i64, *mut JitContext) -> i64 = unsafe { std::mem::transmute(func_addr) };
+        let result = func(encoded.as_ptr(), &mut ctx);
+        Ok(if ctx.deopt != 0 { None } else { Some(decode(result, ctx.width)) })
+    }
+}
+
+// 操作数栈上值的静态类型：整数的位宽在运行时跟踪，不超过 i64
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Slot {
+    Int,
+    // 比较和逻辑运算的结果，只能用作条件或与另一个布尔值比较相等
+    Bool,
+}
+
+// 检查函数能否编译为本地代码（结果类型与解释器一致），返回它调用的函数
+fn check_function(name: &str, func: &CompiledFunction, functions: &HashMap<String, Rc<CompiledFunction>>) -> Result<Vec<String>, Diagnostic> {
+    if func.memoize {
+        return Err(not_compilable(name, "memoized functions stay in the interpreter"));
+    }
+    let leaders = block_leaders(&func.instructions);
+    let mut stack: Vec<Slot> = Vec::new();
+    let mut callees = Vec::new();
+    // 每个基本块入口处的操作数栈，`and`/`or` 的结果在分支汇合处仍留在栈上
+    let mut entry_stacks: HashMap<usize, Vec<Slot>> = HashMap::new();
+    let mut terminated = false;
+    let pop = |stack: &mut Vec<Slot>| stack.pop().ok_or_else(|| not_compilable(name, "operand stack underflow"));
+    let branch_to = |entry_stacks: &mut HashMap<usize, Vec<Slot>>, target: usize, stack: &[Slot]| {
+        if *entry_stacks.entry(target).or_insert_with(|| stack.to_vec()) != stack {
+            return Err(not_compilable(name, "operand stack differs between branches"));
+        }
+        Ok(())
+    };
+
+    for (pc, instr) in func.instructions.iter().enumerate() {
+        if leaders.contains(&pc) {
+            if terminated {
+                stack = entry_stacks.get(&pc).cloned().unwrap_or_default();
+            } else {
+                branch_to(&mut entry_stacks, pc, &stack)?;
+            }
+        }
+        terminated = matches!(instr, Bytecode::Jump(_) | Bytecode::Return | Bytecode::TailCall(_));
+        match instr {
+            Bytecode::LoadConst(crate::types::Value::Integer(value)) => {
+                if encode(value).is_none() {
+                    return Err(not_compilable(name, "uses a constant wider than i64"));
+                }
+                stack.push(Slot::Int);
+            }
+            Bytecode::LoadConst(crate::types::Value::Bool(_)) => stack.push(Slot::Bool),
+            Bytecode::LoadConst(value) => {
+                return Err(not_compilable(name, format!("uses a {} constant", value.type_name())));
+            }
+            Bytecode::LoadVar(_) | Bytecode::LoadReg(_, _) => stack.push(Slot::Int),
+            Bytecode::StoreVar(var) => {
+                if pop(&mut stack)? == Slot::Bool {
+                    return Err(not_compilable(name, format!("assigns a bool to `{}`", var)));
+                }
+            }
+            Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::AddI64 | Bytecode::SubI64 | Bytecode::MulI64 | Bytecode::Div | Bytecode::Mod
+            | Bytecode::BitAnd | Bytecode::BitOr | Bytecode::BitXor | Bytecode::Shl | Bytecode::Shr => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                if a == Slot::Bool || b == Slot::Bool {
+                    // 解释器会报告类型错误
+                    return Err(not_compilable(name, "does arithmetic on a bool"));
+                }
+                stack.push(Slot::Int);
             }
-            1 => {
-                let func: extern "C" fn(u64) -> u64 = unsafe { std::mem::transmute(func_addr) };
-                Ok(func(args[0]))
+            Bytecode::Le | Bytecode::Lt | Bytecode::Gt | Bytecode::Ge | Bytecode::Eq => {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                // 布尔值只能与布尔值比较相等，和整数比较时类型不同恒为假
+                let both_bool = a == Slot::Bool && b == Slot::Bool;
+                if (a == Slot::Bool || b == Slot::Bool) && !(both_bool && matches!(instr, Bytecode::Eq)) {
+                    return Err(not_compilable(name, "compares a bool with an integer"));
+                }
+                stack.push(Slot::Bool);
             }
-            2 => {
-                let func: extern "C" fn(u64, u64) -> u64 = unsafe { std::mem::transmute(func_addr) };
-                Ok(func(args[0], args[1]))
+            Bytecode::Neg | Bytecode::BitNot => {
+                if pop(&mut stack)? == Slot::Bool {
+                    return Err(not_compilable(name, "negates a bool"));
+                }
+                stack.push(Slot::Int);
             }
-            _ => {
-                Err("Too many arguments for JIT function".to_string())
+            Bytecode::Not => {
+                pop(&mut stack)?;
+                stack.push(Slot::Bool);
             }
+            Bytecode::RangeInit => {
+                let end = pop(&mut stack)?;
+                let start = pop(&mut stack)?;
+                if start == Slot::Bool || end == Slot::Bool {
+                    return Err(not_compilable(name, "uses a bool as a range bound"));
+                }
+                stack.push(Slot::Int);
+                stack.push(Slot::Int);
+            }
+            Bytecode::Jump(offset) => branch_to(&mut entry_stacks, jump_target(pc, *offset), &stack)?,
+            Bytecode::JumpIfFalse(offset) | Bytecode::JumpIfTrue(offset) => {
+                pop(&mut stack)?;
+                branch_to(&mut entry_stacks, jump_target(pc, *offset), &stack)?;
+            }
+            Bytecode::Call(callee) | Bytecode::TailCall(callee) => {
+                let target = functions
+                    .get(callee)
+                    .ok_or_else(|| not_compilable(name, format!("calls unknown function `{}`", callee)))?;
+                for _ in 0..target.param_count {
+                    if pop(&mut stack)? == Slot::Bool {
+                        return Err(not_compilable(name, format!("passes a bool to `{}`", callee)));
+                    }
+                }
+                if !callees.contains(callee) {
+                    callees.push(callee.clone());
+                }
+                if matches!(instr, Bytecode::Call(_)) {
+                    stack.push(Slot::Int);
+                }
+            }
+            Bytecode::Return => {
+                if pop(&mut stack)? == Slot::Bool {
+                    return Err(not_compilable(name, "returns a bool"));
+                }
+            }
+            Bytecode::Out => return Err(not_compilable(name, "uses `out`")),
+            Bytecode::Concat => return Err(not_compilable(name, "concatenates strings")),
+            _ => return Err(not_compilable(name, format!("uses unsupported instruction {:?}", instr))),
         }
     }
+    Ok(callees)
 }
 
-// JIT执行字节码函数
-pub fn jit_execute_function(func: &CompiledFunction, args: &[u64]) -> Result<u64, String> {
-    let mut jit = JITCompiler::new()?;
-    let func_addr = jit.compile_function("anonymous", func)?;
-    jit.execute(func_addr, args)
+// 跳转目标所在的 pc（相对偏移从下一条指令算起）
+fn jump_target(pc: usize, offset: i32) -> usize {
+    (pc as i64 + 1 + offset as i64) as usize
+}
+
+// 基本块起点：函数开头、跳转目标以及跳转/返回之后的指令
+fn block_leaders(instructions: &[Bytecode]) -> HashSet<usize> {
+    let mut leaders = HashSet::from([0]);
+    for (pc, instr) in instructions.iter().enumerate() {
+        match instr {
+            Bytecode::Jump(offset) | Bytecode::JumpIfFalse(offset) | Bytecode::JumpIfTrue(offset) => {
+                leaders.insert(jump_target(pc, *offset));
+                leaders.insert(pc + 1);
+            }
+            Bytecode::Return | Bytecode::TailCall(_) => {
+                leaders.insert(pc + 1);
+            }
+            _ => {}
+        }
+    }
+    leaders
+}
+
+// 循环入口在缓存中的键
+fn loop_key(name: &str, pc: usize) -> String {
+    format!("{}@{}", name, pc)
+}
+
+// 局部变量的固定顺序：形参在前，其余按首次出现排列；循环入口按此顺序传入变量值
+pub fn local_names(func: &CompiledFunction) -> Vec<String> {
+    let mut names: Vec<String> = func.param_str.split(',').filter(|p| !p.is_empty()).map(String::from).collect();
+    for instr in &func.instructions {
+        if let Bytecode::LoadVar(var_name) | Bytecode::LoadReg(_, var_name) | Bytecode::StoreVar(var_name) = instr {
+            if !names.contains(var_name) {
+                names.push(var_name.clone());
+            }
+        }
+    }
+    names
 }
 
-// 直接JIT执行斐波那契函数
-pub fn jit_execute_fibonacci(n: u64) -> Result<u64, String> {
+// 把一个字节码函数翻译成 Cranelift IR：局部变量映射为值和位宽两个 Variable，操作数栈在编译期展开
+struct FunctionTranslator<'a> {
+    builder: FunctionBuilder<'a>,
+    module: &'a mut JITModule,
+    ids: &'a HashMap<String, FuncId>,
+    functions: &'a HashMap<String, Rc<CompiledFunction>>,
+    name: &'a str,
+    func: &'a CompiledFunction,
+    params: Vec<(Variable, Variable)>,
+    variables: HashMap<String, (Variable, Variable)>,
+    func_refs: HashMap<String, FuncRef>,
+    blocks: HashMap<usize, Block>,
+    // 已确定块参数个数的基本块
+    bound: HashSet<Block>,
+    // 每项是值和位宽；布尔值的位宽不会被读取
+    stack: Vec<(Value, Value)>,
+    ctx_ptr: Value,
+    body: Block,
+    exit: Block,
+    deopt: Block,
+}
+
+impl<'a> FunctionTranslator<'a> {
+    fn new(
+        mut builder: FunctionBuilder<'a>,
+        module: &'a mut JITModule,
+        ids: &'a HashMap<String, FuncId>,
+        functions: &'a HashMap<String, Rc<CompiledFunction>>,
+        name: &'a str,
+        entry_pc: Option<usize>,
+    ) -> Self {
+        let func = &functions[name];
+        let locals = local_names(func);
+        let arg_count = entry_pc.map_or(func.param_count, |_| locals.len());
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let ctx_ptr = builder.block_params(entry)[2 * arg_count];
+
+        let local_vars = |index: usize| (Variable::new(2 * index), Variable::new(2 * index + 1));
+        let mut variables = HashMap::new();
+        for (index, local) in locals.into_iter().enumerate() {
+            let (value, width) = local_vars(index);
+            builder.declare_var(value, I64);
+            builder.declare_var(width, I64);
+            variables.insert(local, (value, width));
+        }
+        let params = (0..func.param_count).map(local_vars).collect();
+
+        let blocks = block_leaders(&func.instructions)
+            .into_iter()
+            .map(|pc| (pc, builder.create_block()))
+            .collect::<HashMap<_, _>>();
+        let body = blocks[&0];
+        let start = blocks[&entry_pc.unwrap_or(0)];
+        let exit = builder.create_block();
+        builder.append_block_param(exit, I64);
+        builder.append_block_param(exit, I64);
+        let deopt = builder.create_block();
+
+        let mut translator = FunctionTranslator {
+            builder,
+            module,
+            ids,
+            functions,
+            name,
+            func,
+            params,
+            variables,
+            func_refs: HashMap::new(),
+            blocks,
+            bound: HashSet::new(),
+            stack: Vec::new(),
+            ctx_ptr,
+            body,
+            exit,
+            deopt,
+        };
+        let args: Vec<(Value, Value)> = translator.builder.block_params(entry)[..2 * arg_count]
+            .chunks(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+        match entry_pc {
+            None => translator.bind_locals(&args),
+            Some(_) => {
+                // 循环入口：局部变量直接取解释器中的当前值
+                for (index, (value, width)) in args.iter().enumerate() {
+                    let (value_var, width_var) = local_vars(index);
+                    translator.builder.def_var(value_var, *value);
+                    translator.builder.def_var(width_var, *width);
+                }
+            }
+        }
+
+        // 进入函数时增加调用深度，超过上限则回退
+        let depth = translator.load_ctx(DEPTH_OFFSET);
+        let depth = translator.builder.ins().iadd_imm(depth, 1);
+        translator.store_ctx(DEPTH_OFFSET, depth);
+        let max_depth = translator.load_ctx(MAX_DEPTH_OFFSET);
+        let too_deep = translator.builder.ins().icmp(IntCC::SignedGreaterThan, depth, max_depth);
+        translator.bound.insert(start);
+        translator.builder.ins().brif(too_deep, deopt, &[], start, &[]);
+        translator
+    }
+
+    // 跳到基本块时把操作数栈作为块参数传过去；第一次跳转决定参数个数
+    fn branch_args(&mut self, block: Block) -> Vec<Value> {
+        if self.bound.insert(block) {
+            for _ in 0..2 * self.stack.len() {
+                self.builder.append_block_param(block, I64);
+            }
+        }
+        self.stack.iter().flat_map(|&(value, width)| [value, width]).collect()
+    }
+
+    fn switch_to(&mut self, block: Block) {
+        self.bound.insert(block);
+        self.builder.switch_to_block(block);
+        self.stack = self.builder.block_params(block).chunks(2).map(|pair| (pair[0], pair[1])).collect();
+    }
+
+    fn load_ctx(&mut self, offset: i32) -> Value {
+        self.builder.ins().load(I64, MemFlags::trusted(), self.ctx_ptr, offset)
+    }
+
+    fn store_ctx(&mut self, offset: i32, value: Value) {
+        self.builder.ins().store(MemFlags::trusted(), value, self.ctx_ptr, offset);
+    }
+
+    // 与解释器的新帧一致：形参绑定实参，其余局部变量未赋值时读作 i64 的 0
+    fn bind_locals(&mut self, args: &[(Value, Value)]) {
+        let zero = self.builder.ins().iconst(I64, 0);
+        let i64_width = self.builder.ins().iconst(I64, I64_WIDTH);
+        for (value, width) in self.variables.values() {
+            self.builder.def_var(*value, zero);
+            self.builder.def_var(*width, i64_width);
+        }
+        for ((value_var, width_var), (value, width)) in self.params.iter().zip(args) {
+            self.builder.def_var(*value_var, *value);
+            self.builder.def_var(*width_var, *width);
+        }
+    }
+
+    // 条件成立时跳到回退路径，否则在新块中继续
+    fn deopt_if(&mut self, cond: Value) {
+        let next = self.builder.create_block();
+        self.builder.ins().brif(cond, self.deopt, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    fn pop(&mut self) -> (Value, Value) {
+        self.stack.pop().expect("operand stack checked before translation")
+    }
+
+    fn pop_args(&mut self, count: usize) -> Vec<(Value, Value)> {
+        self.stack.split_off(self.stack.len() - count)
+    }
+
+    // 弹出两个整数操作数，位宽取两者中较宽的一个，与解释器的类型提升一致
+    fn pop_operands(&mut self) -> (Value, Value, Value) {
+        let (b, b_width) = self.pop();
+        let (a, a_width) = self.pop();
+        let wider = self.builder.ins().icmp(IntCC::SignedGreaterThan, a_width, b_width);
+        let width = self.builder.ins().select(wider, a_width, b_width);
+        (a, b, width)
+    }
+
+    fn push_bool(&mut self, flag: Value) {
+        let value = self.builder.ins().uextend(I64, flag);
+        let width = self.builder.ins().iconst(I64, 0);
+        self.stack.push((value, width));
+    }
+
+    // 位宽编号对应的位数
+    fn bits(&mut self, width: Value) -> Value {
+        let eight = self.builder.ins().iconst(I64, 8);
+        self.builder.ins().ishl(eight, width)
+    }
+
+    // 截断到给定位宽后再符号扩展回 i64
+    fn wrap(&mut self, value: Value, width: Value) -> Value {
+        let bits = self.bits(width);
+        let sixty_four = self.builder.ins().iconst(I64, 64);
+        let shift = self.builder.ins().isub(sixty_four, bits);
+        let shifted = self.builder.ins().ishl(value, shift);
+        self.builder.ins().sshr(shifted, shift)
+    }
+
+    // 值超出位宽的表示范围时为真
+    fn exceeds(&mut self, value: Value, width: Value) -> Value {
+        let wrapped = self.wrap(value, width);
+        self.builder.ins().icmp(IntCC::NotEqual, wrapped, value)
+    }
+
+    fn call(&mut self, callee: &str, args: Vec<(Value, Value)>) -> (Value, Value) {
+        let func_ref = match self.func_refs.get(callee) {
+            Some(func_ref) => *func_ref,
+            None => {
+                let func_ref = self.module.declare_func_in_func(self.ids[callee], self.builder.func);
+                self.func_refs.insert(callee.to_string(), func_ref);
+                func_ref
+            }
+        };
+        let mut args: Vec<Value> = args.into_iter().flat_map(|(value, width)| [value, width]).collect();
+        args.push(self.ctx_ptr);
+        let call = self.builder.ins().call(func_ref, &args);
+        let result = (self.builder.inst_results(call)[0], self.builder.inst_results(call)[1]);
+        // 被调函数已回退时逐层退出
+        let deopt = self.load_ctx(DEOPT_OFFSET);
+        self.deopt_if(deopt);
+        result
+    }
+
+    fn compare(&mut self, cc: IntCC) {
+        let (b, _) = self.pop();
+        let (a, _) = self.pop();
+        let flag = self.builder.ins().icmp(cc, a, b);
+        self.push_bool(flag);
+    }
+
+    fn translate(mut self) {
+        let mut terminated = true;
+        for (pc, instr) in self.func.instructions.iter().enumerate() {
+            if let Some(block) = self.blocks.get(&pc).copied() {
+                if !terminated {
+                    let args = self.branch_args(block);
+                    self.builder.ins().jump(block, &args);
+                }
+                self.switch_to(block);
+                terminated = false;
+            }
+            match instr {
+                Bytecode::LoadConst(crate::types::Value::Integer(value)) => {
+                    let [value, width] = encode(value).expect("constant checked before translation");
+                    let value = self.builder.ins().iconst(I64, value);
+                    let width = self.builder.ins().iconst(I64, width);
+                    self.stack.push((value, width));
+                }
+                Bytecode::LoadConst(crate::types::Value::Bool(value)) => {
+                    let value = self.builder.ins().iconst(I64, *value as i64);
+                    let width = self.builder.ins().iconst(I64, 0);
+                    self.stack.push((value, width));
+                }
+                Bytecode::LoadVar(var_name) | Bytecode::LoadReg(_, var_name) => {
+                    let (value_var, width_var) = self.variables[var_name];
+                    let value = self.builder.use_var(value_var);
+                    let width = self.builder.use_var(width_var);
+                    self.stack.push((value, width));
+                }
+                Bytecode::StoreVar(var_name) => {
+                    let (value, width) = self.pop();
+                    let (value_var, width_var) = self.variables[var_name];
+                    self.builder.def_var(value_var, value);
+                    self.builder.def_var(width_var, width);
+                }
+                Bytecode::Add | Bytecode::Sub | Bytecode::Mul => {
+                    let (a, b, width) = self.pop_operands();
+                    let (result, overflow) = match instr {
+                        Bytecode::Add => self.builder.ins().sadd_overflow(a, b),
+                        Bytecode::Sub => self.builder.ins().ssub_overflow(a, b),
+                        _ => self.builder.ins().smul_overflow(a, b),
+                    };
+                    // 超出位宽时解释器报告加减溢出、把乘积提升为 BigInt，交给它重新执行
+                    self.deopt_if(overflow);
+                    let exceeds = self.exceeds(result, width);
+                    self.deopt_if(exceeds);
+                    self.stack.push((result, width));
+                }
+                Bytecode::AddI64 | Bytecode::SubI64 | Bytecode::MulI64 => {
+                    // 类型推断已确定结果是 i64，只需检查 64 位溢出
+                    let (b, _) = self.pop();
+                    let (a, _) = self.pop();
+                    let (result, overflow) = match instr {
+                        Bytecode::AddI64 => self.builder.ins().sadd_overflow(a, b),
+                        Bytecode::SubI64 => self.builder.ins().ssub_overflow(a, b),
+                        _ => self.builder.ins().smul_overflow(a, b),
+                    };
+                    self.deopt_if(overflow);
+                    let width = self.builder.ins().iconst(I64, I64_WIDTH);
+                    self.stack.push((result, width));
+                }
+                Bytecode::Div | Bytecode::Mod => {
+                    let (a, b, width) = self.pop_operands();
+                    // 除零和 MIN / -1 交给解释器报告错误
+                    let zero = self.builder.ins().icmp_imm(IntCC::Equal, b, 0);
+                    self.deopt_if(zero);
+                    let min = self.builder.ins().icmp_imm(IntCC::Equal, a, i64::MIN);
+                    let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, b, -1);
+                    let overflow = self.builder.ins().band(min, minus_one);
+                    self.deopt_if(overflow);
+                    let result = match instr {
+                        Bytecode::Div => {
+                            let result = self.builder.ins().sdiv(a, b);
+                            // 较窄位宽的 MIN / -1 同样溢出
+                            let exceeds = self.exceeds(result, width);
+                            self.deopt_if(exceeds);
+                            result
+                        }
+                        _ => self.builder.ins().srem(a, b),
+                    };
+                    self.stack.push((result, width));
+                }
+                Bytecode::Le => self.compare(IntCC::SignedLessThanOrEqual),
+                Bytecode::Lt => self.compare(IntCC::SignedLessThan),
+                Bytecode::Gt => self.compare(IntCC::SignedGreaterThan),
+                Bytecode::Ge => self.compare(IntCC::SignedGreaterThanOrEqual),
+                Bytecode::Eq => self.compare(IntCC::Equal),
+                Bytecode::Neg => {
+                    let (value, width) = self.pop();
+                    // 各位宽的 -MIN 都溢出，交给解释器报告
+                    let overflow = self.builder.ins().icmp_imm(IntCC::Equal, value, i64::MIN);
+                    self.deopt_if(overflow);
+                    let result = self.builder.ins().ineg(value);
+                    let exceeds = self.exceeds(result, width);
+                    self.deopt_if(exceeds);
+                    self.stack.push((result, width));
+                }
+                Bytecode::BitAnd | Bytecode::BitOr | Bytecode::BitXor => {
+                    let (a, b, width) = self.pop_operands();
+                    let result = match instr {
+                        Bytecode::BitAnd => self.builder.ins().band(a, b),
+                        Bytecode::BitOr => self.builder.ins().bor(a, b),
+                        _ => self.builder.ins().bxor(a, b),
+                    };
+                    self.stack.push((result, width));
+                }
+                Bytecode::Shl | Bytecode::Shr => {
+                    let (a, b, width) = self.pop_operands();
+                    // 移位量不在 0..位数 时交给解释器报告错误
+                    let bits = self.bits(width);
+                    let out_of_range = self.builder.ins().icmp(IntCC::UnsignedGreaterThanOrEqual, b, bits);
+                    self.deopt_if(out_of_range);
+                    let result = match instr {
+                        Bytecode::Shl => {
+                            // 左移出位宽的高位被丢弃
+                            let shifted = self.builder.ins().ishl(a, b);
+                            self.wrap(shifted, width)
+                        }
+                        _ => self.builder.ins().sshr(a, b),
+                    };
+                    self.stack.push((result, width));
+                }
+                Bytecode::BitNot => {
+                    let (value, width) = self.pop();
+                    let result = self.builder.ins().bnot(value);
+                    self.stack.push((result, width));
+                }
+                // 区间两端统一为较宽的位宽
+                Bytecode::RangeInit => {
+                    let (start, end, width) = self.pop_operands();
+                    self.stack.push((start, width));
+                    self.stack.push((end, width));
+                }
+                Bytecode::Not => {
+                    let (value, _) = self.pop();
+                    let flag = self.builder.ins().icmp_imm(IntCC::Equal, value, 0);
+                    self.push_bool(flag);
+                }
+                Bytecode::Jump(offset) => {
+                    let target = self.blocks[&jump_target(pc, *offset)];
+                    let args = self.branch_args(target);
+                    self.builder.ins().jump(target, &args);
+                    terminated = true;
+                }
+                Bytecode::JumpIfFalse(offset) | Bytecode::JumpIfTrue(offset) => {
+                    let (cond, _) = self.pop();
+                    let target = self.blocks[&jump_target(pc, *offset)];
+                    let next = self.blocks[&(pc + 1)];
+                    let target_args = self.branch_args(target);
+                    let next_args = self.branch_args(next);
+                    if matches!(instr, Bytecode::JumpIfTrue(_)) {
+                        self.builder.ins().brif(cond, target, &target_args, next, &next_args);
+                    } else {
+                        self.builder.ins().brif(cond, next, &next_args, target, &target_args);
+                    }
+                    terminated = true;
+                }
+                Bytecode::Call(callee) => {
+                    let count = self.functions[callee].param_count;
+                    let args = self.pop_args(count);
+                    let result = self.call(callee, args);
+                    self.stack.push(result);
+                }
+                Bytecode::TailCall(callee) => {
+                    let count = self.functions[callee].param_count;
+                    let args = self.pop_args(count);
+                    if callee == self.name {
+                        // 自身尾调用编译为跳回函数开头，和解释器一样不增加深度
+                        self.bind_locals(&args);
+                        let body_args = self.branch_args(self.body);
+                        self.builder.ins().jump(self.body, &body_args);
+                    } else {
+                        let (value, width) = self.call(callee, args);
+                        self.builder.ins().jump(self.exit, &[value, width]);
+                    }
+                    terminated = true;
+                }
+                Bytecode::Return => {
+                    let (value, width) = self.pop();
+                    self.builder.ins().jump(self.exit, &[value, width]);
+                    terminated = true;
+                }
+                _ => unreachable!("instruction checked before translation"),
+            }
+        }
+
+        // 执行到末尾（或跳到末尾）而没有 return 时返回 i64 的 0
+        let end = self.func.instructions.len();
+        if let Some(block) = self.blocks.get(&end).copied() {
+            if !terminated {
+                let args = self.branch_args(block);
+                self.builder.ins().jump(block, &args);
+            }
+            self.switch_to(block);
+            terminated = false;
+        }
+        if !terminated {
+            let zero = self.builder.ins().iconst(I64, 0);
+            let i64_width = self.builder.ins().iconst(I64, I64_WIDTH);
+            self.builder.ins().jump(self.exit, &[zero, i64_width]);
+        }
+
+        self.builder.switch_to_block(self.exit);
+        let (result, width) = (self.builder.block_params(self.exit)[0], self.builder.block_params(self.exit)[1]);
+        let depth = self.load_ctx(DEPTH_OFFSET);
+        let depth = self.builder.ins().iadd_imm(depth, -1);
+        self.store_ctx(DEPTH_OFFSET, depth);
+        self.builder.ins().return_(&[result, width]);
+
+        self.builder.switch_to_block(self.deopt);
+        let one = self.builder.ins().iconst(I64, 1);
+        self.store_ctx(DEOPT_OFFSET, one);
+        let zero = self.builder.ins().iconst(I64, 0);
+        self.builder.ins().return_(&[zero, zero]);
+
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+    }
+}
+
+// JIT执行字节码函数，None 表示需要回退到解释器
+pub fn jit_execute_function(func: &CompiledFunction, args: &[IntegerValue]) -> Result<Option<IntegerValue>, Diagnostic> {
+    if args.len() != func.param_count {
+        return Err(jit_error(format!("Parameter count mismatch: expected {} parameters, got {}", func.param_count, args.len())));
+    }
     let mut jit = JITCompiler::new()?;
-    
-    // 创建一个简单的CompiledFunction作为占位符
-    let func = CompiledFunction {
-        param_str: "n".to_string(),
-        instructions: vec![],
-        param_count: 1,
-        inline_hint: true,
-    };
-    
-    let func_addr = jit.compile_fibonacci_function("fibonacci", &func)?;
-    jit.execute(func_addr, &[n])
+    let functions = HashMap::from([("anonymous".to_string(), Rc::new(func.clone()))]);
+    jit.compile_function("anonymous", &functions)?;
+    jit.execute("anonymous", args, MAX_NATIVE_DEPTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::compile_to_bytecode;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn compile(source: &str, name: &str) -> (JITCompiler, Result<(), Diagnostic>) {
+        let program = compile_to_bytecode(&parse(tokenize(source).unwrap()).unwrap());
+        let mut jit = JITCompiler::new().unwrap();
+        let result = jit.compile_function(name, &program.compiled_functions);
+        (jit, result)
+    }
+
+    fn run(jit: &JITCompiler, name: &str, args: &[i64], max_depth: usize) -> Option<i64> {
+        let args: Vec<IntegerValue> = args.iter().map(|&n| IntegerValue::I64(n)).collect();
+        jit.execute(name, &args, max_depth).unwrap().map(|result| result.to_i64().unwrap())
+    }
+
+    #[test]
+    fn test_recursive_function_runs_natively() {
+        let source = "def fib n\n    if n < 2\n        return n\n    end\n    a = fib n - 1\n    b = fib n - 2\n    return a + b\nend\n";
+        let (jit, result) = compile(source, "fib");
+        result.unwrap();
+        assert_eq!(run(&jit, "fib", &[30], MAX_NATIVE_DEPTH), Some(832040));
+    }
+
+    #[test]
+    fn test_loops_and_tail_calls() {
+        let source = "def total n\n    acc = 0i64\n    while n > 0\n        if n % 3 == 0\n            acc += n\n        end\n        n -= 1\n    end\n    return acc\nend\ndef count n, acc\n    if n == 0\n        return acc\n    end\n    return count n - 1, acc + 1\nend\n";
+        let (mut jit, result) = compile(source, "total");
+        result.unwrap();
+        assert_eq!(run(&jit, "total", &[10], MAX_NATIVE_DEPTH), Some(18));
+        let program = compile_to_bytecode(&parse(tokenize(source).unwrap()).unwrap());
+        jit.compile_function("count", &program.compiled_functions).unwrap();
+        // 自身尾调用不占用本地栈深度
+        assert_eq!(run(&jit, "count", &[100_000, 0], 4), Some(100_000));
+    }
+
+    #[test]
+    fn test_overflow_and_depth_limit_deopt() {
+        let source = "def square n\n    return n * n\nend\ndef down n\n    if n == 0\n        return n\n    end\n    return n + down n - 1\nend\n";
+        let (mut jit, result) = compile(source, "square");
+        result.unwrap();
+        assert_eq!(run(&jit, "square", &[4_000_000_000], MAX_NATIVE_DEPTH), None);
+        assert_eq!(run(&jit, "square", &[3_000], MAX_NATIVE_DEPTH), Some(9_000_000));
+        let program = compile_to_bytecode(&parse(tokenize(source).unwrap()).unwrap());
+        jit.compile_function("down", &program.compiled_functions).unwrap();
+        assert_eq!(run(&jit, "down", &[10], MAX_NATIVE_DEPTH), Some(55));
+        assert_eq!(run(&jit, "down", &[10], 5), None);
+        // 只能执行已编译的函数，且实参个数必须一致
+        assert_eq!(jit.execute("missing", &[], MAX_NATIVE_DEPTH).unwrap_err().message, "`missing` has not been compiled");
+        assert!(jit.execute("down", &[], MAX_NATIVE_DEPTH).is_err());
+    }
+
+    #[test]
+    fn test_short_circuit_values_cross_blocks() {
+        let source = "def count n, m\n    acc = 0i64\n    while n > 0 and not n == m\n        if n % 2 == 0 or n % 3 == 0\n            acc += 1\n        end\n        n -= 1\n    end\n    return -acc\nend\n";
+        let (jit, result) = compile(source, "count");
+        result.unwrap();
+        assert_eq!(run(&jit, "count", &[12, 0], MAX_NATIVE_DEPTH), Some(-8));
+        assert_eq!(run(&jit, "count", &[12, 6], MAX_NATIVE_DEPTH), Some(-4));
+    }
+
+    #[test]
+    fn test_bitwise_operations_run_natively() {
+        let source = "def popcount n\n    count = 0i64\n    mask = 0i64 - 1 << 63\n    while n != 0\n        count += n & 1\n        n = n >> 1 & ~mask\n    end\n    return count\nend\n";
+        let (jit, result) = compile(source, "popcount");
+        result.unwrap();
+        assert_eq!(run(&jit, "popcount", &[0b1011_0110], MAX_NATIVE_DEPTH), Some(5));
+        assert_eq!(run(&jit, "popcount", &[-1], MAX_NATIVE_DEPTH), Some(64));
+    }
+
+    #[test]
+    fn test_functions_outside_i64_subset_are_rejected() {
+        let (_, result) = compile("def show n\n    out n\n    return n\nend\n", "show");
+        assert_eq!(result.unwrap_err().message, "Cannot JIT-compile `show`: uses `out`");
+        let (_, result) = compile("def positive n\n    return n > 0\nend\n", "positive");
+        assert_eq!(result.unwrap_err().message, "Cannot JIT-compile `positive`: returns a bool");
+    }
+
+    #[test]
+    fn test_narrow_integers_keep_interpreter_widths() {
+        let source = "def total n\n    acc = 0\n    i = 0\n    while i < n\n        acc += i\n        i += 1\n    end\n    return acc\nend\ndef shift n\n    return n << 4\nend\ndef negate n\n    return -n\nend\n";
+        let (mut jit, result) = compile(source, "total");
+        result.unwrap();
+        // 结果保持 i8；和超过 i8 时交给解释器报告溢出
+        assert_eq!(jit.execute("total", &[IntegerValue::I8(10)], MAX_NATIVE_DEPTH).unwrap(), Some(IntegerValue::I8(45)));
+        assert_eq!(jit.execute("total", &[IntegerValue::I8(20)], MAX_NATIVE_DEPTH).unwrap(), None);
+        assert_eq!(jit.execute("total", &[IntegerValue::I32(100_000)], MAX_NATIVE_DEPTH).unwrap(), None);
+        let program = compile_to_bytecode(&parse(tokenize(source).unwrap()).unwrap());
+        // 左移在原位宽内丢弃高位
+        jit.compile_function("shift", &program.compiled_functions).unwrap();
+        assert_eq!(jit.execute("shift", &[IntegerValue::I8(9)], MAX_NATIVE_DEPTH).unwrap(), Some(IntegerValue::I8(-112)));
+        assert_eq!(jit.execute("shift", &[IntegerValue::I16(9)], MAX_NATIVE_DEPTH).unwrap(), Some(IntegerValue::I16(144)));
+        // i8 的 -MIN 溢出，交给解释器报告
+        jit.compile_function("negate", &program.compiled_functions).unwrap();
+        assert_eq!(jit.execute("negate", &[IntegerValue::I8(i8::MIN)], MAX_NATIVE_DEPTH).unwrap(), None);
+        assert_eq!(jit.execute("negate", &[IntegerValue::I16(i8::MIN.into())], MAX_NATIVE_DEPTH).unwrap(), Some(IntegerValue::I16(128)));
+    }
 }
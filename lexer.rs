@@ -1,5 +1,6 @@
 use logos::{Logos};
-use crate::types::{IntegerValue, StringValue, Value};
+use crate::diagnostic::{Diagnostic, ErrorKind, LineIndex, Span, Stage};
+use crate::types::{FloatValue, IntegerValue, StringValue};
 
 #[derive(Logos, Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Token {
@@ -9,9 +10,30 @@ pub enum Token {
     #[token("if")]
     If,
 
+    #[token("elif")]
+    Elif,
+
+    #[token("else")]
+    Else,
+
     #[token("while")]
     While,
 
+    #[token("for")]
+    For,
+
+    #[token("in")]
+    In,
+
+    #[token("break")]
+    Break,
+
+    #[token("continue")]
+    Continue,
+
+    #[token("global")]
+    Global,
+
     #[token("return")]
     Return,
 
@@ -21,6 +43,21 @@ pub enum Token {
     #[token("out")]
     Out,
 
+    #[token("and")]
+    And,
+
+    #[token("or")]
+    Or,
+
+    #[token("not")]
+    Not,
+
+    #[token("true")]
+    True,
+
+    #[token("false")]
+    False,
+
     #[token("=")]
     Assign,
 
@@ -37,6 +74,9 @@ pub enum Token {
     #[token("%=")]
     ModAssign,
 
+    #[token("/=")]
+    DivAssign,
+
     #[token("+")]
     Plus,
 
@@ -49,6 +89,28 @@ pub enum Token {
     #[token("%")]
     Mod,
 
+    // `//` 注释是更长的匹配，不会被识别为除号
+    #[token("/")]
+    Div,
+
+    #[token("&")]
+    BitAnd,
+
+    #[token("|")]
+    BitOr,
+
+    #[token("^")]
+    BitXor,
+
+    #[token("~")]
+    BitNot,
+
+    #[token("<<")]
+    Shl,
+
+    #[token(">>")]
+    Shr,
+
     #[token("<")]
     Lt,
 
@@ -64,9 +126,40 @@ pub enum Token {
     #[token("==")]
     Eq,
 
+    #[token("!=")]
+    NotEq,
+
     #[token(",")]
     Comma,
 
+    #[token("(")]
+    LParen,
+
+    #[token(")")]
+    RParen,
+
+    #[token("[")]
+    LBracket,
+
+    #[token("]")]
+    RBracket,
+
+    #[token("{")]
+    LBrace,
+
+    #[token("}")]
+    RBrace,
+
+    #[token(":")]
+    Colon,
+
+    // 区间 `a..b`；浮点数要求小数点后有数字，`0..n` 不会被识别为浮点数
+    #[token("..")]
+    DotDot,
+
+    #[token("@")]
+    At,
+
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
     Ident(String),
 
@@ -89,34 +182,24 @@ pub enum Token {
     })]
     Number(IntegerValue),
 
-    #[regex(r"[0-9]+i8", |lex| {
-        let value = lex.slice().trim_end_matches("i8").parse::<i8>().unwrap();
-        IntegerValue::I8(value)
-    })]
+    // 浮点数必须带小数部分或指数：1.5、2e10、6.02e-23
+    #[regex(r"[0-9]+\.[0-9]+([eE][+-]?[0-9]+)?", |lex| lex.slice().parse::<f64>().ok().map(FloatValue))]
+    #[regex(r"[0-9]+[eE][+-]?[0-9]+", |lex| lex.slice().parse::<f64>().ok().map(FloatValue))]
+    Float(FloatValue),
+
+    #[regex(r"[0-9]+i8", |lex| lex.slice().trim_end_matches("i8").parse::<i8>().ok().map(IntegerValue::I8))]
     TypedNumber(IntegerValue),
 
-    #[regex(r"[0-9]+i16", |lex| {
-        let value = lex.slice().trim_end_matches("i16").parse::<i16>().unwrap();
-        IntegerValue::I16(value)
-    })]
+    #[regex(r"[0-9]+i16", |lex| lex.slice().trim_end_matches("i16").parse::<i16>().ok().map(IntegerValue::I16))]
     TypedNumber16(IntegerValue),
 
-    #[regex(r"[0-9]+i32", |lex| {
-        let value = lex.slice().trim_end_matches("i32").parse::<i32>().unwrap();
-        IntegerValue::I32(value)
-    })]
+    #[regex(r"[0-9]+i32", |lex| lex.slice().trim_end_matches("i32").parse::<i32>().ok().map(IntegerValue::I32))]
     TypedNumber32(IntegerValue),
 
-    #[regex(r"[0-9]+i64", |lex| {
-        let value = lex.slice().trim_end_matches("i64").parse::<i64>().unwrap();
-        IntegerValue::I64(value)
-    })]
+    #[regex(r"[0-9]+i64", |lex| lex.slice().trim_end_matches("i64").parse::<i64>().ok().map(IntegerValue::I64))]
     TypedNumber64(IntegerValue),
 
-    #[regex(r"[0-9]+i128", |lex| {
-        let value = lex.slice().trim_end_matches("i128").parse::<i128>().unwrap();
-        IntegerValue::I128(value)
-    })]
+    #[regex(r"[0-9]+i128", |lex| lex.slice().trim_end_matches("i128").parse::<i128>().ok().map(IntegerValue::I128))]
     TypedNumber128(IntegerValue),
 
     #[regex(r"[0-9]+bigint", |lex| {
@@ -125,33 +208,159 @@ pub enum Token {
     })]
     TypedNumberBigInt(IntegerValue),
 
-    // 暂时注释掉字符串字面量支持，直到正则表达式问题解决
-    // #[regex(r"\"([^\"\\]|\\.)*\"")] 
-    // String(StringValue),
+    // 字符串字面量，支持 \n \t \r \0 \\ \" 转义
+    #[regex(r#""([^"\\]|\\.)*""#, |lex| unescape(lex.slice()))]
+    String(StringValue),
 
-    #[regex(r"\s+", logos::skip)]
+    // 语句在行尾结束；括号内的换行由 tokenize 丢弃
+    #[token("\n")]
+    Newline,
+
+    #[regex(r"[ \t\r\x0c]+", logos::skip)]
     Whitespace,
 
     #[regex(r"//.*", logos::skip)]
     Comment,
 }
 
-pub fn tokenize(code: &str) -> Result<Vec<Token>, String> {
+// 处理字符串字面量中的转义序列（输入包含首尾引号）
+fn unescape(literal: &str) -> Option<StringValue> {
+    let inner = &literal[1..literal.len() - 1];
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            'r' => value.push('\r'),
+            '0' => value.push('\0'),
+            '\\' => value.push('\\'),
+            '"' => value.push('"'),
+            _ => return None,
+        }
+    }
+    Some(StringValue::new(value))
+}
+
+// 带位置信息的 token
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+pub fn tokenize(code: &str) -> Result<Vec<SpannedToken>, Diagnostic> {
     let mut lexer = Token::lexer(code);
     let mut tokens = Vec::new();
+    let line_index = LineIndex::new(code);
+    let mut depth = 0usize;  // 未闭合的括号层数
 
     while let Some(token) = lexer.next() {
+        let span = line_index.span(code, lexer.span());
         match token {
             Ok(token) => {
-                tokens.push(token);
+                match token {
+                    Token::LParen | Token::LBracket | Token::LBrace => depth += 1,
+                    Token::RParen | Token::RBracket | Token::RBrace => depth = depth.saturating_sub(1),
+                    // 括号内的换行是续行；开头的换行和空行不产生 token
+                    Token::Newline if depth > 0 || matches!(tokens.last(), None | Some(SpannedToken { token: Token::Newline, .. })) => continue,
+                    _ => {}
+                }
+                tokens.push(SpannedToken { token, span });
             }
             Err(_) => {
-                let span = lexer.span();
-                let error_char = &code[span.clone()];
-                return Err(format!("Unexpected character: '{}' at position {}", error_char, span.start));
+                let error_char = lexer.slice();
+                // 带类型后缀的整数超出该类型的范围
+                let (digits, suffix) = error_char.split_at(error_char.find(|c: char| !c.is_ascii_digit()).unwrap_or(error_char.len()));
+                if !digits.is_empty() && ["i8", "i16", "i32", "i64", "i128"].contains(&suffix) {
+                    let message = format!("{} is out of range for {}", digits, suffix);
+                    return Err(Diagnostic::new(Stage::Lexer, ErrorKind::InvalidLiteral, message).at(span));
+                }
+                if error_char.starts_with('"') {
+                    // 未闭合的字符串或非法转义
+                    let message = if error_char.len() > 1 && error_char.ends_with('"') {
+                        format!("Invalid escape sequence in string literal {}", error_char)
+                    } else {
+                        "Unterminated string literal".to_string()
+                    };
+                    return Err(Diagnostic::new(Stage::Lexer, ErrorKind::InvalidLiteral, message).at(span));
+                }
+                return Err(Diagnostic::new(Stage::Lexer, ErrorKind::UnexpectedCharacter, format!("Unexpected character: '{}'", error_char)).at(span));
             }
         }
     }
 
     Ok(tokens)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_escapes() {
+        let tokens = tokenize(r#"out "a\tb\n\"c\"""#).unwrap();
+        assert_eq!(tokens[1].token, Token::String(StringValue::new("a\tb\n\"c\"".to_string())));
+    }
+
+    #[test]
+    fn test_unterminated_string() {
+        let err = tokenize("out \"abc").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidLiteral);
+        assert_eq!(err.span.unwrap().col, 5);
+    }
+
+    #[test]
+    fn test_typed_literal_out_of_range() {
+        assert_eq!(tokenize("x = 127i8").unwrap()[2].token, Token::TypedNumber(IntegerValue::I8(127)));
+        let err = tokenize("x = 1\ny = 255i8").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidLiteral);
+        assert_eq!(err.message, "255 is out of range for i8");
+        let span = err.span.unwrap();
+        assert_eq!((span.line, span.col), (2, 5));
+        assert_eq!(tokenize("99999999999999999999i64").unwrap_err().message, "99999999999999999999 is out of range for i64");
+    }
+
+    #[test]
+    fn test_division_tokens_and_comments() {
+        let tokens: Vec<Token> = tokenize("x /= a / b // c / d").unwrap().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[1], Token::DivAssign);
+        assert_eq!(tokens[3], Token::Div);
+        assert_eq!(tokens.len(), 5);
+    }
+
+    #[test]
+    fn test_float_literals() {
+        let tokens: Vec<Token> = tokenize("1.5 2e10 6.02e-23 7 3i8").unwrap().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[0], Token::Float(FloatValue(1.5)));
+        assert_eq!(tokens[1], Token::Float(FloatValue(2e10)));
+        assert_eq!(tokens[2], Token::Float(FloatValue(6.02e-23)));
+        assert_eq!(tokens[3], Token::Number(IntegerValue::I8(7)));
+        assert_eq!(tokens[4], Token::TypedNumber(IntegerValue::I8(3)));
+    }
+
+    #[test]
+    fn test_bitwise_and_shift_tokens() {
+        let tokens: Vec<Token> = tokenize("a<<b>>c<=d&e|f^~g").unwrap().into_iter().map(|t| t.token).collect();
+        assert_eq!(tokens[1], Token::Shl);
+        assert_eq!(tokens[3], Token::Shr);
+        assert_eq!(tokens[5], Token::Le);
+        assert_eq!(tokens[7], Token::BitAnd);
+        assert_eq!(tokens[9], Token::BitOr);
+        assert_eq!(tokens[11..13], [Token::BitXor, Token::BitNot]);
+    }
+
+    #[test]
+    fn test_newline_tokens() {
+        let tokens: Vec<Token> = tokenize("\nx = [1,\n  2]\r\n\n// note\nout f(x,\n 3)\n").unwrap().into_iter().map(|t| t.token).collect();
+        let lines: Vec<&[Token]> = tokens.split(|t| *t == Token::Newline).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].len(), 7);
+        assert_eq!(lines[1].len(), 7);
+        assert!(lines[2].is_empty());
+    }
+}
@@ -10,6 +10,8 @@ mod profiler;
 mod memory;
 mod bytecode;
 mod types;
+mod builtins;
+mod diagnostic;
 
 pub use lexer::*;
 pub use parser::*;
@@ -23,3 +25,5 @@ pub use profiler::*;
 pub use memory::*;
 pub use bytecode::*;
 pub use types::*;
+pub use builtins::*;
+pub use diagnostic::*;
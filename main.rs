@@ -17,11 +17,16 @@ mod profiler;
 mod memory;
 mod bytecode;
 mod types;
+mod builtins;
+mod diagnostic;
 
 #[derive(Parser)]
 pub struct Cli {
     #[clap(long, short, help = "Specify the syl file to run")]
     pub file: Option<PathBuf>,
+
+    #[clap(long, help = "Reject conditions that are not booleans, such as `if 5`")]
+    pub strict_conditions: bool,
     
     #[clap(subcommand)]
     pub command: Option<Commands>,
@@ -59,19 +64,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut code = String::new();
     f.read_to_string(&mut code)?;
 
+    // 将诊断信息渲染为带源码位置的错误消息后退出
+    let file_name = normalized_path.display().to_string();
+    let report_all = |errors: Vec<diagnostic::Diagnostic>| -> ! {
+        for err in &errors {
+            eprintln!("{}\n", err.render(&file_name, &code));
+        }
+        eprintln!("aborting due to {} previous error(s)", errors.len());
+        std::process::exit(1)
+    };
+    let report = |err: diagnostic::Diagnostic| -> ! { report_all(vec![err]) };
+
     let tokens = profiler::profile("tokenization", || {
         lexer::tokenize(&code)
-    })?;
+    }).unwrap_or_else(|err| report(err));
     //println!("Tokens: {:?}", tokens);
 
     let ast = profiler::profile("parsing", || {
         parser::parse(tokens)
-    })?;
+    }).unwrap_or_else(|errors| report_all(errors));
    // println!("AST: {:#?}", ast);
 
     let semantic_ast = profiler::profile("semantic_analysis", || {
-        semantic::analyze(ast)
-    })?;
+        semantic::analyze_with_config(ast, semantic::SemanticConfig { strict_conditions: cli.strict_conditions })
+    }).unwrap_or_else(|err| report(err));
     //println!("Semantic AST: {:?}", semantic_ast);
 
     let ir = profiler::profile("code_generation", || {
@@ -82,7 +98,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!();
     let (result, output) = profiler::profile("execution", || {
         executor::execute(ir)
-    })?;
+    }).unwrap_or_else(|err| report(err));
     
     // 打印分析结果
     println!("========================================");
@@ -1,5 +1,6 @@
-use crate::ast::{Expr, BinOpType, Stmt, Program};
-use crate::lexer::Token;
+use crate::ast::{Expr, ExprKind, BinOpType, ForTarget, UnaryOp, Stmt, StmtKind, Program};
+use crate::diagnostic::{Diagnostic, ErrorKind, Span, Stage};
+use crate::lexer::{SpannedToken, Token};
 
 // Parser implementation with function identification during parsing
 // Changes made to fix function call identification issue:
@@ -10,20 +11,103 @@ use crate::lexer::Token;
 // This ensures that only actual functions are treated as function calls, preventing incorrect argument parsing
 // for non-function identifiers.
 
+// 表达式由一个优先级爬升（Pratt）解析器处理，所有语句都通过 parse_expr 解析表达式。
+// 优先级数值越大绑定越紧：
+//
+// | 优先级 | 运算符                        | 结合性         |
+// |--------|-------------------------------|----------------|
+// | 1      | `or`                          | 左结合         |
+// | 2      | `and`                         | 左结合         |
+// | -      | 前缀 `not`                    | 操作数不含 `and`/`or` |
+// | 3      | `==` `!=`                     | 不能连用       |
+// | 4      | `<` `<=` `>` `>=`             | 不能连用       |
+// | 5      | `|`                           | 左结合         |
+// | 6      | `^`                           | 左结合         |
+// | 7      | `&`                           | 左结合         |
+// | 8      | `<<` `>>`                     | 左结合         |
+// | 9      | `+` `-`                       | 左结合         |
+// | 10     | `*` `/` `%`                   | 左结合         |
+// | -      | 前缀 `-` `~`                  | 操作数只含下标 |
+// | -      | 后缀下标 `a[i]`、调用 `f(x)`   |                |
+//
+// 不带括号的调用 `f a, b` 的参数是完整的表达式，一直延伸到逗号或行尾。
+
+// `and` 的优先级；`not` 的操作数只包含比它更紧的运算符
+const AND_PRECEDENCE: u32 = 2;
+
+// 二元运算符的结合性
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Assoc {
+    Left,
+    // `a < b < c` 这样的连用是语法错误
+    NonAssoc,
+}
+
+// 二元运算符表：token -> (优先级, 结合性, 运算)
+fn infix_operator(token: &Token) -> Option<(u32, Assoc, BinOpType)> {
+    static INFIX_OPERATORS: std::sync::OnceLock<std::collections::HashMap<Token, (u32, Assoc, BinOpType)>> = std::sync::OnceLock::new();
+    let map = INFIX_OPERATORS.get_or_init(|| {
+        use Assoc::{Left, NonAssoc};
+        let mut map = std::collections::HashMap::new();
+        map.insert(Token::Or, (1, Left, BinOpType::Or));
+        map.insert(Token::And, (AND_PRECEDENCE, Left, BinOpType::And));
+        map.insert(Token::Eq, (3, NonAssoc, BinOpType::Eq));
+        map.insert(Token::NotEq, (3, NonAssoc, BinOpType::NotEq));
+        map.insert(Token::Lt, (4, NonAssoc, BinOpType::Lt));
+        map.insert(Token::Le, (4, NonAssoc, BinOpType::Le));
+        map.insert(Token::Gt, (4, NonAssoc, BinOpType::Gt));
+        map.insert(Token::Ge, (4, NonAssoc, BinOpType::Ge));
+        map.insert(Token::BitOr, (5, Left, BinOpType::BitOr));
+        map.insert(Token::BitXor, (6, Left, BinOpType::BitXor));
+        map.insert(Token::BitAnd, (7, Left, BinOpType::BitAnd));
+        map.insert(Token::Shl, (8, Left, BinOpType::Shl));
+        map.insert(Token::Shr, (8, Left, BinOpType::Shr));
+        map.insert(Token::Plus, (9, Left, BinOpType::Plus));
+        map.insert(Token::Minus, (9, Left, BinOpType::Minus));
+        map.insert(Token::Mul, (10, Left, BinOpType::Mul));
+        map.insert(Token::Div, (10, Left, BinOpType::Div));
+        map.insert(Token::Mod, (10, Left, BinOpType::Mod));
+        map
+    });
+    map.get(token).copied()
+}
+
+// 用于错误消息的token描述
+fn describe(token: Option<&Token>) -> String {
+    match token {
+        Some(Token::Newline) => "end of line".to_string(),
+        Some(token) => format!("{:?}", token),
+        None => "end of input".to_string(),
+    }
+}
+
+// 能够开始一个表达式的token；函数名后同一行出现这些token时视为调用参数
+fn starts_expr(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Ident(_) | Token::Number(_) | Token::TypedNumber(_) | Token::TypedNumber16(_) | Token::TypedNumber32(_)
+            | Token::TypedNumber64(_) | Token::TypedNumber128(_) | Token::TypedNumberBigInt(_) | Token::Float(_)
+            | Token::String(_) | Token::True | Token::False | Token::Minus | Token::Not | Token::BitNot
+            | Token::LParen | Token::LBracket | Token::LBrace
+    )
+}
+
 struct Parser {
-    tokens: Vec<Token>,
+    tokens: Vec<SpannedToken>,
     pos: usize,
     functions: std::collections::HashSet<String>,
     function_locations: std::collections::HashMap<String, usize>,
+    errors: Vec<Diagnostic>,  // 收集到的所有语法错误
 }
 
 impl Parser {
-    fn new(tokens: Vec<Token>) -> Self {
+    fn new(tokens: Vec<SpannedToken>) -> Self {
         let mut parser = Parser {
             tokens,
             pos: 0,
             functions: std::collections::HashSet::new(),
             function_locations: std::collections::HashMap::new(),
+            errors: Vec::new(),
         };
         parser.scan_function_locations();
         parser
@@ -34,14 +118,15 @@ impl Parser {
         self.pos = 0;
         
         while self.pos < self.tokens.len() {
-            if let Some(Token::Def) = self.tokens.get(self.pos) {
+            if let Some(Token::Def) = self.peek() {
                 self.pos += 1;
-                if let Some(Token::Ident(name)) = self.tokens.get(self.pos) {
+                if let Some(Token::Ident(name)) = self.peek() {
+                    let name = name.clone();
                     self.functions.insert(name.clone());
-                    self.function_locations.insert(name.clone(), self.pos - 1);
+                    self.function_locations.insert(name, self.pos - 1);
                     // Skip the rest of the function definition
                     while self.pos < self.tokens.len() {
-                        if let Some(Token::End) = self.tokens.get(self.pos) {
+                        if let Some(Token::End) = self.peek() {
                             self.pos += 1;
                             break;
                         }
@@ -56,17 +141,48 @@ impl Parser {
         self.pos = original_pos;
     }
 
+    // 用户定义的函数或内置函数
     fn is_function(&self, name: &str) -> bool {
-        self.functions.contains(name)
+        self.functions.contains(name) || crate::builtins::is_builtin(name)
     }
 
     fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.pos)
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    // 当前token的位置；到达输入末尾时指向最后一个token之后
+    fn peek_span(&self) -> Span {
+        if let Some(token) = self.tokens.get(self.pos) {
+            token.span
+        } else if let Some(last) = self.tokens.last() {
+            Span::new(last.span.end, last.span.end, last.span.line, last.span.col + (last.span.end - last.span.start))
+        } else {
+            Span::new(0, 0, 1, 1)
+        }
+    }
+
+    // 上一个已消费token的位置
+    fn prev_span(&self) -> Span {
+        if self.pos > 0 {
+            self.tokens[self.pos - 1].span
+        } else {
+            self.peek_span()
+        }
+    }
+
+    // 在当前位置构造语法错误
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        let kind = if self.peek().is_none() {
+            ErrorKind::UnexpectedEof
+        } else {
+            ErrorKind::UnexpectedToken
+        };
+        Diagnostic::new(Stage::Parser, kind, message).at(self.peek_span())
     }
 
     fn consume(&mut self) -> Option<Token> {
         if self.pos < self.tokens.len() {
-            let token = self.tokens[self.pos].clone();
+            let token = self.tokens[self.pos].token.clone();
             self.pos += 1;
             Some(token)
         } else {
@@ -80,165 +196,224 @@ impl Parser {
         }
     }
 
-    fn expect(&mut self, expected: Token) -> Result<(), String> {
-        if let Some(token) = self.peek() {
-            if token == &expected {
-                self.consume();
-                Ok(())
-            } else {
-                Err(format!("Expected {:?}, got {:?}", expected, token))
-            }
-        } else {
-            Err("Unexpected end of input".to_string())
+    fn skip_newlines(&mut self) {
+        while let Some(Token::Newline) = self.peek() {
+            self.consume_no_clone();
         }
     }
 
-    fn parse_expr(&mut self) -> Result<Expr, String> {
-        self.parse_bin_op(0)
+    // 语句和语句块头部必须在行尾结束；换行本身留给外层跳过
+    fn expect_line_end(&self, what: &str) -> Result<(), Diagnostic> {
+        match self.peek() {
+            Some(Token::Newline) | None => Ok(()),
+            other => Err(self.error(format!("Expected end of line after {}, got {}", what, describe(other)))),
+        }
     }
 
-    fn parse_bin_op(&mut self, precedence: u32) -> Result<Expr, String> {
-        let mut left = self.parse_primary()?;
+    fn parse_expr(&mut self) -> Result<Expr, Diagnostic> {
+        self.parse_bin_op(0)
+    }
 
-        while let Some(token) = self.peek() {
-            let (op_prec, op_type) = match token {
-                Token::Mul | Token::Mod => (3, match token {
-                    Token::Mul => BinOpType::Mul,
-                    Token::Mod => BinOpType::Mod,
-                    _ => unreachable!(),
-                }),
-                Token::Plus | Token::Minus => (2, match token {
-                    Token::Plus => BinOpType::Plus,
-                    Token::Minus => BinOpType::Minus,
-                    _ => unreachable!(),
-                }),
-                Token::Lt => (1, BinOpType::Lt),
-                Token::Le => (1, BinOpType::Le),
-                Token::Gt => (1, BinOpType::Gt),
-                Token::Ge => (1, BinOpType::Ge),
-                Token::Eq => (0, BinOpType::Eq),
-                _ => break,
-            };
+    // 解析优先级高于 `precedence` 的二元运算链
+    fn parse_bin_op(&mut self, precedence: u32) -> Result<Expr, Diagnostic> {
+        let mut left = self.parse_unary()?;
+        let mut chained = None;  // 上一个不可连用运算符的优先级
 
+        while let Some((op_prec, assoc, op_type)) = self.peek().and_then(infix_operator) {
             if op_prec <= precedence {
                 break;
             }
-
-            self.consume();
+            if assoc == Assoc::NonAssoc && chained == Some(op_prec) {
+                return Err(self.error("Comparison operators cannot be chained; combine the comparisons with `and`"));
+            }
+            self.consume_no_clone();
+            // 左结合：右操作数只包含绑定更紧的运算符
             let right = self.parse_bin_op(op_prec)?;
-            left = Expr::BinOp(Box::new(left), op_type, Box::new(right));
+            let span = left.span.to(right.span);
+            left = Expr::new(ExprKind::BinOp(Box::new(left), op_type, Box::new(right)), span);
+            chained = (assoc == Assoc::NonAssoc).then_some(op_prec);
         }
 
         Ok(left)
     }
 
-    fn parse_primary(&mut self) -> Result<Expr, String> {
-        let token = self.tokens.get(self.pos).cloned();
+    // 前缀运算符：`-`、`~` 绑定最紧，`not` 低于比较、高于 `and`
+    fn parse_unary(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.peek_span();
+        let op = match self.peek() {
+            Some(Token::Minus) => UnaryOp::Neg,
+            Some(Token::BitNot) => UnaryOp::BitNot,
+            Some(Token::Not) => UnaryOp::Not,
+            _ => return self.parse_primary(),
+        };
+        self.consume_no_clone();
+        // `-a * b` 即 `(-a) * b`；`not a == b` 即 `not (a == b)`
+        let operand = if op == UnaryOp::Not { self.parse_bin_op(AND_PRECEDENCE)? } else { self.parse_unary()? };
+        let span = start.to(operand.span);
+        Ok(Expr::new(ExprKind::Unary(op, Box::new(operand)), span))
+    }
+
+    // 基本表达式及其后的下标访问：`a[i][j]`
+    fn parse_primary(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.parse_atom()?;
+        while let Some(Token::LBracket) = self.peek() {
+            self.consume_no_clone();
+            let index = self.parse_expr()?;
+            self.expect_closing(Token::RBracket, "]")?;
+            let span = expr.span.to(self.prev_span());
+            expr = Expr::new(ExprKind::Index(Box::new(expr), Box::new(index)), span);
+        }
+        Ok(expr)
+    }
+
+    // 消费闭合括号或分隔符，缺少时报告
+    fn expect_closing(&mut self, token: Token, text: &str) -> Result<(), Diagnostic> {
+        if self.peek() == Some(&token) {
+            self.consume_no_clone();
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected `{}`, got {}", text, describe(self.peek()))))
+        }
+    }
+
+    // 列表字面量，允许末尾多一个逗号：`[1, 2, 3,]`
+    fn parse_list(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.peek_span();
+        self.consume_no_clone();
+        let mut items = Vec::new();
+        while self.peek() != Some(&Token::RBracket) {
+            items.push(self.parse_expr()?);
+            if let Some(Token::Comma) = self.peek() {
+                self.consume_no_clone();
+            } else {
+                break;
+            }
+        }
+        self.expect_closing(Token::RBracket, "]")?;
+        Ok(Expr::new(ExprKind::List(items), start.to(self.prev_span())))
+    }
+
+    // 映射字面量 `{"a": 1, 2: "b"}`，同样允许末尾多一个逗号
+    fn parse_map(&mut self) -> Result<Expr, Diagnostic> {
+        let start = self.peek_span();
+        self.consume_no_clone();
+        let mut entries = Vec::new();
+        while self.peek() != Some(&Token::RBrace) {
+            let key = self.parse_expr()?;
+            self.expect_closing(Token::Colon, ":")?;
+            let value = self.parse_expr()?;
+            entries.push((key, value));
+            if let Some(Token::Comma) = self.peek() {
+                self.consume_no_clone();
+            } else {
+                break;
+            }
+        }
+        self.expect_closing(Token::RBrace, "}")?;
+        Ok(Expr::new(ExprKind::Map(entries), start.to(self.prev_span())))
+    }
+
+    fn parse_atom(&mut self) -> Result<Expr, Diagnostic> {
+        let token = self.peek().cloned();
+        let start = self.peek_span();
         match token {
             Some(Token::Number(n)) => {
                 self.consume();
-                Ok(Expr::Number(n))
+                Ok(Expr::new(ExprKind::Number(n), start))
             }
             Some(Token::TypedNumber(value)) => {
                 self.consume();
-                Ok(Expr::TypedNumber(value))
+                Ok(Expr::new(ExprKind::TypedNumber(value), start))
             }
             Some(Token::TypedNumber16(value)) => {
                 self.consume();
-                Ok(Expr::TypedNumber(value))
+                Ok(Expr::new(ExprKind::TypedNumber(value), start))
             }
             Some(Token::TypedNumber32(value)) => {
                 self.consume();
-                Ok(Expr::TypedNumber(value))
+                Ok(Expr::new(ExprKind::TypedNumber(value), start))
             }
             Some(Token::TypedNumber64(value)) => {
                 self.consume();
-                Ok(Expr::TypedNumber(value))
+                Ok(Expr::new(ExprKind::TypedNumber(value), start))
             }
             Some(Token::TypedNumber128(value)) => {
                 self.consume();
-                Ok(Expr::TypedNumber(value))
+                Ok(Expr::new(ExprKind::TypedNumber(value), start))
             }
             Some(Token::TypedNumberBigInt(value)) => {
                 self.consume();
-                Ok(Expr::TypedNumber(value))
+                Ok(Expr::new(ExprKind::TypedNumber(value), start))
             },
+            Some(Token::Float(value)) => {
+                self.consume();
+                Ok(Expr::new(ExprKind::Float(value), start))
+            }
+            Some(Token::String(value)) => {
+                self.consume();
+                Ok(Expr::new(ExprKind::Str(value), start))
+            }
+            Some(Token::True) | Some(Token::False) => {
+                self.consume();
+                Ok(Expr::new(ExprKind::Bool(token == Some(Token::True)), start))
+            }
+            Some(Token::LParen) => {
+                self.consume_no_clone();
+                let mut expr = self.parse_expr()?;
+                self.expect_closing(Token::RParen, ")")?;
+                expr.span = start.to(self.prev_span());
+                Ok(expr)
+            }
+            Some(Token::LBracket) => self.parse_list(),
+            Some(Token::LBrace) => self.parse_map(),
             Some(Token::Ident(name)) => {
                 self.consume();
-                // 检查是否是函数调用
-                if let Some(next_token) = self.peek() {
-                    match next_token {
-                        Token::Ident(_) => {
-                            // 特殊检查：如果标识符后面是赋值操作符，则不是函数参数
-                            // 例如: `b = mo` 后面是 `c = 5`，不应将 `c` 作为 `mo` 的参数
-                            if self.pos + 1 < self.tokens.len() {
-                                if let Some(Token::Assign) = self.tokens.get(self.pos + 1) {
-                                    // 下一个标识符后面是 `=`，所以它不是参数，而是新语句
-                                    return Ok(Expr::Ident(name));
-                                }
-                            }
-                            // 只有当标识符是已定义的函数时，才视为函数调用
-                            if self.is_function(&name) {
-                                // 这是一个带参数的函数调用
-                                let mut args = Vec::new();
-                                let arg = self.parse_expr()?;
-                                args.push(arg);
-                                // 检查是否有更多参数
-                                while let Some(Token::Comma) = self.peek() {
-                                    self.consume();
-                                    let arg = self.parse_expr()?;
-                                    args.push(arg);
-                                }
-                                Ok(Expr::Call(name, args))
-                            } else {
-                                // 这只是一个普通的标识符
-                                Ok(Expr::Ident(name))
-                            }
-                        }
-                        Token::Number(_) | Token::Minus => {
-                            // 只有当标识符是已定义的函数时，才视为函数调用
-                            if self.is_function(&name) {
-                                // 这是一个带参数的函数调用
-                                let mut args = Vec::new();
-                                let arg = self.parse_expr()?;
-                                args.push(arg);
-                                // 检查是否有更多参数
-                                while let Some(Token::Comma) = self.peek() {
-                                    self.consume();
-                                    let arg = self.parse_expr()?;
-                                    args.push(arg);
-                                }
-                                Ok(Expr::Call(name, args))
-                            } else {
-                                // 这只是一个普通的标识符
-                                Ok(Expr::Ident(name))
-                            }
-                        }
-                        _ => {
-                            // 这只是一个普通的标识符
-                            Ok(Expr::Ident(name))
-                        }
-                    }
-                } else {
-                    // 这只是一个普通的标识符
-                    Ok(Expr::Ident(name))
+                if self.at_paren_call(start) {
+                    return self.parse_paren_call(name, start);
+                }
+                // 函数名后同一行能开始表达式的token是参数，参数列表在行尾结束
+                if !self.is_function(&name) || !self.peek().is_some_and(starts_expr) {
+                    return Ok(Expr::new(ExprKind::Ident(name), start));
                 }
+                let mut args = vec![self.parse_expr()?];
+                while let Some(Token::Comma) = self.peek() {
+                    self.consume_no_clone();
+                    args.push(self.parse_expr()?);
+                }
+                Ok(Expr::new(ExprKind::Call(name, args), start.to(self.prev_span())))
             }
-            _ => Err(format!("Expected primary expression, got {:?}", token)),
+            _ => Err(self.error(format!("Expected primary expression, got {}", describe(token.as_ref())))),
         }
     }
 
-    fn parse_call(&mut self, name: String) -> Result<Expr, String> {
+    // 紧跟在标识符后、中间没有空格的 `(` 开始括号形式的调用；
+    // `f (a + b) * 2` 仍是以 `(a + b) * 2` 为参数的普通调用
+    fn at_paren_call(&self, name_span: Span) -> bool {
+        self.peek() == Some(&Token::LParen) && self.peek_span().start == name_span.end
+    }
+
+    // 括号形式的调用 `f(x, y)`，可以没有参数，允许末尾多一个逗号
+    fn parse_paren_call(&mut self, name: String, start: Span) -> Result<Expr, Diagnostic> {
+        if !self.is_function(&name) {
+            return Err(Diagnostic::new(Stage::Parser, ErrorKind::UndefinedFunction, format!("Cannot call `{}`: it is not a function", name)).at(start));
+        }
+        self.consume_no_clone();
         let mut args = Vec::new();
-        // 尝试解析参数表达式
-        let arg = self.parse_expr()?;
-        args.push(arg);
-        Ok(Expr::Call(name, args))
+        while self.peek() != Some(&Token::RParen) {
+            args.push(self.parse_expr()?);
+            if let Some(Token::Comma) = self.peek() {
+                self.consume_no_clone();
+            } else {
+                break;
+            }
+        }
+        self.expect_closing(Token::RParen, ")")?;
+        Ok(Expr::new(ExprKind::Call(name, args), start.to(self.prev_span())))
     }
 
-    fn parse_stmt(&mut self) -> Result<Stmt, String> {
+    fn parse_stmt(&mut self) -> Result<Stmt, Diagnostic> {
         // 使用函数指针映射进行快速token查找
-        type StmtParser = fn(&mut Parser) -> Result<Stmt, String>;
+        type StmtParser = fn(&mut Parser) -> Result<Stmt, Diagnostic>;
         
         // 静态映射表，只初始化一次
         static STMT_PARSERS: std::sync::OnceLock<std::collections::HashMap<Token, StmtParser>> = std::sync::OnceLock::new();
@@ -246,13 +421,15 @@ impl Parser {
         let map = STMT_PARSERS.get_or_init(|| {
             let mut map = std::collections::HashMap::new();
             map.insert(Token::Def, Parser::parse_func_def as StmtParser);
+            map.insert(Token::At, Parser::parse_annotated_def as StmtParser);
             map.insert(Token::If, Parser::parse_if_stmt as StmtParser);
             map.insert(Token::While, Parser::parse_while_stmt as StmtParser);
+            map.insert(Token::For, Parser::parse_for_stmt as StmtParser);
+            map.insert(Token::Break, Parser::parse_loop_control as StmtParser);
+            map.insert(Token::Continue, Parser::parse_loop_control as StmtParser);
+            map.insert(Token::Global, Parser::parse_global_stmt as StmtParser);
             map.insert(Token::Return, Parser::parse_return_stmt as StmtParser);
             map.insert(Token::Out, Parser::parse_out_stmt as StmtParser);
-            map.insert(Token::Ident("dummy".to_string()), Parser::parse_ident_stmt as StmtParser);
-            map.insert(Token::Minus, Parser::parse_minus_expr as StmtParser);
-            map.insert(Token::Number(crate::types::IntegerValue::I8(0)), Parser::parse_number_expr as StmtParser);
             map
         });
         
@@ -265,333 +442,441 @@ impl Parser {
                         // 处理标识符特殊情况
                         Self::parse_ident_stmt(self)
                     }
-                    Token::Number(_) => {
-                        // 处理数字特殊情况
-                        Self::parse_number_expr(self)
-                    }
-                    Token::Minus => {
-                        // 处理减号特殊情况
-                        Self::parse_minus_expr(self)
+                    token if starts_expr(token) => {
+                        // 处理以字面量、一元运算符或括号开头的表达式语句
+                        Self::parse_expr_stmt(self)
                     }
                     _ => {
                         // 使用映射表查找解析函数
                         match token {
-                            Token::Def | Token::If | Token::While | Token::Return | Token::Out => {
-                                if let Some(parser) = map.get(&token) {
+                            Token::Def | Token::At | Token::If | Token::While | Token::For | Token::Break | Token::Continue
+                            | Token::Global | Token::Return | Token::Out => {
+                                if let Some(parser) = map.get(token) {
                                     parser(self)
                                 } else {
-                                    Err(format!("Expected statement, got {:?}", current_token))
+                                    Err(self.error(format!("Expected statement, got {}", describe(current_token.as_ref()))))
                                 }
                             }
                             _ => {
-                                Err(format!("Expected statement, got {:?}", current_token))
+                                Err(self.error(format!("Expected statement, got {}", describe(current_token.as_ref()))))
                             }
                         }
                     }
                 }
             }
-            None => Err("Unexpected end of input".to_string()),
+            None => Err(self.error("Unexpected end of input")),
+        }
+    }
+    
+    // 解析语句并在出错时记录错误、同步到下一条语句的开头
+    fn parse_stmt_recovering(&mut self, statements: &mut Vec<Stmt>) {
+        let start_pos = self.pos;
+        let start_line = self.peek_span().line;
+        match self.parse_stmt() {
+            Ok(stmt) => {
+                statements.push(stmt);
+                if let Err(err) = self.expect_line_end("statement") {
+                    self.errors.push(err);
+                    self.synchronize(self.peek_span().line);
+                }
+            }
+            Err(err) => {
+                self.errors.push(err);
+                // 保证至少前进一个token，避免死循环
+                if self.pos == start_pos {
+                    self.consume_no_clone();
+                }
+                self.synchronize(start_line);
+            }
+        }
+    }
+
+    // 跳过token直到语句边界：`def`、`@`、`if`、`while`、`for`、`break`、`continue`、`global`、`return`、`out`、`elif`、`else`、`end` 或行尾
+    fn synchronize(&mut self, start_line: usize) {
+        while let Some(token) = self.peek() {
+            if self.peek_span().line > start_line {
+                break;
+            }
+            match token {
+                Token::Newline | Token::Def | Token::At | Token::If | Token::While | Token::For | Token::Break | Token::Continue
+                | Token::Global | Token::Return | Token::Out | Token::Elif | Token::Else | Token::End => break,
+                _ => self.consume_no_clone(),
+            }
+        }
+    }
+
+    // 解析直到 `end` 的语句块；缺少 `end` 时报告在块的起始关键字处
+    fn parse_block(&mut self, keyword: &str, start: Span) -> Vec<Stmt> {
+        let body = self.parse_block_body(keyword, start, false);
+        if let Some(Token::End) = self.peek() {
+            self.consume_no_clone();
+        }
+        body
+    }
+
+    // 解析语句块但不消费结束符；`allow_else` 时遇到 `elif`/`else` 也停止
+    fn parse_block_body(&mut self, keyword: &str, start: Span, allow_else: bool) -> Vec<Stmt> {
+        if let Err(err) = self.expect_line_end("block header") {
+            self.errors.push(err);
+            self.synchronize(self.peek_span().line);
+        }
+        let mut body = Vec::new();
+        loop {
+            self.skip_newlines();
+            match self.peek() {
+                Some(Token::End) => break,
+                Some(Token::Elif) | Some(Token::Else) if allow_else => break,
+                Some(_) => self.parse_stmt_recovering(&mut body),
+                None => {
+                    self.errors.push(
+                        Diagnostic::new(Stage::Parser, ErrorKind::UnexpectedEof, format!("Missing `end` for `{}` block", keyword))
+                            .at(start)
+                            .with_note(format!("the `{}` block starting on line {} is never closed", keyword, start.line)),
+                    );
+                    break;
+                }
+            }
         }
+        body
+    }
+
+    // 解析头部表达式；出错时记录错误并跳到行尾，以便仍能解析后面的语句块
+    fn parse_header_expr(&mut self) -> Result<Expr, Diagnostic> {
+        let start_line = self.prev_span().line;
+        self.parse_expr().map_err(|err| {
+            self.synchronize(start_line);
+            err
+        })
     }
     
     // 解析函数定义
-    fn parse_func_def(&mut self) -> Result<Stmt, String> {
+    fn parse_func_def(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
         self.consume_no_clone();
-        if let Some(Token::Ident(name)) = self.peek() {
-            let func_name = name.clone();
+        let func_name = match self.peek() {
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                self.consume_no_clone();
+                Some(name)
+            }
+            _ => None,
+        };
+        // 解析参数列表：`def f a, b` 或 `def f(a, b)`
+        let parenthesized = self.peek() == Some(&Token::LParen);
+        if parenthesized {
             self.consume_no_clone();
-            // 解析参数列表
-            let mut params = Vec::new();
-            while let Some(token) = self.peek() {
-                match token {
-                    Token::Ident(param) => {
-                        params.push(param.clone());
+        }
+        let mut params = Vec::new();
+        let mut param_spans = Vec::new();
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Ident(param) => {
+                    params.push(param.clone());
+                    param_spans.push(self.peek_span());
+                    self.consume_no_clone();
+                    // 检查是否有逗号
+                    if let Some(Token::Comma) = self.peek() {
                         self.consume_no_clone();
-                        // 检查是否有逗号
-                        if let Some(Token::Comma) = self.peek() {
-                            self.consume_no_clone();
-                        } else {
-                            break;
-                        }
-                    }
-                    _ => {
+                    } else {
                         break;
                     }
                 }
-            }
-            let mut body = Vec::new();
-            while self.pos < self.tokens.len() {
-                if let Some(Token::End) = self.peek() {
-                    self.consume_no_clone();
+                _ => {
                     break;
                 }
-                match self.parse_stmt() {
-                    Ok(stmt) => body.push(stmt),
-                    Err(err) => {
-                        println!("Warning: {}", err);
-                        if self.pos < self.tokens.len() {
-                            self.pos += 1;
-                        }
-                    }
-                }
             }
-            Ok(Stmt::FuncDef(func_name, params, body))
-        } else {
-            Err("Expected function name".to_string())
+        }
+        if parenthesized {
+            if let Err(err) = self.expect_closing(Token::RParen, ")") {
+                // 仍然解析函数体，保持 `end` 的配对
+                self.synchronize(start.line);
+                self.parse_block("def", start);
+                return Err(err);
+            }
+        }
+        match func_name {
+            Some(func_name) => {
+                let body = self.parse_block("def", start);
+                Ok(Stmt::new(StmtKind::FuncDef(func_name, params, param_spans, body, Vec::new()), start.to(self.prev_span())))
+            }
+            None => {
+                // 仍然解析函数体，保持 `end` 的配对
+                let err = self.error("Expected function name");
+                self.synchronize(start.line);
+                self.parse_block("def", start);
+                Err(err)
+            }
         }
     }
     
+    // 解析带注解的函数定义，例如 `@memo def f n ... end`
+    fn parse_annotated_def(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
+        let mut annotations = Vec::new();
+        while let Some(Token::At) = self.peek() {
+            self.consume_no_clone();
+            match self.peek() {
+                Some(Token::Ident(name)) => {
+                    annotations.push(name.clone());
+                    self.consume_no_clone();
+                    // 注解单独占一行
+                    self.skip_newlines();
+                }
+                other => return Err(self.error(format!("Expected annotation name after `@`, got {}", describe(other)))),
+            }
+        }
+        if self.peek() != Some(&Token::Def) {
+            return Err(self.error(format!("Expected `def` after annotation, got {}", describe(self.peek()))));
+        }
+        let mut stmt = self.parse_func_def()?;
+        if let StmtKind::FuncDef(_, _, _, _, attrs) = &mut stmt.kind {
+            *attrs = annotations;
+        }
+        stmt.span = start.to(stmt.span);
+        Ok(stmt)
+    }
+
     // 解析if语句
-    fn parse_if_stmt(&mut self) -> Result<Stmt, String> {
+    fn parse_if_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
         self.consume_no_clone();
-        let cond = self.parse_expr()?;
-        let mut body = Vec::new();
-        while self.pos < self.tokens.len() {
-            if let Some(Token::End) = self.peek() {
+        self.parse_if_branch(start, start)
+    }
+
+    // 解析 `if`/`elif` 分支；整条链共用一个 `end`，缺少时报告在最初的 `if` 处
+    fn parse_if_branch(&mut self, if_start: Span, start: Span) -> Result<Stmt, Diagnostic> {
+        let cond = self.parse_header_expr();
+        let body = self.parse_block_body("if", if_start, true);
+        let else_body = match self.peek() {
+            Some(Token::Elif) => {
+                let elif_start = self.peek_span();
                 self.consume_no_clone();
-                break;
-            }
-            match self.parse_stmt() {
-                Ok(stmt) => body.push(stmt),
-                Err(err) => {
-                    println!("Warning: {}", err);
-                    if self.pos < self.tokens.len() {
-                        self.pos += 1;
+                match self.parse_if_branch(if_start, elif_start) {
+                    Ok(stmt) => vec![stmt],
+                    Err(err) => {
+                        self.errors.push(err);
+                        Vec::new()
                     }
                 }
             }
-        }
-        Ok(Stmt::If(cond, body))
+            Some(Token::Else) => {
+                self.consume_no_clone();
+                self.parse_block("if", if_start)
+            }
+            Some(Token::End) => {
+                self.consume_no_clone();
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+        Ok(Stmt::new(StmtKind::If(cond?, body, else_body), start.to(self.prev_span())))
     }
     
     // 解析while语句
-    fn parse_while_stmt(&mut self) -> Result<Stmt, String> {
+    fn parse_while_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
         self.consume_no_clone();
-        let cond = self.parse_expr()?;
-        let mut body = Vec::new();
-        while self.pos < self.tokens.len() {
-            if let Some(Token::End) = self.peek() {
-                self.consume_no_clone();
-                break;
+        let cond = self.parse_header_expr();
+        let body = self.parse_block("while", start);
+        Ok(Stmt::new(StmtKind::While(cond?, body), start.to(self.prev_span())))
+    }
+
+    // 解析for语句：`for i in a..b` 或 `for x in items`
+    fn parse_for_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
+        self.consume_no_clone();
+        let header = self.parse_for_header().inspect_err(|_| self.synchronize(start.line));
+        let body = self.parse_block("for", start);
+        let (var, target) = header?;
+        Ok(Stmt::new(StmtKind::For(var, target, body), start.to(self.prev_span())))
+    }
+
+    // 解析 `for` 之后的循环变量和遍历对象
+    fn parse_for_header(&mut self) -> Result<(String, ForTarget), Diagnostic> {
+        let var = match self.peek() {
+            Some(Token::Ident(name)) => name.clone(),
+            other => return Err(self.error(format!("Expected loop variable after `for`, got {}", describe(other)))),
+        };
+        self.consume_no_clone();
+        if self.peek() != Some(&Token::In) {
+            return Err(self.error(format!("Expected `in` after loop variable, got {}", describe(self.peek()))));
+        }
+        self.consume_no_clone();
+        let first = self.parse_expr()?;
+        if self.peek() != Some(&Token::DotDot) {
+            return Ok((var, ForTarget::Each(first)));
+        }
+        self.consume_no_clone();
+        let end = self.parse_expr()?;
+        Ok((var, ForTarget::Range(first, end)))
+    }
+    
+    // 解析break或continue语句；是否位于循环中由语义分析检查
+    fn parse_loop_control(&mut self) -> Result<Stmt, Diagnostic> {
+        let span = self.peek_span();
+        let kind = match self.consume() {
+            Some(Token::Break) => StmtKind::Break,
+            _ => StmtKind::Continue,
+        };
+        Ok(Stmt::new(kind, span))
+    }
+
+    // 解析 `global a, b`；是否位于函数中由语义分析检查
+    fn parse_global_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
+        self.consume_no_clone();
+        let mut names = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Ident(name)) => names.push(name.clone()),
+                other => return Err(self.error(format!("Expected variable name after `global`, got {}", describe(other)))),
             }
-            match self.parse_stmt() {
-                Ok(stmt) => body.push(stmt),
-                Err(err) => {
-                    println!("Warning: {}", err);
-                    if self.pos < self.tokens.len() {
-                        self.pos += 1;
-                    }
-                }
+            self.consume_no_clone();
+            if self.peek() != Some(&Token::Comma) {
+                break;
             }
+            self.consume_no_clone();
         }
-        Ok(Stmt::While(cond, body))
+        Ok(Stmt::new(StmtKind::Global(names), start.to(self.prev_span())))
     }
-    
+
     // 解析return语句
-    fn parse_return_stmt(&mut self) -> Result<Stmt, String> {
+    fn parse_return_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
         self.consume_no_clone();
         let expr = self.parse_expr()?;
-        Ok(Stmt::Return(expr))
+        let span = start.to(expr.span);
+        Ok(Stmt::new(StmtKind::Return(expr), span))
     }
     
     // 解析out语句
-    fn parse_out_stmt(&mut self) -> Result<Stmt, String> {
+    fn parse_out_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
         self.consume_no_clone();
         let expr = self.parse_expr()?;
-        Ok(Stmt::Out(expr))
+        let span = start.to(expr.span);
+        Ok(Stmt::new(StmtKind::Out(expr), span))
     }
-    
+
+    // 将 `x op= rhs` 展开为 `x = x op rhs`
+    fn compound_assign(&mut self, ident: String, start: Span, op: BinOpType) -> Result<Stmt, Diagnostic> {
+        self.consume();
+        let right = self.parse_expr()?;
+        let span = start.to(right.span);
+        let expr = Expr::new(
+            ExprKind::BinOp(
+                Box::new(Expr::new(ExprKind::Ident(ident.clone()), start)),
+                op,
+                Box::new(right)
+            ),
+            span,
+        );
+        Ok(Stmt::new(StmtKind::Assign(ident, expr), span))
+    }
+
     // 解析标识符语句
-    fn parse_ident_stmt(&mut self) -> Result<Stmt, String> {
+    fn parse_ident_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let start = self.peek_span();
         if let Some(Token::Ident(name)) = self.peek() {
             let ident = name.clone();
+            let start_pos = self.pos;
             self.consume();
             
             // 检查是否是赋值或复合赋值
             match self.peek() {
+                Some(Token::LBracket) if !self.is_function(&ident) => self.parse_index_stmt(start_pos),
                 Some(Token::Assign) => {
                     self.consume();
                     let expr = self.parse_expr()?;
-                    Ok(Stmt::Assign(ident, expr))
-                }
-                Some(Token::PlusAssign) => {
-                    self.consume();
-                    let right = self.parse_expr()?;
-                    let expr = Expr::BinOp(
-                        Box::new(Expr::Ident(ident.clone())),
-                        BinOpType::Plus,
-                        Box::new(right)
-                    );
-                    Ok(Stmt::Assign(ident, expr))
-                }
-                Some(Token::MinusAssign) => {
-                    self.consume();
-                    let right = self.parse_expr()?;
-                    let expr = Expr::BinOp(
-                        Box::new(Expr::Ident(ident.clone())),
-                        BinOpType::Minus,
-                        Box::new(right)
-                    );
-                    Ok(Stmt::Assign(ident, expr))
-                }
-                Some(Token::MulAssign) => {
-                    self.consume();
-                    let right = self.parse_expr()?;
-                    let expr = Expr::BinOp(
-                        Box::new(Expr::Ident(ident.clone())),
-                        BinOpType::Mul,
-                        Box::new(right)
-                    );
-                    Ok(Stmt::Assign(ident, expr))
-                }
-                Some(Token::ModAssign) => {
-                    self.consume();
-                    let right = self.parse_expr()?;
-                    let expr = Expr::BinOp(
-                        Box::new(Expr::Ident(ident.clone())),
-                        BinOpType::Mod,
-                        Box::new(right)
-                    );
-                    Ok(Stmt::Assign(ident, expr))
-                }
-                _ => {
-                    // 检查是否是函数调用
-                    if let Some(token) = self.peek() {
-                        match token {
-                            Token::Ident(_) | Token::Number(_) | Token::Minus => {
-                                // 只有当标识符是已定义的函数时，才视为函数调用
-                                if self.is_function(&ident) {
-                                    // 这是一个带参数的函数调用
-                                    let mut args = Vec::new();
-                                    let arg = self.parse_expr()?;
-                                    args.push(arg);
-                                    // 检查是否有更多参数
-                                    while let Some(Token::Comma) = self.peek() {
-                                        self.consume();
-                                        let arg = self.parse_expr()?;
-                                        args.push(arg);
-                                    }
-                                    let call_expr = Expr::Call(ident, args);
-                                    Ok(Stmt::Out(call_expr))
-                                } else {
-                                    // 这只是一个普通的标识符
-                                    let mut left = Expr::Ident(ident);
-                                    // 检查是否有二元操作符
-                                    while let Some(token) = self.peek() {
-                                        match token {
-                                            Token::Plus | Token::Minus | Token::Le | Token::Lt => {
-                                                let op_type = match token {
-                                                    Token::Plus => BinOpType::Plus,
-                                                    Token::Minus => BinOpType::Minus,
-                                                    Token::Le => BinOpType::Le,
-                                                    Token::Lt => BinOpType::Lt,
-                                                    _ => unreachable!(),
-                                                };
-                                                self.consume();
-                                                let right = self.parse_primary()?;
-                                                left = Expr::BinOp(Box::new(left), op_type, Box::new(right));
-                                            }
-                                            _ => break,
-                                        }
-                                    }
-                                    Ok(Stmt::Out(left))
-                                }
-                            }
-                            _ => {
-                                // 检查是否是无参数函数调用
-                                // 这里需要特殊处理，因为无参数函数调用在语法上与普通标识符相同
-                                // 我们暂时将其视为普通标识符，在语义分析阶段再处理
-                                let mut left = Expr::Ident(ident);
-                                // 检查是否有二元操作符
-                                while let Some(token) = self.peek() {
-                                    match token {
-                                        Token::Plus | Token::Minus | Token::Le | Token::Lt => {
-                                            let op_type = match token {
-                                                Token::Plus => BinOpType::Plus,
-                                                Token::Minus => BinOpType::Minus,
-                                                Token::Le => BinOpType::Le,
-                                                Token::Lt => BinOpType::Lt,
-                                                _ => unreachable!(),
-                                            };
-                                            self.consume();
-                                            let right = self.parse_primary()?;
-                                            left = Expr::BinOp(Box::new(left), op_type, Box::new(right));
-                                        }
-                                        _ => break,
-                                    }
-                                }
-                                Ok(Stmt::Out(left))
-                            }
-                        }
-                    } else {
-                        // 只有一个标识符，作为表达式语句
-                        Ok(Stmt::Out(Expr::Ident(ident)))
-                    }
+                    let span = start.to(expr.span);
+                    Ok(Stmt::new(StmtKind::Assign(ident, expr), span))
                 }
+                Some(Token::PlusAssign) => self.compound_assign(ident, start, BinOpType::Plus),
+                Some(Token::MinusAssign) => self.compound_assign(ident, start, BinOpType::Minus),
+                Some(Token::MulAssign) => self.compound_assign(ident, start, BinOpType::Mul),
+                Some(Token::ModAssign) => self.compound_assign(ident, start, BinOpType::Mod),
+                Some(Token::DivAssign) => self.compound_assign(ident, start, BinOpType::Div),
+                _ => self.parse_ident_expr_stmt(start_pos),
             }
         } else {
-            Err("Expected identifier".to_string())
+            Err(self.error("Expected identifier"))
         }
     }
     
-    // 解析以减号开头的表达式
-    fn parse_minus_expr(&mut self) -> Result<Stmt, String> {
-        self.consume();
-        let right = self.parse_primary()?;
-        let expr = Expr::BinOp(Box::new(Expr::Number(crate::types::IntegerValue::I8(0))), BinOpType::Minus, Box::new(right));
-        Ok(Stmt::Out(expr))
+    // 以下标访问开头的语句：`a[i] = v` 或 `a[i] += v`，其余情况按表达式语句输出
+    fn parse_index_stmt(&mut self, start_pos: usize) -> Result<Stmt, Diagnostic> {
+        self.pos = start_pos;
+        let target = self.parse_primary()?;
+        let op = match self.peek() {
+            Some(Token::Assign) => None,
+            Some(Token::PlusAssign) => Some(BinOpType::Plus),
+            Some(Token::MinusAssign) => Some(BinOpType::Minus),
+            Some(Token::MulAssign) => Some(BinOpType::Mul),
+            Some(Token::ModAssign) => Some(BinOpType::Mod),
+            Some(Token::DivAssign) => Some(BinOpType::Div),
+            _ => {
+                self.pos = start_pos;
+                return self.parse_expr_stmt();
+            }
+        };
+        self.consume_no_clone();
+        let right = self.parse_expr()?;
+        let span = target.span.to(right.span);
+        // 复合赋值展开为 `a[i] = a[i] op rhs`
+        let value = match op {
+            Some(op) => Expr::new(ExprKind::BinOp(Box::new(target.clone()), op, Box::new(right)), span),
+            None => right,
+        };
+        let ExprKind::Index(container, index) = target.kind else {
+            unreachable!("identifier followed by `[` parses as an index expression");
+        };
+        Ok(Stmt::new(StmtKind::IndexAssign(*container, *index, value), span))
     }
-    
-    // 解析以数字开头的表达式
-    fn parse_number_expr(&mut self) -> Result<Stmt, String> {
+
+    // 以标识符开头的表达式语句，结果直接输出；单独一行的内置函数调用（如 `append a, 1`）不输出结果
+    fn parse_ident_expr_stmt(&mut self, start_pos: usize) -> Result<Stmt, Diagnostic> {
+        self.pos = start_pos;
         let expr = self.parse_expr()?;
-        Ok(Stmt::Out(expr))
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Call(name, _) if !self.functions.contains(name) => Ok(Stmt::new(StmtKind::Expr(expr), span)),
+            _ => Ok(Stmt::new(StmtKind::Out(expr), span)),
+        }
     }
 
-    fn parse_program(&mut self) -> Result<Program, String> {
+    // 解析表达式语句，结果直接输出
+    fn parse_expr_stmt(&mut self) -> Result<Stmt, Diagnostic> {
+        let expr = self.parse_expr()?;
+        let span = expr.span;
+        Ok(Stmt::new(StmtKind::Out(expr), span))
+    }
+
+    fn parse_program(&mut self) -> Result<Program, Vec<Diagnostic>> {
         let mut statements = Vec::new();
         let original_pos = self.pos;
 
         self.pos = 0;
-        while self.pos < self.tokens.len() {
-            let current_token = self.peek();
-            match current_token {
-                Some(Token::Def) => {
-                    // 解析函数定义并添加到statements中
-                    if let Ok(func_def) = self.parse_func_def() {
-                        statements.push(func_def);
-                    } else {
-                        // 解析失败，跳过当前标记
-                        self.pos += 1;
-                    }
-                }
-                Some(_) => {
-                    // 解析非函数定义的语句
-                    match self.parse_stmt() {
-                        Ok(stmt) => {
-                            statements.push(stmt);
-                        }
-                        Err(err) => {
-                            println!("Warning: {}", err);
-                            if self.pos < self.tokens.len() {
-                                self.pos += 1;
-                            }
-                        }
-                    }
-                }
-                None => {
-                    break;
-                }
+        loop {
+            self.skip_newlines();
+            if self.pos >= self.tokens.len() {
+                break;
             }
+            self.parse_stmt_recovering(&mut statements);
         }
 
         self.pos = original_pos;
+        if !self.errors.is_empty() {
+            // 头部表达式的错误在语句块解析完之后才返回，这里按源码位置排序
+            let mut errors = std::mem::take(&mut self.errors);
+            errors.sort_by_key(|err| err.span.map(|span| span.start));
+            return Err(errors);
+        }
         Ok(Program {
             statements,
         })
     }
 
-    fn parse_function_on_demand(&mut self, name: &str) -> Result<Stmt, String> {
+    fn parse_function_on_demand(&mut self, name: &str) -> Result<Stmt, Diagnostic> {
         if let Some(&location) = self.function_locations.get(name) {
             let original_pos = self.pos;
             self.pos = location;
@@ -602,12 +887,378 @@ impl Parser {
             self.pos = original_pos;
             result
         } else {
-            Err(format!("Function {} not found", name))
+            Err(Diagnostic::new(Stage::Parser, ErrorKind::UndefinedFunction, format!("Function {} not found", name)))
         }
     }
 }
 
-pub fn parse(tokens: Vec<Token>) -> Result<Program, String> {
+pub fn parse(tokens: Vec<SpannedToken>) -> Result<Program, Vec<Diagnostic>> {
     let mut parser = Parser::new(tokens);
     parser.parse_program()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+
+    fn parse_source(source: &str) -> Result<Program, Vec<Diagnostic>> {
+        parse(tokenize(source).unwrap())
+    }
+
+    #[test]
+    fn test_reports_every_syntax_error() {
+        let errors = parse_source("x = \nout 1\ny = *\nout 2\n").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        // 表达式在行尾结束，缺少的右操作数报告在第一行末尾
+        assert_eq!(errors[0].span.unwrap().line, 1);
+        assert_eq!(errors[0].message, "Expected primary expression, got end of line");
+        assert_eq!(errors[1].span.unwrap().line, 3);
+        assert!(errors.iter().all(|err| err.kind == ErrorKind::UnexpectedToken));
+    }
+
+    #[test]
+    fn test_missing_end_points_at_block_start() {
+        let errors = parse_source("def f a\n    if a < 1\n        return 1\n    return a\nend\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ErrorKind::UnexpectedEof);
+        assert_eq!(errors[0].span.unwrap().line, 1);
+    }
+
+    #[test]
+    fn test_broken_def_does_not_leak_body() {
+        let errors = parse_source("def\n    out 1\nend\nout 2\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Expected function name");
+    }
+
+    #[test]
+    fn test_elif_chain_nests_in_else_branch() {
+        let program = parse_source("if x < 1\n    out 1\nelif x < 2\n    out 2\nelse\n    out 3\nend\nout 4\n").unwrap();
+        assert_eq!(program.statements.len(), 2);
+        match &program.statements[0].kind {
+            StmtKind::If(_, body, else_body) => {
+                assert_eq!(body.len(), 1);
+                assert_eq!(else_body.len(), 1);
+                match &else_body[0].kind {
+                    StmtKind::If(_, elif_body, final_else) => {
+                        assert_eq!(elif_body.len(), 1);
+                        assert_eq!(final_else.len(), 1);
+                    }
+                    other => panic!("expected nested if, got {:?}", other),
+                }
+            }
+            other => panic!("expected if, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unary_and_logical_precedence() {
+        let program = parse_source("x = -a * b or not c == 1 and d != 2\n").unwrap();
+        let StmtKind::Assign(_, expr) = &program.statements[0].kind else {
+            panic!("expected assignment");
+        };
+        let ExprKind::BinOp(left, BinOpType::Or, right) = &expr.kind else {
+            panic!("expected `or` at the top, got {:?}", expr.kind);
+        };
+        assert!(matches!(&left.kind, ExprKind::BinOp(neg, BinOpType::Mul, _) if matches!(neg.kind, ExprKind::Unary(UnaryOp::Neg, _))));
+        let ExprKind::BinOp(not, BinOpType::And, ne) = &right.kind else {
+            panic!("expected `and` under `or`, got {:?}", right.kind);
+        };
+        assert!(matches!(&not.kind, ExprKind::Unary(UnaryOp::Not, eq) if matches!(eq.kind, ExprKind::BinOp(_, BinOpType::Eq, _))));
+        assert!(matches!(ne.kind, ExprKind::BinOp(_, BinOpType::NotEq, _)));
+    }
+
+    #[test]
+    fn test_index_assignment_and_builtin_statements() {
+        let program = parse_source("m = [[1, 2], [3]]\nm[1][0] += 4\nappend m, [5,]\nout m[0][1]\n").unwrap();
+        let StmtKind::IndexAssign(container, _, value) = &program.statements[1].kind else {
+            panic!("expected index assignment, got {:?}", program.statements[1].kind);
+        };
+        assert!(matches!(container.kind, ExprKind::Index(_, _)));
+        assert!(matches!(&value.kind, ExprKind::BinOp(read, BinOpType::Plus, _) if matches!(read.kind, ExprKind::Index(_, _))));
+        // 单独一行的内置函数调用不输出结果
+        assert!(matches!(&program.statements[2].kind, StmtKind::Expr(call) if matches!(&call.kind, ExprKind::Call(name, args) if name == "append" && args.len() == 2)));
+        assert!(matches!(program.statements[3].kind, StmtKind::Out(_)));
+
+        let errors = parse_source("a = [1, 2\nout a\n").unwrap_err();
+        assert_eq!(errors[0].message, "Expected `]`, got Out");
+    }
+
+    #[test]
+    fn test_stray_else_is_reported() {
+        let errors = parse_source("out 1\nelse\nout 2\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_for_headers() {
+        let program = parse_source("for i in 0..n + 1\n    out i\nend\nfor x in [1, 2]\n    out x\nend\n").unwrap();
+        let StmtKind::For(var, ForTarget::Range(start, end), body) = &program.statements[0].kind else {
+            panic!("expected range loop, got {:?}", program.statements[0].kind);
+        };
+        assert_eq!(var, "i");
+        assert!(matches!(start.kind, ExprKind::Number(_)));
+        assert!(matches!(end.kind, ExprKind::BinOp(_, BinOpType::Plus, _)));
+        assert_eq!(body.len(), 1);
+        assert!(matches!(&program.statements[1].kind, StmtKind::For(_, ForTarget::Each(items), _) if matches!(items.kind, ExprKind::List(_))));
+
+        // 头部出错时仍然配对 `end`，后面的语句照常解析
+        let errors = parse_source("for i 0..3\n    out i\nend\nout 1\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Expected `in` after loop variable, got Number(I8(0))");
+    }
+
+    #[test]
+    fn test_parentheses_and_call_forms() {
+        let program = parse_source("def add(a, b)\n    return a + b\nend\nx = (1 + 2) * 3\nout add(add(1, 2), 3)\nout add (1 + 2) * 3, 1\nadd(x, 1)\n").unwrap();
+        let StmtKind::FuncDef(_, params, _, _, _) = &program.statements[0].kind else {
+            panic!("expected function definition");
+        };
+        assert_eq!(params, &["a", "b"]);
+        let StmtKind::Assign(_, expr) = &program.statements[1].kind else {
+            panic!("expected assignment");
+        };
+        assert!(matches!(&expr.kind, ExprKind::BinOp(sum, BinOpType::Mul, _) if matches!(sum.kind, ExprKind::BinOp(_, BinOpType::Plus, _))));
+        // 紧跟函数名的括号是参数列表，隔开的括号只是第一个参数的一部分
+        let StmtKind::Out(call) = &program.statements[2].kind else {
+            panic!("expected out");
+        };
+        assert!(matches!(&call.kind, ExprKind::Call(_, args) if args.len() == 2 && matches!(args[0].kind, ExprKind::Call(_, _))));
+        let StmtKind::Out(call) = &program.statements[3].kind else {
+            panic!("expected out");
+        };
+        assert!(matches!(&call.kind, ExprKind::Call(_, args) if args.len() == 2 && matches!(args[0].kind, ExprKind::BinOp(_, BinOpType::Mul, _))));
+        assert!(matches!(&program.statements[4].kind, StmtKind::Out(call) if matches!(call.kind, ExprKind::Call(_, _))));
+
+        let errors = parse_source("x = 1\nout x(2)\n").unwrap_err();
+        assert_eq!(errors[0].message, "Cannot call `x`: it is not a function");
+        let errors = parse_source("def f(a b)\n    return a\nend\nout (1\n").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Expected `)`, got Ident(\"b\")");
+        assert_eq!(errors[1].span.unwrap().line, 4);
+    }
+
+    #[test]
+    fn test_statements_end_at_newlines() {
+        // 参数列表在行尾结束，下一行的 `y + 1` 不是 `f` 的参数
+        let program = parse_source("def f\n    return 3\nend\nx = f\ny + 1\nout [1,\n    2]\n").unwrap();
+        assert!(matches!(&program.statements[0].kind, StmtKind::FuncDef(_, params, _, body, _) if params.is_empty() && body.len() == 1));
+        assert!(matches!(&program.statements[1].kind, StmtKind::Assign(_, value) if matches!(value.kind, ExprKind::Ident(_))));
+        assert!(matches!(&program.statements[2].kind, StmtKind::Out(sum) if matches!(sum.kind, ExprKind::BinOp(_, BinOpType::Plus, _))));
+        assert!(matches!(&program.statements[3].kind, StmtKind::Out(list) if matches!(&list.kind, ExprKind::List(items) if items.len() == 2)));
+
+        let errors = parse_source("out 1 out 2\nif x > 0 out 3\nend\nout 4\n").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Expected end of line after statement, got Out");
+        assert_eq!(errors[1].message, "Expected end of line after block header, got Out");
+        assert_eq!(errors[1].span.unwrap().line, 2);
+    }
+
+    #[test]
+    fn test_bare_expression_statements_match_out() {
+        // 单独一行的表达式与 `out` 之后的表达式解析结果相同
+        let program = parse_source("x * 2 + 1\nout x * 2 + 1\nx == 1 or ~x\nout x == 1 or ~x\n").unwrap();
+        let rendered: Vec<String> = program.statements.iter().map(|stmt| match &stmt.kind {
+            StmtKind::Out(expr) => render(expr),
+            other => panic!("expected out, got {:?}", other),
+        }).collect();
+        assert_eq!(rendered[0], "(Plus (Mul x 2) 1)");
+        assert_eq!(rendered[0], rendered[1]);
+        assert_eq!(rendered[2], "(Or (Eq x 1) (BitNot x))");
+        assert_eq!(rendered[2], rendered[3]);
+    }
+
+    #[test]
+    fn test_chained_comparisons_are_rejected() {
+        let errors = parse_source("out a < b < c\n").unwrap_err();
+        assert_eq!(errors[0].message, "Comparison operators cannot be chained; combine the comparisons with `and`");
+        assert_eq!(errors[0].span.unwrap().col, 11);
+        // 不同优先级的比较可以组合：`a < b == c` 即 `(a < b) == c`
+        assert!(parse_source("out a < b == c\nout a == b and b != c\n").is_ok());
+    }
+
+    // 参考文法：每个优先级一个产生式，由松到紧
+    //   expr    := level(0)
+    //   level_i := level_{i+1} (op_i level_{i+1})*    不可连用的层最多一个运算符
+    //   unary   := ('-' | '~') unary | postfix
+    //   postfix := atom ('[' expr ']')*
+    //   atom    := ident | '(' expr ')' | '[' expr? ']' | 'not' level(`==`)
+    struct Reference {
+        tokens: Vec<Token>,
+        pos: usize,
+    }
+
+    impl Reference {
+        fn levels() -> Vec<(bool, Vec<(Token, &'static str)>)> {
+            vec![
+                (false, vec![(Token::Or, "Or")]),
+                (false, vec![(Token::And, "And")]),
+                (true, vec![(Token::Eq, "Eq"), (Token::NotEq, "NotEq")]),
+                (true, vec![(Token::Lt, "Lt"), (Token::Le, "Le"), (Token::Gt, "Gt"), (Token::Ge, "Ge")]),
+                (false, vec![(Token::BitOr, "BitOr")]),
+                (false, vec![(Token::BitXor, "BitXor")]),
+                (false, vec![(Token::BitAnd, "BitAnd")]),
+                (false, vec![(Token::Shl, "Shl"), (Token::Shr, "Shr")]),
+                (false, vec![(Token::Plus, "Plus"), (Token::Minus, "Minus")]),
+                (false, vec![(Token::Mul, "Mul"), (Token::Div, "Div"), (Token::Mod, "Mod")]),
+            ]
+        }
+
+        fn parse(tokens: Vec<Token>) -> Option<String> {
+            let mut reference = Reference { tokens, pos: 0 };
+            let expr = reference.level(0)?;
+            (reference.pos == reference.tokens.len()).then_some(expr)
+        }
+
+        fn eat(&mut self, token: &Token) -> bool {
+            let found = self.tokens.get(self.pos) == Some(token);
+            if found {
+                self.pos += 1;
+            }
+            found
+        }
+
+        fn level(&mut self, index: usize) -> Option<String> {
+            let levels = Self::levels();
+            let Some((non_assoc, ops)) = levels.get(index) else {
+                return self.unary();
+            };
+            let mut left = self.level(index + 1)?;
+            let mut count = 0;
+            while let Some((_, name)) = ops.iter().find(|(token, _)| self.tokens.get(self.pos) == Some(token)) {
+                count += 1;
+                if *non_assoc && count > 1 {
+                    return None;
+                }
+                self.pos += 1;
+                let right = self.level(index + 1)?;
+                left = format!("({} {} {})", name, left, right);
+            }
+            Some(left)
+        }
+
+        fn unary(&mut self) -> Option<String> {
+            if self.eat(&Token::Minus) {
+                return Some(format!("(Neg {})", self.unary()?));
+            }
+            if self.eat(&Token::BitNot) {
+                return Some(format!("(BitNot {})", self.unary()?));
+            }
+            let mut expr = self.atom()?;
+            while self.eat(&Token::LBracket) {
+                let index = self.level(0)?;
+                if !self.eat(&Token::RBracket) {
+                    return None;
+                }
+                expr = format!("(Index {} {})", expr, index);
+            }
+            Some(expr)
+        }
+
+        fn atom(&mut self) -> Option<String> {
+            if self.eat(&Token::Not) {
+                return Some(format!("(Not {})", self.level(2)?));
+            }
+            if self.eat(&Token::LParen) {
+                let expr = self.level(0)?;
+                return self.eat(&Token::RParen).then_some(expr);
+            }
+            // 删掉下标前的操作数后剩下列表字面量
+            if self.eat(&Token::LBracket) {
+                if self.eat(&Token::RBracket) {
+                    return Some("(List)".to_string());
+                }
+                let item = self.level(0)?;
+                return self.eat(&Token::RBracket).then(|| format!("(List {})", item));
+            }
+            match self.tokens.get(self.pos) {
+                Some(Token::Ident(name)) => {
+                    self.pos += 1;
+                    Some(name.clone())
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn render(expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Ident(name) => name.clone(),
+            ExprKind::Number(n) => n.to_string(),
+            ExprKind::BinOp(left, op, right) => format!("({:?} {} {})", op, render(left), render(right)),
+            ExprKind::Unary(op, operand) => format!("({:?} {})", op, render(operand)),
+            ExprKind::Index(container, index) => format!("(Index {} {})", render(container), render(index)),
+            ExprKind::List(items) => format!("(List{})", items.iter().map(|item| format!(" {}", render(item))).collect::<String>()),
+            other => panic!("unexpected expression {:?}", other),
+        }
+    }
+
+    // 固定种子的 xorshift，保证失败可以复现
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    fn random_expr(rng: &mut Rng, depth: usize, out: &mut Vec<&'static str>) {
+        const OPERATORS: [&str; 18] = ["or", "and", "==", "!=", "<", "<=", ">", ">=", "|", "^", "&", "<<", ">>", "+", "-", "*", "/", "%"];
+        const PREFIXES: [&str; 3] = ["-", "~", "not"];
+        const NAMES: [&str; 5] = ["a", "b", "c", "d", "e"];
+        let operands = 1 + rng.below(if depth > 0 { 4 } else { 2 });
+        for i in 0..operands {
+            if i > 0 {
+                out.push(OPERATORS[rng.below(OPERATORS.len())]);
+            }
+            while rng.below(4) == 0 {
+                out.push(PREFIXES[rng.below(PREFIXES.len())]);
+            }
+            if depth > 0 && rng.below(4) == 0 {
+                out.push("(");
+                random_expr(rng, depth - 1, out);
+                out.push(")");
+            } else {
+                out.push(NAMES[rng.below(NAMES.len())]);
+            }
+            if depth > 0 && rng.below(6) == 0 {
+                out.push("[");
+                random_expr(rng, depth - 1, out);
+                out.push("]");
+            }
+        }
+    }
+
+    #[test]
+    fn test_expression_parser_matches_reference_grammar() {
+        let mut rng = Rng(0x5eed_1234_abcd_0001);
+        let mut accepted = 0;
+        for _ in 0..3000 {
+            let mut words = Vec::new();
+            random_expr(&mut rng, 3, &mut words);
+            // 偶尔删掉一个token，检查两边对错误输入的判断也一致
+            if rng.below(5) == 0 {
+                words.remove(rng.below(words.len()));
+            }
+            let source = words.join(" ");
+            let tokens: Vec<Token> = tokenize(&source).unwrap().into_iter().map(|t| t.token).collect();
+            let expected = Reference::parse(tokens);
+            let actual = match parse_source(&format!("out {}\n", source)) {
+                Ok(program) => match &program.statements[..] {
+                    [Stmt { kind: StmtKind::Out(expr), .. }] => Some(render(expr)),
+                    _ => None,
+                },
+                Err(_) => None,
+            };
+            assert_eq!(actual, expected, "source: {}", source);
+            accepted += expected.is_some() as usize;
+        }
+        // 大部分随机输入应当是合法的表达式
+        assert!(accepted > 1500, "only {} inputs were accepted", accepted);
+    }
+}
@@ -1,21 +1,170 @@
-use crate::ast::{Expr, Stmt, Program};
+use crate::ast::{Expr, ExprKind, ForTarget, Stmt, StmtKind, Program, BinOpType, UnaryOp};
+use crate::builtins;
+use crate::diagnostic::{Diagnostic, ErrorKind, Span, Stage};
+use crate::types::{IntegerType, StaticType};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use rayon::prelude::*;
 
+// 语义检查选项
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SemanticConfig {
+    // 严格模式：条件和逻辑运算的操作数必须是布尔值，`if 5` 是语义错误
+    pub strict_conditions: bool,
+}
+
+// 函数的作用域：形参和函数中赋值过的名字是局部变量，其余名字指向模块级变量。
+// `global` 声明的名字在整个函数中都指向模块级变量，对它们的赋值也写入模块级变量
+#[derive(Debug, Clone, Default)]
+pub struct FunctionScope {
+    pub locals: HashSet<String>,
+    pub globals: HashSet<String>,
+}
+
+impl FunctionScope {
+    pub fn new(params: &[String], body: &[Stmt]) -> Self {
+        let mut assigned = HashSet::new();
+        let mut globals = HashSet::new();
+        Self::collect(body, &mut assigned, &mut globals);
+        // 形参总是局部变量；同时声明为 global 的形参由语义分析报错
+        let mut locals: HashSet<String> = params.iter().cloned().collect();
+        locals.extend(assigned.into_iter().filter(|name| !globals.contains(name)));
+        FunctionScope { locals, globals }
+    }
+
+    pub fn is_local(&self, name: &str) -> bool {
+        self.locals.contains(name)
+    }
+
+    fn collect(statements: &[Stmt], assigned: &mut HashSet<String>, globals: &mut HashSet<String>) {
+        for stmt in statements {
+            match &stmt.kind {
+                StmtKind::Assign(name, _) => {
+                    assigned.insert(name.clone());
+                }
+                StmtKind::Global(names) => globals.extend(names.iter().cloned()),
+                StmtKind::If(_, body, else_body) => {
+                    Self::collect(body, assigned, globals);
+                    Self::collect(else_body, assigned, globals);
+                }
+                StmtKind::While(_, body) => Self::collect(body, assigned, globals),
+                StmtKind::For(var, _, body) => {
+                    assigned.insert(var.clone());
+                    Self::collect(body, assigned, globals);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// "1 argument" / "2 arguments"
+fn arguments(count: usize) -> String {
+    format!("{} argument{}", count, if count == 1 { "" } else { "s" })
+}
+
+// 实参个数必须与形参个数一致
+fn check_arity(name: &str, expected: usize, found: usize, span: Span) -> Result<(), Diagnostic> {
+    if expected == found {
+        return Ok(());
+    }
+    let kind = if builtins::is_builtin(name) { "Built-in function" } else { "Function" };
+    Err(Diagnostic::new(Stage::Semantic, ErrorKind::ArityMismatch, format!("{} `{}` expects {}, got {}", kind, name, arguments(expected), found)).at(span))
+}
+
+// 与内置函数同名的函数、形参或变量会让 `len xs` 这样的调用产生歧义
+fn check_not_builtin(what: &str, name: &str, span: Span) -> Result<(), Diagnostic> {
+    if builtins::is_builtin(name) {
+        return Err(Diagnostic::new(Stage::Semantic, ErrorKind::DuplicateDefinition, format!("{} `{}` shadows a built-in function", what, name)).at(span));
+    }
+    Ok(())
+}
+
+// 检查函数名和形参列表：不能与内置函数同名，形参不能重复；形参的错误指向该形参
+fn check_signature(name: &str, params: &[String], param_spans: &[Span], stmt: &Stmt) -> Result<(), Diagnostic> {
+    check_not_builtin("Function", name, stmt.span)?;
+    for (i, (param, span)) in params.iter().zip(param_spans).enumerate() {
+        check_not_builtin("Parameter", param, *span)?;
+        if params[..i].contains(param) {
+            return Err(Diagnostic::new(Stage::Semantic, ErrorKind::DuplicateDefinition, format!("Duplicate parameter `{}` in function `{}`", param, name)).at(*span));
+        }
+    }
+    Ok(())
+}
+
+// 使 `@memo` 函数的结果依赖调用之外状态的原因
+enum Impurity {
+    Stmt(&'static Stmt),  // `out` 或 `global` 语句
+    Read(String),  // 读取的模块级变量
+    Mutation(&'static Stmt),  // 修改了不是函数自己创建的列表或映射
+}
+
+impl Impurity {
+    fn describe(&self) -> String {
+        match self {
+            Impurity::Stmt(stmt) if matches!(stmt.kind, StmtKind::Out(_)) => format!("uses `out` on line {}", stmt.span.line),
+            Impurity::Stmt(stmt) => format!("uses `global` on line {}", stmt.span.line),
+            Impurity::Read(name) => format!("reads the module-level variable `{}`", name),
+            Impurity::Mutation(stmt) => format!("changes a list or map it did not create on line {}", stmt.span.line),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SemanticAnalyzer {
     functions: Arc<std::collections::HashMap<String, (Vec<String>, &'static Vec<Stmt>)>>,
-    variables: std::collections::HashSet<String>,
-    expr_cache: std::collections::HashMap<u64, Result<(), String>>,
+    scopes: Vec<HashSet<String>>,  // 由外到内的语句块作用域
+    function: Option<FunctionScope>,  // 正在分析的函数；顶层代码为 None
+    module_globals: Arc<HashSet<String>>,  // 函数中可以读取的模块级变量
+    expr_cache: std::collections::HashMap<u64, Result<(), Diagnostic>>,
+    config: SemanticConfig,
+    loop_depth: usize,  // 当前所在循环的嵌套层数
 }
 
 impl SemanticAnalyzer {
-    fn new() -> Self {
+    fn new(config: SemanticConfig) -> Self {
         SemanticAnalyzer {
             functions: Arc::new(std::collections::HashMap::new()),
-            variables: std::collections::HashSet::new(),
+            scopes: vec![HashSet::new()],
+            function: None,
+            module_globals: Arc::new(HashSet::new()),
             expr_cache: std::collections::HashMap::new(),
+            config,
+            loop_depth: 0,
+        }
+    }
+
+    // 与用户函数同名的形参或变量同样会让 `f x` 这样的调用产生歧义
+    fn check_not_function(&self, what: &str, name: &str, span: Span) -> Result<(), Diagnostic> {
+        if self.functions.contains_key(name) {
+            return Err(Diagnostic::new(Stage::Semantic, ErrorKind::DuplicateDefinition, format!("{} `{}` shadows a user-defined function", what, name)).at(span));
+        }
+        Ok(())
+    }
+
+    fn is_visible(&self, name: &str) -> bool {
+        self.scopes.iter().any(|scope| scope.contains(name))
+    }
+
+    // 对不可见的名字赋值时在最内层作用域中声明它；已可见的名字沿用外层的变量
+    fn declare(&mut self, name: &str) {
+        if !self.is_visible(name) {
+            self.scopes.last_mut().unwrap().insert(name.to_string());
+        }
+    }
+
+    // 分析语句块，块中声明的变量在块结束后不可见
+    fn analyze_block(&mut self, body: &[Stmt], loop_var: Option<&str>) -> Result<(), Diagnostic> {
+        let mut scope = HashSet::new();
+        if let Some(var) = loop_var {
+            scope.insert(var.to_string());
         }
+        self.scopes.push(scope);
+        let result = body.iter().try_for_each(|stmt| self.analyze_stmt(stmt));
+        self.scopes.pop();
+        // 缓存的结果依赖当前可见的变量
+        self.expr_cache.clear();
+        result
     }
 
     fn expr_hash(expr: &Expr) -> u64 {
@@ -23,25 +172,42 @@ impl SemanticAnalyzer {
         use std::hash::{Hash, Hasher};
 
         let mut hasher = DefaultHasher::new();
-        match expr {
-            Expr::Number(n) => {
+        match &expr.kind {
+            ExprKind::Number(n) => {
                 "Number".hash(&mut hasher);
                 n.hash(&mut hasher);
             }
-            Expr::TypedNumber(_) => {
+            ExprKind::TypedNumber(_) => {
                 "TypedNumber".hash(&mut hasher);
             }
-            Expr::Ident(name) => {
+            ExprKind::Str(value) => {
+                "Str".hash(&mut hasher);
+                value.hash(&mut hasher);
+            }
+            ExprKind::Bool(value) => {
+                "Bool".hash(&mut hasher);
+                value.hash(&mut hasher);
+            }
+            ExprKind::Float(value) => {
+                "Float".hash(&mut hasher);
+                value.hash(&mut hasher);
+            }
+            ExprKind::Ident(name) => {
                 "Ident".hash(&mut hasher);
                 name.hash(&mut hasher);
             }
-            Expr::BinOp(left, op, right) => {
+            ExprKind::Unary(op, operand) => {
+                "Unary".hash(&mut hasher);
+                op.hash(&mut hasher);
+                Self::expr_hash(operand).hash(&mut hasher);
+            }
+            ExprKind::BinOp(left, op, right) => {
                 "BinOp".hash(&mut hasher);
                 Self::expr_hash(left).hash(&mut hasher);
                 op.hash(&mut hasher);
                 Self::expr_hash(right).hash(&mut hasher);
             }
-            Expr::Call(name, args) => {
+            ExprKind::Call(name, args) => {
                 "Call".hash(&mut hasher);
                 name.hash(&mut hasher);
                 args.len().hash(&mut hasher);
@@ -49,11 +215,31 @@ impl SemanticAnalyzer {
                     Self::expr_hash(arg).hash(&mut hasher);
                 }
             }
+            ExprKind::List(items) => {
+                "List".hash(&mut hasher);
+                items.len().hash(&mut hasher);
+                for item in items {
+                    Self::expr_hash(item).hash(&mut hasher);
+                }
+            }
+            ExprKind::Map(entries) => {
+                "Map".hash(&mut hasher);
+                entries.len().hash(&mut hasher);
+                for (key, value) in entries {
+                    Self::expr_hash(key).hash(&mut hasher);
+                    Self::expr_hash(value).hash(&mut hasher);
+                }
+            }
+            ExprKind::Index(container, index) => {
+                "Index".hash(&mut hasher);
+                Self::expr_hash(container).hash(&mut hasher);
+                Self::expr_hash(index).hash(&mut hasher);
+            }
         }
         hasher.finish()
     }
 
-    fn analyze_expr(&mut self, expr: &Expr) -> Result<(), String> {
+    fn analyze_expr(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
         // 检查缓存中是否已有结果
         let cache_key = Self::expr_hash(expr);
         if let Some(result) = self.expr_cache.get(&cache_key) {
@@ -61,7 +247,7 @@ impl SemanticAnalyzer {
         }
         
         // 使用函数指针映射进行快速表达式分析
-        type ExprAnalyzer = fn(&mut SemanticAnalyzer, &Expr) -> Result<(), String>;
+        type ExprAnalyzer = fn(&mut SemanticAnalyzer, &Expr) -> Result<(), Diagnostic>;
         
         // 静态映射表，只初始化一次
         static EXPR_ANALYZERS: std::sync::OnceLock<std::collections::HashMap<&'static str, ExprAnalyzer>> = std::sync::OnceLock::new();
@@ -70,18 +256,27 @@ impl SemanticAnalyzer {
             let mut map = std::collections::HashMap::new();
             map.insert("Number", Self::analyze_number as ExprAnalyzer);
             map.insert("Ident", Self::analyze_ident as ExprAnalyzer);
+            map.insert("Unary", Self::analyze_unary as ExprAnalyzer);
             map.insert("BinOp", Self::analyze_bin_op as ExprAnalyzer);
             map.insert("Call", Self::analyze_call as ExprAnalyzer);
+            map.insert("List", Self::analyze_list as ExprAnalyzer);
+            map.insert("Map", Self::analyze_map as ExprAnalyzer);
+            map.insert("Index", Self::analyze_index as ExprAnalyzer);
             map
         });
         
         // 根据表达式类型选择分析函数
-        let analyzer = match expr {
-            Expr::Number(_) => map.get("Number").unwrap(),
-            Expr::TypedNumber(_) => map.get("Number").unwrap(), // 复用 Number 分析函数
-            Expr::Ident(_) => map.get("Ident").unwrap(),
-            Expr::BinOp(_, _, _) => map.get("BinOp").unwrap(),
-            Expr::Call(_, _) => map.get("Call").unwrap(),
+        let analyzer = match &expr.kind {
+            ExprKind::Number(_) => map.get("Number").unwrap(),
+            ExprKind::TypedNumber(_) => map.get("Number").unwrap(), // 复用 Number 分析函数
+            ExprKind::Str(_) | ExprKind::Bool(_) | ExprKind::Float(_) => map.get("Number").unwrap(), // 字面量同样无需检查
+            ExprKind::Ident(_) => map.get("Ident").unwrap(),
+            ExprKind::Unary(_, _) => map.get("Unary").unwrap(),
+            ExprKind::BinOp(_, _, _) => map.get("BinOp").unwrap(),
+            ExprKind::Call(_, _) => map.get("Call").unwrap(),
+            ExprKind::List(_) => map.get("List").unwrap(),
+            ExprKind::Map(_) => map.get("Map").unwrap(),
+            ExprKind::Index(_, _) => map.get("Index").unwrap(),
         };
         
         let result = analyzer(self, expr);
@@ -93,53 +288,114 @@ impl SemanticAnalyzer {
     }
     
     // 分析数字表达式
-    fn analyze_number(&mut self, _expr: &Expr) -> Result<(), String> {
+    fn analyze_number(&mut self, _expr: &Expr) -> Result<(), Diagnostic> {
         Ok(())
     }
     
     // 分析标识符表达式
-    fn analyze_ident(&mut self, expr: &Expr) -> Result<(), String> {
-        if let Expr::Ident(name) = expr {
-            if !self.variables.contains(name) && !self.functions.contains_key(name) {
-                Err(format!("Undefined variable or function: {}", name))
-            } else {
-                Ok(())
+    fn analyze_ident(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        if let ExprKind::Ident(name) = &expr.kind {
+            // 单独出现的函数名按无参调用执行
+            if let Some((params, _)) = self.functions.get(name) {
+                return check_arity(name, params.len(), 0, expr.span);
             }
+            let (defined, shadowed) = match &self.function {
+                Some(scope) if !scope.is_local(name) => (self.module_globals.contains(name), false),
+                // 函数中赋值过的名字是局部变量，赋值之前不能读取同名的模块级变量
+                Some(_) => (self.is_visible(name), self.module_globals.contains(name)),
+                None => (self.is_visible(name), false),
+            };
+            if defined {
+                return Ok(());
+            }
+            let mut err = Diagnostic::new(Stage::Semantic, ErrorKind::UndefinedVariable, format!("Undefined variable or function: {}", name)).at(expr.span);
+            if shadowed {
+                err = err.with_note(format!("`{}` is assigned in this function, so it is a local variable here; add `global {}` to use the module-level one", name, name));
+            }
+            Err(err)
         } else {
-            Err("Expected identifier".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected identifier").at(expr.span))
         }
     }
     
+    // 分析一元操作表达式
+    fn analyze_unary(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        if let ExprKind::Unary(_, operand) = &expr.kind {
+            self.analyze_expr(operand)
+        } else {
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected unary operation").at(expr.span))
+        }
+    }
+
     // 分析二元操作表达式
-    fn analyze_bin_op(&mut self, expr: &Expr) -> Result<(), String> {
-        if let Expr::BinOp(left, _, right) = expr {
+    fn analyze_bin_op(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        if let ExprKind::BinOp(left, _, right) = &expr.kind {
             self.analyze_expr(left)?;
             self.analyze_expr(right)?;
             Ok(())
         } else {
-            Err("Expected binary operation".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected binary operation").at(expr.span))
         }
     }
     
     // 分析函数调用表达式
-    fn analyze_call(&mut self, expr: &Expr) -> Result<(), String> {
-        if let Expr::Call(name, args) = expr {
-            if !self.functions.contains_key(name) {
-                Err(format!("Undefined function: {}", name))
-            } else {
-                for arg in args {
-                    self.analyze_expr(arg)?;
+    fn analyze_call(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        if let ExprKind::Call(name, args) = &expr.kind {
+            let expected = match (self.functions.get(name), builtins::lookup(name)) {
+                (Some((params, _)), _) => params.len(),
+                (None, Some(builtin)) => builtin.arity,
+                (None, None) => {
+                    return Err(Diagnostic::new(Stage::Semantic, ErrorKind::UndefinedFunction, format!("Undefined function: {}", name)).at(expr.span));
                 }
-                Ok(())
+            };
+            check_arity(name, expected, args.len(), expr.span)?;
+            for arg in args {
+                self.analyze_expr(arg)?;
+            }
+            Ok(())
+        } else {
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected function call").at(expr.span))
+        }
+    }
+
+    // 分析列表字面量
+    fn analyze_list(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        if let ExprKind::List(items) = &expr.kind {
+            for item in items {
+                self.analyze_expr(item)?;
             }
+            Ok(())
+        } else {
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected list literal").at(expr.span))
+        }
+    }
+
+    // 分析映射字面量
+    fn analyze_map(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        if let ExprKind::Map(entries) = &expr.kind {
+            for (key, value) in entries {
+                self.analyze_expr(key)?;
+                self.analyze_expr(value)?;
+            }
+            Ok(())
+        } else {
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected map literal").at(expr.span))
+        }
+    }
+
+    // 分析下标访问表达式
+    fn analyze_index(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        if let ExprKind::Index(container, index) = &expr.kind {
+            self.analyze_expr(container)?;
+            self.analyze_expr(index)
         } else {
-            Err("Expected function call".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected index expression").at(expr.span))
         }
     }
 
-    fn analyze_stmt(&mut self, stmt: &Stmt) -> Result<(), String> {
+    fn analyze_stmt(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
         // 使用函数指针映射进行快速语句分析
-        type StmtAnalyzer = fn(&mut SemanticAnalyzer, &Stmt) -> Result<(), String>;
+        type StmtAnalyzer = fn(&mut SemanticAnalyzer, &Stmt) -> Result<(), Diagnostic>;
         
         // 静态映射表，只初始化一次
         static STMT_ANALYZERS: std::sync::OnceLock<std::collections::HashMap<&'static str, StmtAnalyzer>> = std::sync::OnceLock::new();
@@ -149,88 +405,169 @@ impl SemanticAnalyzer {
             map.insert("Assign", Self::analyze_assign as StmtAnalyzer);
             map.insert("If", Self::analyze_if as StmtAnalyzer);
             map.insert("While", Self::analyze_while as StmtAnalyzer);
+            map.insert("For", Self::analyze_for as StmtAnalyzer);
+            map.insert("LoopControl", Self::analyze_loop_control as StmtAnalyzer);
+            map.insert("Global", Self::analyze_global as StmtAnalyzer);
             map.insert("Return", Self::analyze_return as StmtAnalyzer);
             map.insert("Out", Self::analyze_out as StmtAnalyzer);
+            map.insert("IndexAssign", Self::analyze_index_assign as StmtAnalyzer);
+            map.insert("Expr", Self::analyze_expr_stmt as StmtAnalyzer);
             map.insert("FuncDef", Self::analyze_func_def as StmtAnalyzer);
             map
         });
         
         // 根据语句类型选择分析函数
-        let analyzer = match stmt {
-            Stmt::Assign(_, _) => map.get("Assign").unwrap(),
-            Stmt::If(_, _) => map.get("If").unwrap(),
-            Stmt::While(_, _) => map.get("While").unwrap(),
-            Stmt::Return(_) => map.get("Return").unwrap(),
-            Stmt::Out(_) => map.get("Out").unwrap(),
-            Stmt::FuncDef(_, _, _) => map.get("FuncDef").unwrap(),
+        let analyzer = match &stmt.kind {
+            StmtKind::Assign(_, _) => map.get("Assign").unwrap(),
+            StmtKind::If(_, _, _) => map.get("If").unwrap(),
+            StmtKind::While(_, _) => map.get("While").unwrap(),
+            StmtKind::For(_, _, _) => map.get("For").unwrap(),
+            StmtKind::Break | StmtKind::Continue => map.get("LoopControl").unwrap(),
+            StmtKind::Global(_) => map.get("Global").unwrap(),
+            StmtKind::Return(_) => map.get("Return").unwrap(),
+            StmtKind::Out(_) => map.get("Out").unwrap(),
+            StmtKind::IndexAssign(_, _, _) => map.get("IndexAssign").unwrap(),
+            StmtKind::Expr(_) => map.get("Expr").unwrap(),
+            StmtKind::FuncDef(..) => map.get("FuncDef").unwrap(),
         };
         
         analyzer(self, stmt)
     }
     
     // 分析赋值语句
-    fn analyze_assign(&mut self, stmt: &Stmt) -> Result<(), String> {
-        if let Stmt::Assign(name, expr) = stmt {
+    fn analyze_assign(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::Assign(name, expr) = &stmt.kind {
+            check_not_builtin("Variable", name, stmt.span)?;
+            self.check_not_function("Variable", name, stmt.span)?;
             self.analyze_expr(expr)?;
-            self.variables.insert(name.clone());
+            let writes_global = self.function.as_ref().is_some_and(|scope| scope.globals.contains(name));
+            if !writes_global {
+                self.declare(name);
+            }
             Ok(())
         } else {
-            Err("Expected assignment".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected assignment").at(stmt.span))
         }
     }
     
     // 分析if语句
-    fn analyze_if(&mut self, stmt: &Stmt) -> Result<(), String> {
-        if let Stmt::If(cond, body) = stmt {
+    fn analyze_if(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::If(cond, body, else_body) = &stmt.kind {
             self.analyze_expr(cond)?;
-            for stmt in body {
-                self.analyze_stmt(stmt)?;
-            }
-            Ok(())
+            self.analyze_block(body, None)?;
+            self.analyze_block(else_body, None)
         } else {
-            Err("Expected if statement".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected if statement").at(stmt.span))
         }
     }
     
     // 分析while语句
-    fn analyze_while(&mut self, stmt: &Stmt) -> Result<(), String> {
-        if let Stmt::While(cond, body) = stmt {
+    fn analyze_while(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::While(cond, body) = &stmt.kind {
             self.analyze_expr(cond)?;
-            for stmt in body {
-                self.analyze_stmt(stmt)?;
+            self.analyze_loop_body(body, None)
+        } else {
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected while statement").at(stmt.span))
+        }
+    }
+
+    // 分析for语句；区间端点和遍历对象的类型由类型推断检查
+    fn analyze_for(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::For(var, target, body) = &stmt.kind {
+            check_not_builtin("Loop variable", var, stmt.span)?;
+            self.check_not_function("Loop variable", var, stmt.span)?;
+            match target {
+                ForTarget::Range(start, end) => {
+                    self.analyze_expr(start)?;
+                    self.analyze_expr(end)?;
+                }
+                ForTarget::Each(items) => self.analyze_expr(items)?,
             }
-            Ok(())
+            self.analyze_loop_body(body, Some(var))
         } else {
-            Err("Expected while statement".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected for statement").at(stmt.span))
+        }
+    }
+
+    // 循环变量只在循环体中可见，除非循环前已有同名变量
+    fn analyze_loop_body(&mut self, body: &[Stmt], loop_var: Option<&str>) -> Result<(), Diagnostic> {
+        self.loop_depth += 1;
+        let result = self.analyze_block(body, loop_var.filter(|var| !self.is_visible(var)));
+        self.loop_depth -= 1;
+        result
+    }
+
+    // `break` 和 `continue` 只能出现在循环中；函数体单独分析，不会越过函数边界
+    fn analyze_loop_control(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if self.loop_depth > 0 {
+            return Ok(());
+        }
+        let keyword = if matches!(stmt.kind, StmtKind::Break) { "break" } else { "continue" };
+        Err(Diagnostic::new(Stage::Semantic, ErrorKind::OutsideLoop, format!("`{}` outside of a loop", keyword)).at(stmt.span))
+    }
+
+    // `global` 只能用在函数中，且不能声明形参
+    fn analyze_global(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        let StmtKind::Global(names) = &stmt.kind else {
+            return Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected global declaration").at(stmt.span));
+        };
+        let Some(scope) = &self.function else {
+            return Err(Diagnostic::new(Stage::Semantic, ErrorKind::InvalidGlobal, "`global` can only be used inside a function").at(stmt.span));
+        };
+        // 形参总在 locals 中，其余声明为 global 的名字不在
+        match names.iter().find(|name| scope.is_local(name)) {
+            Some(name) => Err(Diagnostic::new(Stage::Semantic, ErrorKind::InvalidGlobal, format!("Parameter `{}` cannot be declared global", name)).at(stmt.span)),
+            None => Ok(()),
         }
     }
     
     // 分析return语句
-    fn analyze_return(&mut self, stmt: &Stmt) -> Result<(), String> {
-        if let Stmt::Return(expr) = stmt {
+    fn analyze_return(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::Return(expr) = &stmt.kind {
             self.analyze_expr(expr)?;
             Ok(())
         } else {
-            Err("Expected return statement".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected return statement").at(stmt.span))
         }
     }
     
     // 分析out语句
-    fn analyze_out(&mut self, stmt: &Stmt) -> Result<(), String> {
-        if let Stmt::Out(expr) = stmt {
+    fn analyze_out(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::Out(expr) = &stmt.kind {
             self.analyze_expr(expr)?;
             Ok(())
         } else {
-            Err("Expected out statement".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected out statement").at(stmt.span))
         }
     }
     
+    // 分析下标赋值语句
+    fn analyze_index_assign(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::IndexAssign(container, index, value) = &stmt.kind {
+            self.analyze_expr(container)?;
+            self.analyze_expr(index)?;
+            self.analyze_expr(value)
+        } else {
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected index assignment").at(stmt.span))
+        }
+    }
+
+    // 分析不输出结果的表达式语句
+    fn analyze_expr_stmt(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::Expr(expr) = &stmt.kind {
+            self.analyze_expr(expr)
+        } else {
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected expression statement").at(stmt.span))
+        }
+    }
+
     // 分析函数定义语句
-    fn analyze_func_def(&mut self, stmt: &Stmt) -> Result<(), String> {
-        if let Stmt::FuncDef(name, params, body) = stmt {
+    fn analyze_func_def(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let StmtKind::FuncDef(name, params, param_spans, body, _) = &stmt.kind {
             if self.functions.contains_key(name) {
-                return Err(format!("Function already defined: {}", name));
+                return Err(Diagnostic::new(Stage::Semantic, ErrorKind::DuplicateDefinition, format!("Function already defined: {}", name)).at(stmt.span));
             }
+            check_signature(name, params, param_spans, stmt)?;
             // 先注册函数，处理前向引用
             let static_body: &'static Vec<Stmt> = unsafe {
                 std::mem::transmute(body)
@@ -239,60 +576,310 @@ impl SemanticAnalyzer {
             functions_map.insert(name.clone(), (params.clone(), static_body));
             Ok(())
         } else {
-            Err("Expected function definition".to_string())
+            Err(Diagnostic::new(Stage::Semantic, ErrorKind::Internal, "Expected function definition").at(stmt.span))
+        }
+    }
+
+    // 检查函数注解；`@memo` 只允许用于纯函数，否则跳过调用会改变程序行为
+    fn check_annotations(&self, name: &str, annotations: &[String], stmt: &Stmt) -> Result<(), Diagnostic> {
+        for annotation in annotations {
+            match annotation.as_str() {
+                "memo" => {
+                    let mut visited = std::collections::HashSet::new();
+                    if let Some((culprit, impurity)) = self.find_impurity(name, &mut visited) {
+                        let note = match &impurity {
+                            Impurity::Stmt(out_stmt) if culprit == name && matches!(out_stmt.kind, StmtKind::Out(_)) => {
+                                format!("`out` on line {} would be skipped on cached calls", out_stmt.span.line)
+                            }
+                            _ if culprit == name => format!("it {}", impurity.describe()),
+                            _ => format!("it calls `{}`, which {}", culprit, impurity.describe()),
+                        };
+                        return Err(Diagnostic::new(Stage::Semantic, ErrorKind::InvalidAnnotation, format!("Cannot memoize `{}`: it is not pure", name))
+                            .at(stmt.span)
+                            .with_note(note));
+                    }
+                }
+                other => {
+                    return Err(Diagnostic::new(Stage::Semantic, ErrorKind::InvalidAnnotation, format!("Unknown annotation: @{}", other)).at(stmt.span));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 查找函数（及其传递调用的函数）中产生副作用的语句或读取的模块级变量，返回所在函数名和原因
+    fn find_impurity(&self, name: &str, visited: &mut std::collections::HashSet<String>) -> Option<(String, Impurity)> {
+        if !visited.insert(name.to_string()) {
+            return None;
+        }
+        let (params, body) = self.functions.get(name)?;
+        let body: &'static Vec<Stmt> = body;
+        let mut callees = Vec::new();
+        if let Some(stmt) = Self::find_effect_stmt(body, &mut callees) {
+            return Some((name.to_string(), Impurity::Stmt(stmt)));
+        }
+        // 既不是函数也不是局部变量的名字读取的是模块级变量
+        let scope = FunctionScope::new(params, body);
+        if let Some(global) = callees.iter().find(|callee| !self.functions.contains_key(*callee) && !builtins::is_builtin(callee) && !scope.is_local(callee)) {
+            return Some((name.to_string(), Impurity::Read(global.clone())));
+        }
+        if let Some(stmt) = Self::find_mutation(body, &Self::fresh_containers(body)) {
+            return Some((name.to_string(), Impurity::Mutation(stmt)));
+        }
+        for callee in callees {
+            if let Some(found) = self.find_impurity(&callee, visited) {
+                return Some(found);
+            }
+        }
+        None
+    }
+
+    // 返回语句块中的第一个 `out` 或 `global`，同时收集被调用的函数名和读取的变量名
+    fn find_effect_stmt<'a>(statements: &'a [Stmt], callees: &mut Vec<String>) -> Option<&'a Stmt> {
+        for stmt in statements {
+            match &stmt.kind {
+                StmtKind::Out(_) | StmtKind::Global(_) => return Some(stmt),
+                StmtKind::Assign(_, expr) | StmtKind::Return(expr) | StmtKind::Expr(expr) => Self::collect_calls(expr, callees),
+                StmtKind::IndexAssign(container, index, value) => {
+                    Self::collect_calls(container, callees);
+                    Self::collect_calls(index, callees);
+                    Self::collect_calls(value, callees);
+                }
+                StmtKind::If(cond, body, else_body) => {
+                    Self::collect_calls(cond, callees);
+                    if let Some(found) = Self::find_effect_stmt(body, callees).or_else(|| Self::find_effect_stmt(else_body, callees)) {
+                        return Some(found);
+                    }
+                }
+                StmtKind::While(cond, body) => {
+                    Self::collect_calls(cond, callees);
+                    if let Some(found) = Self::find_effect_stmt(body, callees) {
+                        return Some(found);
+                    }
+                }
+                StmtKind::For(_, target, body) => {
+                    match target {
+                        ForTarget::Range(start, end) => {
+                            Self::collect_calls(start, callees);
+                            Self::collect_calls(end, callees);
+                        }
+                        ForTarget::Each(items) => Self::collect_calls(items, callees),
+                    }
+                    if let Some(found) = Self::find_effect_stmt(body, callees) {
+                        return Some(found);
+                    }
+                }
+                StmtKind::Break | StmtKind::Continue | StmtKind::FuncDef(..) => {}
+            }
+        }
+        None
+    }
+
+    // 只被赋值为列表或映射字面量的局部变量：它们引用的容器由函数自己创建，修改不会被调用方看到
+    fn fresh_containers(body: &[Stmt]) -> HashSet<String> {
+        fn collect(statements: &[Stmt], fresh: &mut HashMap<String, bool>) {
+            for stmt in statements {
+                match &stmt.kind {
+                    StmtKind::Assign(name, expr) => {
+                        let literal = matches!(expr.kind, ExprKind::List(_) | ExprKind::Map(_));
+                        *fresh.entry(name.clone()).or_insert(true) &= literal;
+                    }
+                    StmtKind::If(_, body, else_body) => {
+                        collect(body, fresh);
+                        collect(else_body, fresh);
+                    }
+                    StmtKind::While(_, body) => collect(body, fresh),
+                    // 循环变量可能是实参中的元素
+                    StmtKind::For(var, _, body) => {
+                        fresh.insert(var.clone(), false);
+                        collect(body, fresh);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut fresh = HashMap::new();
+        collect(body, &mut fresh);
+        fresh.into_iter().filter(|(_, literal)| *literal).map(|(name, _)| name).collect()
+    }
+
+    // 返回第一条修改了 `fresh` 以外的容器的语句：实参、模块级变量或其中的元素
+    fn find_mutation<'a>(statements: &'a [Stmt], fresh: &HashSet<String>) -> Option<&'a Stmt> {
+        let mutates = |expr: &Expr| Self::expr_mutates(expr, fresh);
+        for stmt in statements {
+            let found = match &stmt.kind {
+                StmtKind::Assign(_, expr) | StmtKind::Return(expr) | StmtKind::Expr(expr) | StmtKind::Out(expr) => mutates(expr),
+                StmtKind::IndexAssign(container, index, value) => {
+                    !Self::is_fresh(container, fresh) || mutates(container) || mutates(index) || mutates(value)
+                }
+                StmtKind::If(cond, body, else_body) => {
+                    if mutates(cond) {
+                        return Some(stmt);
+                    }
+                    if let Some(found) = Self::find_mutation(body, fresh).or_else(|| Self::find_mutation(else_body, fresh)) {
+                        return Some(found);
+                    }
+                    false
+                }
+                StmtKind::While(cond, body) => {
+                    if mutates(cond) {
+                        return Some(stmt);
+                    }
+                    if let Some(found) = Self::find_mutation(body, fresh) {
+                        return Some(found);
+                    }
+                    false
+                }
+                StmtKind::For(_, target, body) => {
+                    let target_mutates = match target {
+                        ForTarget::Range(start, end) => mutates(start) || mutates(end),
+                        ForTarget::Each(items) => mutates(items),
+                    };
+                    if target_mutates {
+                        return Some(stmt);
+                    }
+                    if let Some(found) = Self::find_mutation(body, fresh) {
+                        return Some(found);
+                    }
+                    false
+                }
+                StmtKind::Break | StmtKind::Continue | StmtKind::Global(_) | StmtKind::FuncDef(..) => false,
+            };
+            if found {
+                return Some(stmt);
+            }
+        }
+        None
+    }
+
+    // 表达式是否用内置函数修改 `fresh` 以外的容器
+    fn expr_mutates(expr: &Expr, fresh: &HashSet<String>) -> bool {
+        let mutates = |expr: &Expr| Self::expr_mutates(expr, fresh);
+        match &expr.kind {
+            ExprKind::Call(name, args) => {
+                let changes_first = matches!(name.as_str(), "append" | "pop" | "remove");
+                (changes_first && args.first().is_some_and(|target| !Self::is_fresh(target, fresh))) || args.iter().any(mutates)
+            }
+            ExprKind::Unary(_, operand) => mutates(operand),
+            ExprKind::BinOp(left, _, right) | ExprKind::Index(left, right) => mutates(left) || mutates(right),
+            ExprKind::List(items) => items.iter().any(mutates),
+            ExprKind::Map(entries) => entries.iter().any(|(key, value)| mutates(key) || mutates(value)),
+            ExprKind::Ident(_) | ExprKind::Number(_) | ExprKind::TypedNumber(_) | ExprKind::Str(_) | ExprKind::Bool(_) | ExprKind::Float(_) => false,
         }
     }
 
-    fn analyze_program(&mut self, program: &Program) -> Result<(), String> {
+    fn is_fresh(expr: &Expr, fresh: &HashSet<String>) -> bool {
+        matches!(&expr.kind, ExprKind::Ident(name) if fresh.contains(name))
+    }
+
+    fn collect_calls(expr: &Expr, callees: &mut Vec<String>) {
+        match &expr.kind {
+            ExprKind::Call(name, args) => {
+                callees.push(name.clone());
+                for arg in args {
+                    Self::collect_calls(arg, callees);
+                }
+            }
+            // 函数名单独出现时按无参调用执行，否则是读取变量
+            ExprKind::Ident(name) => callees.push(name.clone()),
+            ExprKind::Unary(_, operand) => Self::collect_calls(operand, callees),
+            ExprKind::BinOp(left, _, right) | ExprKind::Index(left, right) => {
+                Self::collect_calls(left, callees);
+                Self::collect_calls(right, callees);
+            }
+            ExprKind::List(items) => {
+                for item in items {
+                    Self::collect_calls(item, callees);
+                }
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries {
+                    Self::collect_calls(key, callees);
+                    Self::collect_calls(value, callees);
+                }
+            }
+            ExprKind::Number(_) | ExprKind::TypedNumber(_) | ExprKind::Str(_) | ExprKind::Bool(_) | ExprKind::Float(_) => {}
+        }
+    }
+
+    fn analyze_program(&mut self, program: &Program) -> Result<(), Diagnostic> {
         // 使用并行分析
         self.analyze_program_parallel(program)
     }
 
-    fn analyze_program_parallel(&mut self, program: &Program) -> Result<(), String> {
-        // 第一遍：注册所有函数（顺序执行，处理函数依赖）
+    fn analyze_program_parallel(&mut self, program: &Program) -> Result<(), Diagnostic> {
+        // 第一遍：注册所有函数（顺序执行，处理函数依赖），同时检查重复定义和签名
         let functions_map = Arc::make_mut(&mut self.functions);
+        let mut first_lines = std::collections::HashMap::new();
         for stmt in &program.statements {
-            if let Stmt::FuncDef(name, params, body) = stmt {
-                if !functions_map.contains_key(name) {
-                    let static_body: &'static Vec<Stmt> = unsafe {
-                        std::mem::transmute(body)
-                    };
-                    functions_map.insert(name.clone(), (params.clone(), static_body));
+            if let StmtKind::FuncDef(name, params, param_spans, body, _) = &stmt.kind {
+                if let Some(line) = first_lines.get(name) {
+                    return Err(Diagnostic::new(Stage::Semantic, ErrorKind::DuplicateDefinition, format!("Function already defined: {}", name))
+                        .at(stmt.span)
+                        .with_note(format!("`{}` was first defined on line {}", name, line)));
+                }
+                first_lines.insert(name.clone(), stmt.span.line);
+                check_signature(name, params, param_spans, stmt)?;
+                let static_body: &'static Vec<Stmt> = unsafe {
+                    std::mem::transmute(body)
+                };
+                functions_map.insert(name.clone(), (params.clone(), static_body));
+            }
+        }
+        
+        // 所有函数注册后再检查形参是否与函数同名，以及函数注解
+        for stmt in &program.statements {
+            if let StmtKind::FuncDef(name, params, param_spans, _, annotations) = &stmt.kind {
+                for (param, span) in params.iter().zip(param_spans) {
+                    self.check_not_function("Parameter", param, *span)?;
                 }
+                self.check_annotations(name, annotations, stmt)?;
             }
         }
         
-        // 收集需要分析的函数体
+        // 收集需要分析的函数体，以及函数中可以读取的模块级变量：
+        // 顶层直接赋值的变量和函数中声明为 global 的变量
         let mut function_bodies = Vec::new();
         let mut non_function_stmts = Vec::new();
+        let mut module_globals = HashSet::new();
         
         for stmt in &program.statements {
-            match stmt {
-                Stmt::FuncDef(_name, params, body) => {
-                    function_bodies.push((params.clone(), body));
+            match &stmt.kind {
+                StmtKind::FuncDef(_name, params, _, body, _) => {
+                    let scope = FunctionScope::new(params, body);
+                    module_globals.extend(scope.globals.iter().cloned());
+                    function_bodies.push((params, body, scope));
+                }
+                StmtKind::Assign(name, _) => {
+                    module_globals.insert(name.clone());
+                    non_function_stmts.push(stmt);
                 }
                 _ => {
                     non_function_stmts.push(stmt);
                 }
             }
         }
+        self.module_globals = Arc::new(module_globals);
         
         // 并行分析函数体（函数体之间是独立的）
         let functions_clone = Arc::clone(&self.functions);
-        let analysis_results: Vec<Result<(), String>> = function_bodies
-            .par_iter()
-            .map(|(params, body)| {
+        let module_globals = Arc::clone(&self.module_globals);
+        let config = self.config;
+        let analysis_results: Vec<Result<(), Diagnostic>> = function_bodies
+            .into_par_iter()
+            .map(|(params, body, scope)| {
+                // 参数在整个函数体中可见
                 let mut local_analyzer = SemanticAnalyzer {
                     functions: Arc::clone(&functions_clone),
-                    variables: std::collections::HashSet::new(),
+                    scopes: vec![params.iter().cloned().collect()],
+                    function: Some(scope),
+                    module_globals: Arc::clone(&module_globals),
                     expr_cache: std::collections::HashMap::new(),
+                    config,
+                    loop_depth: 0,
                 };
-                // 注册所有参数
-                for param in params {
-                    local_analyzer.variables.insert(param.clone());
-                }
                 // 分析函数体
-                for stmt in *body {
+                for stmt in body {
                     if let Err(err) = local_analyzer.analyze_stmt(stmt) {
                         return Err(err);
                     }
@@ -315,8 +902,685 @@ impl SemanticAnalyzer {
     }
 }
 
-pub fn analyze(program: Program) -> Result<Program, String> {
-    let mut analyzer = SemanticAnalyzer::new();
-    analyzer.analyze_program(&program)?;
+// 各变量在某一位置的类型；不在表中的变量类型未知
+type Env = HashMap<String, StaticType>;
+
+// 两条执行路径汇合处的变量类型
+fn join_env(a: &Env, b: &Env) -> Env {
+    a.iter()
+        .filter_map(|(name, ty)| Some((name.clone(), ty.join(b.get(name)?))))
+        .filter(|(_, ty)| *ty != StaticType::Unknown)
+        .collect()
+}
+
+// 语句块是否可能执行到末尾；函数执行到末尾时返回 i64 的 0
+fn may_fall_through(body: &[Stmt]) -> bool {
+    match body.last().map(|stmt| &stmt.kind) {
+        Some(StmtKind::Return(_)) => false,
+        Some(StmtKind::If(_, then_body, else_body)) => may_fall_through(then_body) || may_fall_through(else_body),
+        _ => true,
+    }
+}
+
+// 循环中 `break` 和 `continue` 处的变量类型
+#[derive(Default)]
+struct LoopExits {
+    breaks: Vec<Env>,
+    continues: Vec<Env>,
+}
+
+// 流敏感的类型推断：按执行顺序跟踪变量的类型，在分支和循环的汇合处合并，结果写入每个表达式的 `ty`。
+// 规则与树遍历解释器一致，只有操作数类型确定会出错的运算才报告错误
+struct TypeInference {
+    returns: HashMap<String, StaticType>,  // 用户函数的返回类型
+    untracked: HashSet<String>,  // 通过 `global` 赋值的模块级变量，随时可能被函数修改，类型始终未知
+    env: Env,
+    loops: Vec<LoopExits>,
+    returned: Option<StaticType>,  // 当前函数中各 return 的类型合并
+    speculative: bool,  // 循环和返回类型尚未收敛，这时的类型可能不完整，不报告错误
+    error: Option<Diagnostic>,
+    config: SemanticConfig,
+}
+
+impl TypeInference {
+    fn new(config: SemanticConfig) -> Self {
+        TypeInference {
+            returns: HashMap::new(),
+            untracked: HashSet::new(),
+            env: Env::new(),
+            loops: Vec::new(),
+            returned: None,
+            speculative: false,
+            error: None,
+            config,
+        }
+    }
+
+    // 只保留第一个错误
+    fn report(&mut self, err: Diagnostic) {
+        if !self.speculative && self.error.is_none() {
+            self.error = Some(err);
+        }
+    }
+
+    fn mismatch(&mut self, message: String, span: Span) -> StaticType {
+        self.report(Diagnostic::new(Stage::Semantic, ErrorKind::TypeMismatch, message).at(span));
+        StaticType::Never
+    }
+
+    // 一定出错的表达式之后的代码不会执行，变量保持原来的类型
+    fn assign(&mut self, name: &str, ty: StaticType) {
+        if ty == StaticType::Never {
+            return;
+        }
+        if ty == StaticType::Unknown || self.untracked.contains(name) {
+            self.env.remove(name);
+        } else {
+            self.env.insert(name.to_string(), ty);
+        }
+    }
+
+    // 严格模式下拒绝确定不是布尔值的条件
+    fn check_condition(&mut self, ty: &StaticType, span: Span) {
+        if self.config.strict_conditions && !matches!(ty, StaticType::Bool | StaticType::Unknown | StaticType::Never) {
+            self.report(Diagnostic::new(Stage::Semantic, ErrorKind::TypeMismatch, format!("Condition must be a bool, found {}", ty.name()))
+                .at(span)
+                .with_note("compare explicitly, for example `x != 0`"));
+        }
+    }
+
+    fn infer_expr(&mut self, expr: &mut Expr) -> StaticType {
+        let span = expr.span;
+        let ty = match &mut expr.kind {
+            ExprKind::Number(n) | ExprKind::TypedNumber(n) => StaticType::Integer(Some(n.get_type())),
+            ExprKind::Float(_) => StaticType::Float,
+            ExprKind::Str(_) => StaticType::String,
+            ExprKind::Bool(_) => StaticType::Bool,
+            // 函数名单独出现时按无参调用执行
+            ExprKind::Ident(name) => match self.returns.get(name) {
+                Some(ty) => ty.clone(),
+                None => self.env.get(name).cloned().unwrap_or(StaticType::Unknown),
+            },
+            ExprKind::Unary(op, operand) => {
+                let operand_ty = self.infer_expr(operand);
+                self.unary_type(*op, operand_ty, operand.span, span)
+            }
+            ExprKind::BinOp(left, op, right) => {
+                let left_ty = self.infer_expr(left);
+                let right_ty = self.infer_expr(right);
+                if let BinOpType::And | BinOpType::Or = op {
+                    self.check_condition(&left_ty, left.span);
+                    self.check_condition(&right_ty, right.span);
+                }
+                self.binary_type(*op, &left_ty, &right_ty, span)
+            }
+            ExprKind::Call(name, args) => {
+                for arg in args.iter_mut() {
+                    self.infer_expr(arg);
+                }
+                match (self.returns.get(name), builtins::lookup(name)) {
+                    (Some(ty), _) => ty.clone(),
+                    (None, Some(builtin)) => builtin.result.clone(),
+                    (None, None) => StaticType::Unknown,
+                }
+            }
+            ExprKind::List(items) => {
+                for item in items.iter_mut() {
+                    self.infer_expr(item);
+                }
+                StaticType::List
+            }
+            ExprKind::Map(entries) => {
+                for (key, value) in entries.iter_mut() {
+                    self.infer_expr(key);
+                    self.infer_expr(value);
+                }
+                StaticType::Map
+            }
+            // 列表元素和映射的值没有静态类型
+            ExprKind::Index(container, index) => {
+                let container_ty = self.infer_expr(container);
+                self.infer_expr(index);
+                self.check_indexable(&container_ty, container.span);
+                StaticType::Unknown
+            }
+        };
+        expr.ty = ty.clone();
+        ty
+    }
+
+    fn check_indexable(&mut self, ty: &StaticType, span: Span) {
+        if !matches!(ty, StaticType::List | StaticType::Map | StaticType::Unknown | StaticType::Never) {
+            self.mismatch(format!("Cannot index into {}", ty.name()), span);
+        }
+    }
+
+    fn unary_type(&mut self, op: UnaryOp, operand: StaticType, operand_span: Span, span: Span) -> StaticType {
+        match (op, operand) {
+            (_, StaticType::Never) => StaticType::Never,
+            (UnaryOp::Not, operand) => {
+                self.check_condition(&operand, operand_span);
+                StaticType::Bool
+            }
+            (UnaryOp::Neg, operand @ (StaticType::Integer(_) | StaticType::Float | StaticType::Unknown)) => operand,
+            (UnaryOp::BitNot, operand @ StaticType::Integer(_)) => operand,
+            (UnaryOp::BitNot, StaticType::Unknown) => StaticType::Integer(None),
+            (op, operand) => {
+                let symbol = if op == UnaryOp::Neg { "-" } else { "~" };
+                self.mismatch(format!("Unsupported operand type for {}: {}", symbol, operand.name()), span)
+            }
+        }
+    }
+
+    // 二元运算的结果类型：整数之间按较宽的类型计算，乘法溢出时提升为 BigInt；
+    // 整数与浮点数混合时为浮点数；字符串只支持 `+` 拼接；任意两个值都可以比较相等
+    fn binary_type(&mut self, op: BinOpType, left: &StaticType, right: &StaticType, span: Span) -> StaticType {
+        use StaticType::*;
+        // 短路求值时右侧不一定执行
+        match op {
+            BinOpType::And | BinOpType::Or => return if *left == Never { Never } else { Bool },
+            BinOpType::Eq | BinOpType::NotEq => return if *left == Never || *right == Never { Never } else { Bool },
+            _ => {}
+        }
+        let plus = matches!(op, BinOpType::Plus);
+        let comparison = matches!(op, BinOpType::Le | BinOpType::Lt | BinOpType::Gt | BinOpType::Ge);
+        let bitwise = matches!(op, BinOpType::BitAnd | BinOpType::BitOr | BinOpType::BitXor | BinOpType::Shl | BinOpType::Shr);
+        let numeric = |ty: &StaticType| matches!(ty, Integer(_) | Float | Unknown);
+        let integer = |ty: &StaticType| matches!(ty, Integer(_) | Unknown);
+        match (left, right) {
+            (Never, _) | (_, Never) => Never,
+            (String, String | Unknown) | (Unknown, String) if plus => String,
+            _ if comparison && numeric(left) && numeric(right) => Bool,
+            (Integer(Some(a)), Integer(Some(b))) if !comparison => {
+                let promoted = IntegerType::promote(a, b);
+                // 乘积溢出时提升为 BigInt，位宽只有运行时才知道；加减溢出是错误，结果保持提升后的类型
+                if matches!(op, BinOpType::Mul) && promoted != IntegerType::BigInt {
+                    Integer(None)
+                } else {
+                    Integer(Some(promoted))
+                }
+            }
+            _ if bitwise && integer(left) && integer(right) => Integer(None),
+            (Integer(_), Integer(_)) if !comparison => Integer(None),
+            (Float, Integer(_) | Float | Unknown) | (Integer(_) | Unknown, Float) if !comparison && !bitwise => Float,
+            // 整数与未知类型运算的结果可能是整数也可能是浮点数
+            (Integer(_) | Unknown, Unknown) | (Unknown, Integer(_)) if !comparison && !bitwise => Unknown,
+            (known, Unknown) | (Unknown, known) => {
+                self.mismatch(format!("Unsupported operand type for {}: {}", op.symbol(), known.name()), span)
+            }
+            _ => self.mismatch(format!("Unsupported operand types for {}: {} and {}", op.symbol(), left.name(), right.name()), span),
+        }
+    }
+
+    fn infer_block(&mut self, body: &mut [Stmt]) {
+        for stmt in body {
+            self.infer_stmt(stmt);
+        }
+    }
+
+    fn infer_stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Assign(name, expr) => {
+                let ty = self.infer_expr(expr);
+                self.assign(name, ty);
+            }
+            StmtKind::IndexAssign(container, index, value) => {
+                let container_ty = self.infer_expr(container);
+                self.infer_expr(index);
+                self.infer_expr(value);
+                self.check_indexable(&container_ty, container.span);
+            }
+            StmtKind::If(cond, body, else_body) => {
+                let ty = self.infer_expr(cond);
+                self.check_condition(&ty, cond.span);
+                let before = self.env.clone();
+                self.infer_block(body);
+                let after_then = std::mem::replace(&mut self.env, before);
+                self.infer_block(else_body);
+                self.env = join_env(&after_then, &self.env);
+            }
+            StmtKind::While(cond, body) => self.infer_loop(Some(cond), None, body),
+            StmtKind::For(var, target, body) => {
+                let var_ty = match target {
+                    ForTarget::Range(start, end) => {
+                        let start_ty = self.infer_expr(start);
+                        let end_ty = self.infer_expr(end);
+                        for (ty, span) in [(&start_ty, start.span), (&end_ty, end.span)] {
+                            if !matches!(ty, StaticType::Integer(_) | StaticType::Unknown | StaticType::Never) {
+                                self.mismatch(format!("Range bounds must be integers, found {}", ty.name()), span);
+                            }
+                        }
+                        // 循环变量是两端中较宽的类型
+                        match (start_ty, end_ty) {
+                            (StaticType::Integer(Some(a)), StaticType::Integer(Some(b))) => StaticType::Integer(Some(IntegerType::promote(&a, &b))),
+                            _ => StaticType::Integer(None),
+                        }
+                    }
+                    ForTarget::Each(items) => {
+                        let ty = self.infer_expr(items);
+                        if !matches!(ty, StaticType::List | StaticType::Map | StaticType::Unknown | StaticType::Never) {
+                            self.mismatch(format!("Cannot iterate over {}", ty.name()), items.span);
+                        }
+                        StaticType::Unknown
+                    }
+                };
+                self.infer_loop(None, Some((var, var_ty)), body);
+            }
+            StmtKind::Break => {
+                let env = self.env.clone();
+                if let Some(exits) = self.loops.last_mut() {
+                    exits.breaks.push(env);
+                }
+            }
+            StmtKind::Continue => {
+                let env = self.env.clone();
+                if let Some(exits) = self.loops.last_mut() {
+                    exits.continues.push(env);
+                }
+            }
+            StmtKind::Global(_) => {}
+            StmtKind::Return(expr) => {
+                let ty = self.infer_expr(expr);
+                self.returned = Some(match &self.returned {
+                    Some(returned) => returned.join(&ty),
+                    None => ty,
+                });
+            }
+            StmtKind::Out(expr) | StmtKind::Expr(expr) => {
+                self.infer_expr(expr);
+            }
+            StmtKind::FuncDef(_, params, _, body, _) => {
+                self.infer_function(params, body);
+            }
+        }
+    }
+
+    // 循环头的类型是进入循环时、每轮结束时和 `continue` 处的合并。先不报告错误地迭代到不再变化，
+    // 再按收敛后的类型推断一遍；循环结束后的类型再合并 `break` 处的类型
+    fn infer_loop(&mut self, mut cond: Option<&mut Expr>, var: Option<(&str, StaticType)>, body: &mut [Stmt]) {
+        let speculative = std::mem::replace(&mut self.speculative, true);
+        let mut head = self.env.clone();
+        loop {
+            self.env = head.clone();
+            let exits = self.loop_pass(cond.as_deref_mut(), var.as_ref(), body);
+            let next = exits.continues.iter().fold(join_env(&head, &self.env), |next, env| join_env(&next, env));
+            if next == head {
+                break;
+            }
+            head = next;
+        }
+        self.speculative = speculative;
+        self.env = head.clone();
+        let exits = self.loop_pass(cond, var.as_ref(), body);
+        self.env = exits.breaks.iter().fold(head, |after, env| join_env(&after, env));
+    }
+
+    fn loop_pass(&mut self, cond: Option<&mut Expr>, var: Option<&(&str, StaticType)>, body: &mut [Stmt]) -> LoopExits {
+        if let Some(cond) = cond {
+            let ty = self.infer_expr(cond);
+            self.check_condition(&ty, cond.span);
+        }
+        if let Some((name, ty)) = var {
+            self.assign(name, ty.clone());
+        }
+        self.loops.push(LoopExits::default());
+        self.infer_block(body);
+        self.loops.pop().unwrap_or_default()
+    }
+
+    // 推断函数体，返回函数的返回类型。形参和模块级变量的类型未知
+    fn infer_function(&mut self, params: &[String], body: &mut [Stmt]) -> StaticType {
+        let scope = FunctionScope::new(params, body);
+        let env = std::mem::take(&mut self.env);
+        let loops = std::mem::take(&mut self.loops);
+        let returned = self.returned.take();
+        let untracked = std::mem::replace(&mut self.untracked, scope.globals);
+        self.infer_block(body);
+        let mut result = self.returned.take();
+        if may_fall_through(body) {
+            let zero = StaticType::Integer(Some(IntegerType::I64));
+            result = Some(result.map_or(zero.clone(), |result| result.join(&zero)));
+        }
+        self.env = env;
+        self.loops = loops;
+        self.returned = returned;
+        self.untracked = untracked;
+        result.unwrap_or(StaticType::Unknown)
+    }
+
+    fn infer_program(&mut self, program: &mut Program) -> Result<(), Diagnostic> {
+        let mut untracked = HashSet::new();
+        for stmt in &program.statements {
+            if let StmtKind::FuncDef(name, params, _, body, _) = &stmt.kind {
+                self.returns.insert(name.clone(), StaticType::Unknown);
+                untracked.extend(FunctionScope::new(params, body).globals);
+            }
+        }
+
+        // 调用尚未推断的函数得到 Unknown，因此重复推断函数体直到返回类型不再变化；
+        // 每一轮的结果都成立，轮数只影响精度，按前向引用链的最大长度设上限
+        self.speculative = true;
+        for _ in 0..=self.returns.len() {
+            let mut changed = false;
+            for stmt in program.statements.iter_mut() {
+                if let StmtKind::FuncDef(name, params, _, body, _) = &mut stmt.kind {
+                    let ty = self.infer_function(params, body);
+                    changed |= self.returns.insert(name.clone(), ty.clone()) != Some(ty);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        self.speculative = false;
+
+        for stmt in program.statements.iter_mut() {
+            if let StmtKind::FuncDef(_, params, _, body, _) = &mut stmt.kind {
+                self.infer_function(params, body);
+            }
+        }
+        self.untracked = untracked;
+        for stmt in program.statements.iter_mut() {
+            if !matches!(stmt.kind, StmtKind::FuncDef(..)) {
+                self.infer_stmt(stmt);
+            }
+        }
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+pub fn analyze(program: Program) -> Result<Program, Diagnostic> {
+    analyze_with_config(program, SemanticConfig::default())
+}
+
+pub fn analyze_with_config(mut program: Program, config: SemanticConfig) -> Result<Program, Diagnostic> {
+    SemanticAnalyzer::new(config).analyze_program(&program)?;
+    TypeInference::new(config).infer_program(&mut program)?;
     Ok(program)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::tokenize;
+    use crate::parser::parse;
+
+    fn analyze_source(source: &str) -> Result<Program, Diagnostic> {
+        analyze(parse(tokenize(source).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_memo_on_pure_function() {
+        assert!(analyze_source("@memo\ndef sq n\n    return n * n\nend\nout sq 3\n").is_ok());
+    }
+
+    #[test]
+    fn test_memo_rejects_output() {
+        let err = analyze_source("@memo\ndef noisy n\n    out n\n    return n\nend\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidAnnotation);
+        assert_eq!(err.span.unwrap().line, 1);
+        assert_eq!(err.notes, vec!["`out` on line 3 would be skipped on cached calls"]);
+    }
+
+    #[test]
+    fn test_memo_rejects_impure_callee() {
+        let err = analyze_source("def log n\n    out n\nend\n@memo\ndef f n\n    x = log n\n    return n\nend\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidAnnotation);
+        assert_eq!(err.notes, vec!["it calls `log`, which uses `out` on line 2"]);
+    }
+
+    #[test]
+    fn test_strict_conditions_reject_non_bool() {
+        let strict = SemanticConfig { strict_conditions: true };
+        let analyze_strict = |source: &str| analyze_with_config(parse(tokenize(source).unwrap()).unwrap(), strict);
+
+        let err = analyze_strict("if 5\n    out 1\nend\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::TypeMismatch);
+        assert_eq!(err.message, "Condition must be a bool, found integer");
+        let err = analyze_strict("n = 3\nwhile n % 2\n    n -= 1\nend\n").unwrap_err();
+        assert_eq!(err.span.unwrap().line, 2);
+        // 类型未知的形参按运行时的值判断，类型推断得出的整数变量则不行
+        assert!(analyze_strict("def f x\n    if x > 0 and not false or x\n        out x\n    end\nend\n").is_ok());
+        let err = analyze_strict("x = 1\nif x > 0 and not false or x\n    out x\nend\n").unwrap_err();
+        assert_eq!(err.span.unwrap().col, 27);
+        assert!(analyze_strict("x = 1\nif x > 0 and x - 1\n    out x\nend\n").is_err());
+
+        // 默认模式仍按真值判断
+        assert!(analyze_source("if 5\n    out 1\nend\n").is_ok());
+    }
+
+    #[test]
+    fn test_unknown_annotation() {
+        let err = analyze_source("@inline\ndef f n\n    return n\nend\n").unwrap_err();
+        assert_eq!(err.message, "Unknown annotation: @inline");
+    }
+
+    #[test]
+    fn test_loop_control_outside_loop() {
+        assert!(analyze_source("i = 0\nwhile true\n    i += 1\n    if i > 3\n        break\n    end\nend\nfor x in [1]\n    continue\nend\n").is_ok());
+
+        let err = analyze_source("x = 1\nif x > 0\n    break\nend\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutsideLoop);
+        assert_eq!(err.message, "`break` outside of a loop");
+        assert_eq!(err.span.unwrap().line, 3);
+        // 函数体里的 `continue` 不属于调用它的循环
+        let err = analyze_source("def f n\n    continue\nend\nwhile true\n    x = f 1\nend\n").unwrap_err();
+        assert_eq!(err.message, "`continue` outside of a loop");
+    }
+
+    #[test]
+    fn test_block_scopes() {
+        // 块中给已有变量赋值会更新外层的变量
+        assert!(analyze_source("n = 0
+if true
+    n = 1
+end
+out n
+for i in 0..3
+    n += i
+end
+out n
+").is_ok());
+
+        let err = analyze_source("if true
+    big = 1
+end
+out big
+").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UndefinedVariable);
+        assert_eq!(err.span.unwrap().line, 4);
+        let err = analyze_source("for i in 0..3
+    out i
+end
+out i
+").unwrap_err();
+        assert_eq!(err.message, "Undefined variable or function: i");
+        assert!(analyze_source("i = 9
+for i in 0..3
+    out i
+end
+out i
+").is_ok());
+    }
+
+    #[test]
+    fn test_global_declarations() {
+        assert!(analyze_source("limit = 3
+def under n
+    return n < limit
+end
+def bump
+    global count
+    count += 1
+end
+count = 0
+bump
+").is_ok());
+
+        // 函数中赋值过的名字是局部变量，赋值前不能读取
+        let err = analyze_source("x = 5
+def f
+    out x
+    x = 2
+end
+").unwrap_err();
+        assert_eq!(err.span.unwrap().line, 3);
+        assert_eq!(err.notes, vec!["`x` is assigned in this function, so it is a local variable here; add `global x` to use the module-level one"]);
+        let err = analyze_source("def f
+    return y
+end
+").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UndefinedVariable);
+
+        let err = analyze_source("global x
+").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidGlobal);
+        let err = analyze_source("def f a
+    global a
+    return a
+end
+").unwrap_err();
+        assert_eq!(err.message, "Parameter `a` cannot be declared global");
+    }
+
+    #[test]
+    fn test_memo_rejects_module_variables() {
+        let err = analyze_source("scale = 2\n@memo\ndef g n\n    return n * scale\nend\n").unwrap_err();
+        assert_eq!(err.notes, vec!["it reads the module-level variable `scale`"]);
+        let err = analyze_source("def tick\n    global t\n    t = 1\nend\n@memo\ndef f n\n    x = tick\n    return n\nend\n").unwrap_err();
+        assert_eq!(err.notes, vec!["it calls `tick`, which uses `global` on line 2"]);
+    }
+
+    #[test]
+    fn test_memo_rejects_changing_arguments() {
+        let err = analyze_source("@memo\ndef push xs\n    append xs, 1\n    return len xs\nend\n").unwrap_err();
+        assert_eq!(err.notes, vec!["it changes a list or map it did not create on line 3"]);
+        let err = analyze_source("@memo\ndef put xs\n    ys = xs\n    ys[0] = 1\n    return 0\nend\n").unwrap_err();
+        assert_eq!(err.notes, vec!["it changes a list or map it did not create on line 4"]);
+        let err = analyze_source("@memo\ndef drain xs\n    for row in xs\n        x = pop row\n    end\n    return 0\nend\n").unwrap_err();
+        assert_eq!(err.notes, vec!["it changes a list or map it did not create on line 4"]);
+        let err = analyze_source("@memo\ndef take m, k\n    return remove m, k\nend\n").unwrap_err();
+        assert_eq!(err.notes, vec!["it changes a list or map it did not create on line 3"]);
+        let err = analyze_source("@memo\ndef tag m\n    m[\"seen\"] = true\n    return 0\nend\n").unwrap_err();
+        assert_eq!(err.notes, vec!["it changes a list or map it did not create on line 3"]);
+        assert!(analyze_source("@memo\ndef count n\n    seen = {}\n    seen[n] = 1\n    x = remove seen, n\n    return x\nend\n").is_ok());
+        // 函数自己创建的列表可以修改
+        assert!(analyze_source("@memo\ndef squares n\n    out_list = []\n    for i in 0..n\n        append out_list, i * i\n    end\n    out_list[0] = 1\n    return len out_list\nend\n").is_ok());
+    }
+
+    #[test]
+    fn test_call_arity_and_signatures() {
+        let err = analyze_source("def add a, b\n    return a + b\nend\nout add(1, 2, 3)\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::ArityMismatch);
+        assert_eq!(err.message, "Function `add` expects 2 arguments, got 3");
+        assert_eq!(err.span.unwrap().line, 4);
+        let err = analyze_source("def one n\n    return n\nend\nx = one\n").unwrap_err();
+        assert_eq!(err.message, "Function `one` expects 1 argument, got 0");
+        let err = analyze_source("xs = [1]\nout len(xs, xs)\n").unwrap_err();
+        assert_eq!(err.message, "Built-in function `len` expects 1 argument, got 2");
+        assert!(analyze_source("def add a, b\n    return a + b\nend\nout add 1, 2\n").is_ok());
+
+        let err = analyze_source("def f\n    return 1\nend\n\ndef f\n    return 2\nend\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DuplicateDefinition);
+        assert_eq!(err.span.unwrap().line, 5);
+        assert_eq!(err.notes, vec!["`f` was first defined on line 1"]);
+        // 形参的错误指向出错的那个形参
+        let err = analyze_source("def f a, a\n    return a\nend\n").unwrap_err();
+        assert_eq!(err.message, "Duplicate parameter `a` in function `f`");
+        assert_eq!((err.span.unwrap().line, err.span.unwrap().col), (1, 10));
+
+        let err = analyze_source("def len xs\n    return 0\nend\n").unwrap_err();
+        assert_eq!(err.message, "Function `len` shadows a built-in function");
+        let err = analyze_source("def f keys\n    return keys\nend\n").unwrap_err();
+        assert_eq!(err.message, "Parameter `keys` shadows a built-in function");
+        assert_eq!((err.span.unwrap().line, err.span.unwrap().col), (1, 7));
+        let err = analyze_source("@memo\ndef f(n,\n      len)\n    return n\nend\n").unwrap_err();
+        assert_eq!((err.span.unwrap().line, err.span.unwrap().col), (3, 7));
+        let err = analyze_source("x = 1\npop = 2\n").unwrap_err();
+        assert_eq!(err.message, "Variable `pop` shadows a built-in function");
+        assert_eq!(err.span.unwrap().line, 2);
+        let err = analyze_source("for append in 0..3\n    out append\nend\n").unwrap_err();
+        assert_eq!(err.message, "Loop variable `append` shadows a built-in function");
+
+        // 用户函数同样不能被变量、循环变量或形参遮蔽
+        let err = analyze_source("def f x
+    return x
+end
+f = \"s\"
+").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DuplicateDefinition);
+        assert_eq!(err.message, "Variable `f` shadows a user-defined function");
+        assert_eq!(err.span.unwrap().line, 4);
+        let err = analyze_source("def g
+    f = 1
+    return f
+end
+def f x
+    return x
+end
+").unwrap_err();
+        assert_eq!(err.message, "Variable `f` shadows a user-defined function");
+        assert_eq!(err.span.unwrap().line, 2);
+        let err = analyze_source("def f x
+    return x
+end
+for f in 0..3
+    out f
+end
+").unwrap_err();
+        assert_eq!(err.message, "Loop variable `f` shadows a user-defined function");
+        let err = analyze_source("def f x
+    return x
+end
+def g n, f
+    return n
+end
+").unwrap_err();
+        assert_eq!(err.message, "Parameter `f` shadows a user-defined function");
+        assert_eq!((err.span.unwrap().line, err.span.unwrap().col), (4, 10));
+    }
+
+    #[test]
+    fn test_type_inference() {
+        let program = analyze_source("x = 5i64\ny = x + 1\nz = x * 2\nw = len [1]\n").unwrap();
+        let types: Vec<StaticType> = program.statements.iter().map(|stmt| match &stmt.kind {
+            StmtKind::Assign(_, expr) => expr.ty.clone(),
+            _ => unreachable!(),
+        }).collect();
+        // 乘法溢出时提升为 BigInt，位宽只有运行时才知道
+        assert_eq!(types, vec![
+            StaticType::Integer(Some(IntegerType::I64)),
+            StaticType::Integer(Some(IntegerType::I64)),
+            StaticType::Integer(None),
+            StaticType::Integer(Some(IntegerType::I64)),
+        ]);
+
+        let err = analyze_source("name = \"sylph\"\nout name + 1\n").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::TypeMismatch);
+        assert_eq!(err.message, "Unsupported operand types for +: string and integer");
+        assert_eq!(err.span.unwrap().line, 2);
+        let err = analyze_source("def f n\n    return \"x\" - n\nend\n").unwrap_err();
+        assert_eq!(err.message, "Unsupported operand type for -: string");
+        let err = analyze_source("def name\n    return \"sylph\"\nend\nout name + 1\n").unwrap_err();
+        assert_eq!(err.span.unwrap().line, 4);
+        let err = analyze_source("x = 5\nout x[0]\n").unwrap_err();
+        assert_eq!(err.message, "Cannot index into integer");
+        let err = analyze_source("for i in 0..1.5\n    out i\nend\n").unwrap_err();
+        assert_eq!(err.message, "Range bounds must be integers, found float");
+    }
+
+    #[test]
+    fn test_type_inference_follows_control_flow() {
+        // 循环体第一轮就会出错
+        let err = analyze_source("s = \"\"\ni = 0\nwhile i < 3\n    s = s + 1\n    i += 1\nend\n").unwrap_err();
+        assert_eq!(err.span.unwrap().line, 4);
+        // 第二轮起 `x` 才会被读取，这时它可能是整数
+        assert!(analyze_source("x = \"a\"\nready = false\nwhile not ready\n    if ready\n        out x - 1\n    end\n    x = 5\n    ready = true\nend\n").is_ok());
+        // `break` 时 `x` 是字符串
+        assert!(analyze_source("x = 1\nwhile true\n    x = \"s\"\n    if x == \"s\"\n        break\n    end\n    x = 2\nend\nout x + \"t\"\n").is_ok());
+        let err = analyze_source("x = 1\nif x > 0\n    x = 2\nend\nout x + \"t\"\n").unwrap_err();
+        assert_eq!(err.message, "Unsupported operand types for +: integer and string");
+        // 函数可能用 `global` 修改的变量类型未知
+        assert!(analyze_source("def reset\n    global g\n    g = \"s\"\nend\ng = 1\nx = reset\nout g + \"!\"\n").is_ok());
+    }
+}
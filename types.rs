@@ -1,10 +1,15 @@
 // 类型定义和操作
 
+use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
-use std::ops::{Add, Sub, Mul, Div, Rem};
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, Sub, Mul, Div, Rem, Neg, Not, BitAnd, BitOr, BitXor, Shl, Shr};
+use std::rc::Rc;
 use num_bigint::BigInt;
 use num_traits::cast::ToPrimitive;
+use crate::diagnostic::{Diagnostic, ErrorKind, Stage};
 
 // 整数类型枚举
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -17,6 +22,54 @@ pub enum IntegerType {
     BigInt,
 }
 
+impl IntegerType {
+    // 两种整数运算时统一到的类型：较宽的一个
+    pub fn promote(a: &IntegerType, b: &IntegerType) -> IntegerType {
+        a.max(b).clone()
+    }
+}
+
+// 语义分析推断出的静态类型。整数的位宽只有运行时才知道时为 Integer(None)，
+// 例如不同路径赋值的位宽不同，或乘法溢出后提升为 BigInt；Unknown 表示任何类型都有可能，
+// Never 表示求值一定出错、不会产生值的表达式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StaticType {
+    Integer(Option<IntegerType>),
+    Float,
+    String,
+    Bool,
+    List,
+    Map,
+    Unknown,
+    Never,
+}
+
+impl StaticType {
+    // 与 Value::type_name 一致的名称
+    pub fn name(&self) -> &'static str {
+        match self {
+            StaticType::Integer(_) => "integer",
+            StaticType::Float => "float",
+            StaticType::String => "string",
+            StaticType::Bool => "bool",
+            StaticType::List => "list",
+            StaticType::Map => "map",
+            StaticType::Unknown => "unknown",
+            StaticType::Never => "never",
+        }
+    }
+
+    // 两条执行路径汇合处的类型
+    pub fn join(&self, other: &StaticType) -> StaticType {
+        match (self, other) {
+            _ if self == other => self.clone(),
+            (StaticType::Never, ty) | (ty, StaticType::Never) => ty.clone(),
+            (StaticType::Integer(_), StaticType::Integer(_)) => StaticType::Integer(None),
+            _ => StaticType::Unknown,
+        }
+    }
+}
+
 // 整数值枚举
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum IntegerValue {
@@ -46,6 +99,14 @@ impl StringValue {
     pub fn len(&self) -> usize {
         self.value.len()
     }
+
+    // 字符串拼接
+    pub fn concat(&self, other: &StringValue) -> StringValue {
+        let mut value = String::with_capacity(self.value.len() + other.value.len());
+        value.push_str(&self.value);
+        value.push_str(&other.value);
+        StringValue { value }
+    }
 }
 
 impl fmt::Display for StringValue {
@@ -54,11 +115,460 @@ impl fmt::Display for StringValue {
     }
 }
 
+// 浮点数值；按位比较以便 Value 实现 Eq 和 Hash，语言层面的相等见 Value::equals
+#[derive(Debug, Clone, Copy)]
+pub struct FloatValue(pub f64);
+
+impl PartialEq for FloatValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for FloatValue {}
+
+impl Hash for FloatValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+// 总是带小数点或指数，和整数区分开：1.0、0.1、1e300
+impl fmt::Display for FloatValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+// 列表值；克隆只复制引用，传参和赋值后仍指向同一个列表
+#[derive(Debug, Clone)]
+pub struct ListValue(Rc<RefCell<Vec<Value>>>);
+
+impl ListValue {
+    pub fn new(items: Vec<Value>) -> Self {
+        ListValue(Rc::new(RefCell::new(items)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    pub fn get(&self, index: &Value) -> Result<Value, Diagnostic> {
+        let position = self.position(index)?;
+        Ok(self.0.borrow()[position].clone())
+    }
+
+    pub fn set(&self, index: &Value, value: Value) -> Result<(), Diagnostic> {
+        let position = self.position(index)?;
+        self.0.borrow_mut()[position] = value;
+        Ok(())
+    }
+
+    pub fn push(&self, value: Value) {
+        self.0.borrow_mut().push(value);
+    }
+
+    // 按位置取元素，越界时返回 None；供 `for` 循环逐个取出元素
+    pub fn item(&self, position: usize) -> Option<Value> {
+        self.0.borrow().get(position).cloned()
+    }
+
+    pub fn pop(&self) -> Result<Value, Diagnostic> {
+        self.0.borrow_mut().pop().ok_or_else(|| {
+            Diagnostic::new(Stage::Runtime, ErrorKind::OutOfRange, "Cannot pop from an empty list")
+        })
+    }
+
+    // 逐个元素按语言层面的规则比较；`comparing` 记录正在比较的列表对，
+    // 同一个列表或再次遇到同一对时视为相等，自引用的列表不会无限递归
+    fn equals_in(&self, other: &ListValue, comparing: &mut Vec<(usize, usize)>) -> bool {
+        let pair = (self.address(), other.address());
+        if pair.0 == pair.1 || comparing.contains(&pair) {
+            return true;
+        }
+        let (a, b) = (self.0.borrow(), other.0.borrow());
+        if a.len() != b.len() {
+            return false;
+        }
+        comparing.push(pair);
+        let equal = a.iter().zip(b.iter()).all(|(x, y)| x.equals_in(y, comparing));
+        comparing.pop();
+        equal
+    }
+
+    fn address(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+
+    // `printing` 记录正在输出的容器，列表包含自身时输出 `[...]`
+    fn write(&self, f: &mut fmt::Formatter<'_>, printing: &mut Vec<usize>) -> fmt::Result {
+        if printing.contains(&self.address()) {
+            return write!(f, "[...]");
+        }
+        printing.push(self.address());
+        write!(f, "[")?;
+        for (i, item) in self.0.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write_element(f, item, printing)?;
+        }
+        printing.pop();
+        write!(f, "]")
+    }
+
+    // 下标必须是整数且在 0..len 之内
+    fn position(&self, index: &Value) -> Result<usize, Diagnostic> {
+        let len = self.len();
+        let Value::Integer(index) = index else {
+            return Err(Diagnostic::new(
+                Stage::Runtime,
+                ErrorKind::TypeMismatch,
+                format!("List index must be an integer, found {}", index.type_name()),
+            ));
+        };
+        match index.to_i128() {
+            Ok(i) if i >= 0 && (i as u128) < len as u128 => Ok(i as usize),
+            _ => Err(Diagnostic::new(
+                Stage::Runtime,
+                ErrorKind::OutOfRange,
+                format!("Index {} out of range for list of length {}", index, len),
+            )),
+        }
+    }
+}
+
+impl PartialEq for ListValue {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0) || *self.0.borrow() == *other.0.borrow()
+    }
+}
+
+impl Eq for ListValue {}
+
+// 列表不能作为键，只需与 Eq 保持一致；只哈希长度，自引用的列表也不会无限递归
+impl Hash for ListValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+    }
+}
+
+// 容器中的字符串带引号输出：["a", 1]
+fn write_element(f: &mut fmt::Formatter<'_>, value: &Value, printing: &mut Vec<usize>) -> fmt::Result {
+    match value {
+        Value::String(v) => write!(f, "{:?}", v.as_str()),
+        Value::List(v) => v.write(f, printing),
+        Value::Map(v) => v.write(f, printing),
+        other => write!(f, "{}", other),
+    }
+}
+
+impl fmt::Display for ListValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
+// 映射值；按插入顺序遍历，克隆只复制引用
+#[derive(Debug, Clone, Default)]
+pub struct MapValue(Rc<RefCell<MapEntries>>);
+
+#[derive(Debug, Default)]
+struct MapEntries {
+    entries: Vec<(Value, Value)>,
+    positions: HashMap<Value, usize>,  // 规范化的键 → entries 中的位置
+}
+
+impl MapValue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().entries.is_empty()
+    }
+
+    pub fn get(&self, key: &Value) -> Result<Value, Diagnostic> {
+        let map = self.0.borrow();
+        match map.positions.get(&map_key(key)?) {
+            Some(&position) => Ok(map.entries[position].1.clone()),
+            None => Err(key_not_found(key)),
+        }
+    }
+
+    // 已有的键保留原来的位置，新键追加到末尾
+    pub fn insert(&self, key: Value, value: Value) -> Result<(), Diagnostic> {
+        let normalized = map_key(&key)?;
+        let mut map = self.0.borrow_mut();
+        match map.positions.get(&normalized) {
+            Some(&position) => map.entries[position].1 = value,
+            None => {
+                let position = map.entries.len();
+                map.entries.push((key, value));
+                map.positions.insert(normalized, position);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, key: &Value) -> Result<bool, Diagnostic> {
+        Ok(self.0.borrow().positions.contains_key(&map_key(key)?))
+    }
+
+    // 删除并返回键对应的值，后面的键前移一位以保持顺序
+    pub fn remove(&self, key: &Value) -> Result<Value, Diagnostic> {
+        let mut map = self.0.borrow_mut();
+        let position = map.positions.remove(&map_key(key)?).ok_or_else(|| key_not_found(key))?;
+        let (_, value) = map.entries.remove(position);
+        for index in map.positions.values_mut() {
+            if *index > position {
+                *index -= 1;
+            }
+        }
+        Ok(value)
+    }
+
+    pub fn keys(&self) -> Vec<Value> {
+        self.0.borrow().entries.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    // 键集合相同且对应的值相等，与插入顺序无关；`comparing` 的作用同列表
+    fn equals_in(&self, other: &MapValue, comparing: &mut Vec<(usize, usize)>) -> bool {
+        let pair = (self.address(), other.address());
+        if pair.0 == pair.1 || comparing.contains(&pair) {
+            return true;
+        }
+        if self.len() != other.len() {
+            return false;
+        }
+        comparing.push(pair);
+        let a = self.0.borrow();
+        let equal = a.entries.iter().all(|(key, value)| other.get(key).is_ok_and(|v| value.equals_in(&v, comparing)));
+        comparing.pop();
+        equal
+    }
+
+    fn address(&self) -> usize {
+        Rc::as_ptr(&self.0) as usize
+    }
+
+    // 映射包含自身时输出 `{...}`
+    fn write(&self, f: &mut fmt::Formatter<'_>, printing: &mut Vec<usize>) -> fmt::Result {
+        if printing.contains(&self.address()) {
+            return write!(f, "{{...}}");
+        }
+        printing.push(self.address());
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.0.borrow().entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write_element(f, key, printing)?;
+            write!(f, ": ")?;
+            write_element(f, value, printing)?;
+        }
+        printing.pop();
+        write!(f, "}}")
+    }
+}
+
+// 键按数值规范化，使 `1` 和 `1i64` 指向同一项；只允许整数和字符串作为键
+fn map_key(key: &Value) -> Result<Value, Diagnostic> {
+    match key {
+        Value::Integer(v) => Ok(Value::Integer(v.to_i128().map(IntegerValue::I128).unwrap_or_else(|_| v.clone()))),
+        Value::String(_) => Ok(key.clone()),
+        other => Err(Diagnostic::new(
+            Stage::Runtime,
+            ErrorKind::TypeMismatch,
+            format!("Map keys must be integers or strings, found {}", other.type_name()),
+        )),
+    }
+}
+
+fn key_not_found(key: &Value) -> Diagnostic {
+    let key = match key {
+        Value::String(v) => format!("{:?}", v.as_str()),
+        other => other.to_string(),
+    };
+    Diagnostic::new(Stage::Runtime, ErrorKind::KeyNotFound, format!("Key {} not found in map", key))
+}
+
+impl PartialEq for MapValue {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0) || self.0.borrow().entries == other.0.borrow().entries
+    }
+}
+
+impl Eq for MapValue {}
+
+// 映射不能作为键，只需与 Eq 保持一致
+impl Hash for MapValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+    }
+}
+
+impl fmt::Display for MapValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
 // 统一值类型
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum Value {
     Integer(IntegerValue),
+    Float(FloatValue),
     String(StringValue),
+    Bool(bool),
+    List(ListValue),
+    Map(MapValue),
+}
+
+impl Value {
+    // 条件判断的真值：false、数值零、空字符串和空容器为假，其余为真
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Bool(b) => *b,
+            Value::Float(v) => v.0 != 0.0,
+            Value::Integer(IntegerValue::I8(v)) => *v != 0,
+            Value::Integer(IntegerValue::I16(v)) => *v != 0,
+            Value::Integer(IntegerValue::I32(v)) => *v != 0,
+            Value::Integer(IntegerValue::I64(v)) => *v != 0,
+            Value::Integer(IntegerValue::I128(v)) => *v != 0,
+            Value::Integer(IntegerValue::BigInt(v)) => *v != BigInt::from(0),
+            Value::String(v) => !v.as_str().is_empty(),
+            Value::List(v) => !v.is_empty(),
+            Value::Map(v) => !v.is_empty(),
+        }
+    }
+
+    // 语言层面的相等：数值按大小比较，不区分位宽，整数和浮点数之间也可以相等
+    pub fn equals(&self, other: &Value) -> bool {
+        self.equals_in(other, &mut Vec::new())
+    }
+
+    fn equals_in(&self, other: &Value, comparing: &mut Vec<(usize, usize)>) -> bool {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b) == Ordering::Equal,
+            (Value::List(a), Value::List(b)) => a.equals_in(b, comparing),
+            (Value::Map(a), Value::Map(b)) => a.equals_in(b, comparing),
+            _ => match promote_float(self, other) {
+                Some((a, b)) => a.0 == b.0,
+                None => self == other,
+            },
+        }
+    }
+
+    // 比较和逻辑运算的结果
+    pub fn from_bool(value: bool) -> Value {
+        Value::Bool(value)
+    }
+
+    // 一元负号，只适用于整数
+    pub fn negate(self) -> Result<Value, Diagnostic> {
+        match self {
+            Value::Integer(v) => (-v).map(Value::Integer),
+            Value::Float(v) => Ok(Value::Float(-v)),
+            other => Err(Diagnostic::new(
+                Stage::Runtime,
+                ErrorKind::TypeMismatch,
+                format!("Unsupported operand type for -: {}", other.type_name()),
+            )),
+        }
+    }
+
+    // 按位取反，只适用于整数
+    pub fn bit_not(self) -> Result<Value, Diagnostic> {
+        match self {
+            Value::Integer(v) => Ok(Value::Integer(!v)),
+            other => Err(Diagnostic::new(
+                Stage::Runtime,
+                ErrorKind::TypeMismatch,
+                format!("Unsupported operand type for ~: {}", other.type_name()),
+            )),
+        }
+    }
+
+    // 下标读取 `a[i]`：列表按位置，映射按键
+    pub fn index(&self, index: &Value) -> Result<Value, Diagnostic> {
+        match self {
+            Value::List(list) => list.get(index),
+            Value::Map(map) => map.get(index),
+            other => Err(not_indexable(other)),
+        }
+    }
+
+    // 下标赋值 `a[i] = v`，修改对所有引用该容器的变量可见；映射中不存在的键会被插入
+    pub fn store_index(&self, index: &Value, value: Value) -> Result<(), Diagnostic> {
+        match self {
+            Value::List(list) => list.set(index, value),
+            Value::Map(map) => map.insert(index.clone(), value),
+            other => Err(not_indexable(other)),
+        }
+    }
+
+    // 列表和映射按引用共享，内容在创建后仍可能被修改
+    pub fn is_container(&self) -> bool {
+        matches!(self, Value::List(_) | Value::Map(_))
+    }
+
+    // 用于错误消息的类型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+
+    // `for` 循环遍历的列表：列表按当前内容逐个遍历，映射遍历循环开始时的键
+    pub fn iteration_list(&self) -> Result<ListValue, Diagnostic> {
+        match self {
+            Value::List(list) => Ok(list.clone()),
+            Value::Map(map) => Ok(ListValue::new(map.keys())),
+            other => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, format!("Cannot iterate over {}", other.type_name()))),
+        }
+    }
+}
+
+// `for` 区间的两端统一为其中较宽的整数类型，循环变量也是这个类型
+pub fn range_bounds(start: &Value, end: &Value) -> Result<(IntegerValue, IntegerValue), Diagnostic> {
+    match (start, end) {
+        (Value::Integer(start), Value::Integer(end)) => {
+            let int_type = IntegerValue::promote_type(start, end);
+            Ok((start.cast_to(&int_type)?, end.cast_to(&int_type)?))
+        }
+        (Value::Integer(_), other) | (other, _) => Err(Diagnostic::new(
+            Stage::Runtime,
+            ErrorKind::TypeMismatch,
+            format!("Range bounds must be integers, found {}", other.type_name()),
+        )),
+    }
+}
+
+fn not_indexable(value: &Value) -> Diagnostic {
+    Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, format!("Cannot index into {}", value.type_name()))
+}
+
+// 操作数类型不匹配的运行时错误
+pub fn type_mismatch(op: &str, a: &Value, b: &Value) -> Diagnostic {
+    Diagnostic::new(
+        Stage::Runtime,
+        ErrorKind::TypeMismatch,
+        format!("Unsupported operand types for {}: {} and {}", op, a.type_name(), b.type_name()),
+    )
 }
 
 // 为 Value 实现 PartialOrd
@@ -67,7 +577,8 @@ impl PartialOrd for Value {
         match (self, other) {
             (Value::Integer(a), Value::Integer(b)) => a.partial_cmp(b),
             (Value::String(a), Value::String(b)) => a.value.partial_cmp(&b.value),
-            _ => None, // 不同类型之间不比较
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            _ => promote_float(self, other).and_then(|(a, b)| a.0.partial_cmp(&b.0)), // 不同类型之间不比较
         }
     }
 }
@@ -78,7 +589,11 @@ impl Ord for Value {
         match (self, other) {
             (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
             (Value::String(a), Value::String(b)) => a.value.cmp(&b.value),
-            _ => panic!("Cannot compare different types"),
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            _ => match promote_float(self, other) {
+                Some((a, b)) => a.0.total_cmp(&b.0),
+                None => panic!("Cannot compare different types"),
+            },
         }
     }
 }
@@ -88,7 +603,11 @@ impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Value::Integer(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{}", v),
             Value::String(v) => write!(f, "{}", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::List(v) => write!(f, "{}", v),
+            Value::Map(v) => write!(f, "{}", v),
         }
     }
 }
@@ -173,41 +692,51 @@ impl IntegerTypeInfo for i128 {
     const TYPE: IntegerType = IntegerType::I128;
 }
 
+// 数值超出目标类型范围
+fn out_of_range(value: impl fmt::Display, type_name: &str) -> Diagnostic {
+    Diagnostic::new(Stage::Runtime, ErrorKind::OutOfRange, format!("Value {} out of range for {}", value, type_name))
+}
+
+// 算术溢出
+fn overflow(message: String) -> Diagnostic {
+    Diagnostic::new(Stage::Runtime, ErrorKind::Overflow, message)
+}
+
 // 实现 IntegerValue 的方法
 impl IntegerValue {
     // 从字符串创建 IntegerValue
-    pub fn from_string(s: &str, int_type: IntegerType) -> Result<Self, String> {
+    pub fn from_string(s: &str, int_type: IntegerType) -> Result<Self, Diagnostic> {
         match int_type {
             IntegerType::I8 => {
                 s.parse::<i8>()
                     .map(IntegerValue::I8)
-                    .map_err(|_| format!("Value {} out of range for i8", s))
+                    .map_err(|_| out_of_range(s, "i8"))
             }
             IntegerType::I16 => {
                 s.parse::<i16>()
                     .map(IntegerValue::I16)
-                    .map_err(|_| format!("Value {} out of range for i16", s))
+                    .map_err(|_| out_of_range(s, "i16"))
             }
             IntegerType::I32 => {
                 s.parse::<i32>()
                     .map(IntegerValue::I32)
-                    .map_err(|_| format!("Value {} out of range for i32", s))
+                    .map_err(|_| out_of_range(s, "i32"))
             }
             IntegerType::I64 => {
                 s.parse::<i64>()
                     .map(IntegerValue::I64)
-                    .map_err(|_| format!("Value {} out of range for i64", s))
+                    .map_err(|_| out_of_range(s, "i64"))
             }
             IntegerType::I128 => {
                 s.parse::<i128>()
                     .map(IntegerValue::I128)
-                    .map_err(|_| format!("Value {} out of range for i128", s))
+                    .map_err(|_| out_of_range(s, "i128"))
             }
             IntegerType::BigInt => {
                 // 对于 BigInt，使用 BigInt::parse_bytes 来解析
                 match BigInt::parse_bytes(s.as_bytes(), 10) {
                     Some(value) => Ok(IntegerValue::BigInt(value)),
-                    None => Err(format!("Invalid bigint value: {}", s)),
+                    None => Err(Diagnostic::new(Stage::Runtime, ErrorKind::InvalidLiteral, format!("Invalid bigint value: {}", s))),
                 }
             }
         }
@@ -226,49 +755,49 @@ impl IntegerValue {
     }
 
     // 转换为 i8
-    pub fn to_i8(&self) -> Result<i8, String> {
+    pub fn to_i8(&self) -> Result<i8, Diagnostic> {
         match self {
             IntegerValue::I8(v) => Ok(*v),
             IntegerValue::I16(v) => {
                 if *v >= i8::MIN as i16 && *v <= i8::MAX as i16 {
                     Ok(*v as i8)
                 } else {
-                    Err(format!("Value {} out of range for i8", v))
+                    Err(out_of_range(v, "i8"))
                 }
             }
             IntegerValue::I32(v) => {
                 if *v >= i8::MIN as i32 && *v <= i8::MAX as i32 {
                     Ok(*v as i8)
                 } else {
-                    Err(format!("Value {} out of range for i8", v))
+                    Err(out_of_range(v, "i8"))
                 }
             }
             IntegerValue::I64(v) => {
                 if *v >= i8::MIN as i64 && *v <= i8::MAX as i64 {
                     Ok(*v as i8)
                 } else {
-                    Err(format!("Value {} out of range for i8", v))
+                    Err(out_of_range(v, "i8"))
                 }
             }
             IntegerValue::I128(v) => {
                 if *v >= i8::MIN as i128 && *v <= i8::MAX as i128 {
                     Ok(*v as i8)
                 } else {
-                    Err(format!("Value {} out of range for i8", v))
+                    Err(out_of_range(v, "i8"))
                 }
             }
             IntegerValue::BigInt(v) => {
                 if let Some(value) = v.to_i8() {
                     Ok(value)
                 } else {
-                    Err(format!("Value {} out of range for i8", v))
+                    Err(out_of_range(v, "i8"))
                 }
             }
         }
     }
 
     // 转换为 i16
-    pub fn to_i16(&self) -> Result<i16, String> {
+    pub fn to_i16(&self) -> Result<i16, Diagnostic> {
         match self {
             IntegerValue::I8(v) => Ok(*v as i16),
             IntegerValue::I16(v) => Ok(*v),
@@ -276,35 +805,35 @@ impl IntegerValue {
                 if *v >= i16::MIN as i32 && *v <= i16::MAX as i32 {
                     Ok(*v as i16)
                 } else {
-                    Err(format!("Value {} out of range for i16", v))
+                    Err(out_of_range(v, "i16"))
                 }
             }
             IntegerValue::I64(v) => {
                 if *v >= i16::MIN as i64 && *v <= i16::MAX as i64 {
                     Ok(*v as i16)
                 } else {
-                    Err(format!("Value {} out of range for i16", v))
+                    Err(out_of_range(v, "i16"))
                 }
             }
             IntegerValue::I128(v) => {
                 if *v >= i16::MIN as i128 && *v <= i16::MAX as i128 {
                     Ok(*v as i16)
                 } else {
-                    Err(format!("Value {} out of range for i16", v))
+                    Err(out_of_range(v, "i16"))
                 }
             }
             IntegerValue::BigInt(v) => {
                 if let Some(value) = v.to_i16() {
                     Ok(value)
                 } else {
-                    Err(format!("Value {} out of range for i16", v))
+                    Err(out_of_range(v, "i16"))
                 }
             }
         }
     }
 
     // 转换为 i32
-    pub fn to_i32(&self) -> Result<i32, String> {
+    pub fn to_i32(&self) -> Result<i32, Diagnostic> {
         match self {
             IntegerValue::I8(v) => Ok(*v as i32),
             IntegerValue::I16(v) => Ok(*v as i32),
@@ -313,28 +842,28 @@ impl IntegerValue {
                 if *v >= i32::MIN as i64 && *v <= i32::MAX as i64 {
                     Ok(*v as i32)
                 } else {
-                    Err(format!("Value {} out of range for i32", v))
+                    Err(out_of_range(v, "i32"))
                 }
             }
             IntegerValue::I128(v) => {
                 if *v >= i32::MIN as i128 && *v <= i32::MAX as i128 {
                     Ok(*v as i32)
                 } else {
-                    Err(format!("Value {} out of range for i32", v))
+                    Err(out_of_range(v, "i32"))
                 }
             }
             IntegerValue::BigInt(v) => {
                 if let Some(value) = v.to_i32() {
                     Ok(value)
                 } else {
-                    Err(format!("Value {} out of range for i32", v))
+                    Err(out_of_range(v, "i32"))
                 }
             }
         }
     }
 
     // 转换为 i64
-    pub fn to_i64(&self) -> Result<i64, String> {
+    pub fn to_i64(&self) -> Result<i64, Diagnostic> {
         match self {
             IntegerValue::I8(v) => Ok(*v as i64),
             IntegerValue::I16(v) => Ok(*v as i64),
@@ -344,21 +873,21 @@ impl IntegerValue {
                 if *v >= i64::MIN as i128 && *v <= i64::MAX as i128 {
                     Ok(*v as i64)
                 } else {
-                    Err(format!("Value {} out of range for i64", v))
+                    Err(out_of_range(v, "i64"))
                 }
             }
             IntegerValue::BigInt(v) => {
                 if let Some(value) = v.to_i64() {
                     Ok(value)
                 } else {
-                    Err(format!("Value {} out of range for i64", v))
+                    Err(out_of_range(v, "i64"))
                 }
             }
         }
     }
 
     // 转换为 i128
-    pub fn to_i128(&self) -> Result<i128, String> {
+    pub fn to_i128(&self) -> Result<i128, Diagnostic> {
         match self {
             IntegerValue::I8(v) => Ok(*v as i128),
             IntegerValue::I16(v) => Ok(*v as i128),
@@ -369,7 +898,7 @@ impl IntegerValue {
                 if let Some(value) = v.to_i128() {
                     Ok(value)
                 } else {
-                    Err(format!("Value {} out of range for i128", v))
+                    Err(out_of_range(v, "i128"))
                 }
             }
         }
@@ -387,32 +916,25 @@ impl IntegerValue {
         }
     }
 
+    // 转换为最接近的 f64；超出 f64 范围的 BigInt 得到正负无穷
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            IntegerValue::I8(v) => *v as f64,
+            IntegerValue::I16(v) => *v as f64,
+            IntegerValue::I32(v) => *v as f64,
+            IntegerValue::I64(v) => *v as f64,
+            IntegerValue::I128(v) => *v as f64,
+            IntegerValue::BigInt(v) => v.to_f64().unwrap_or(f64::NAN),
+        }
+    }
+
     // 自动类型提升：返回两个值中较大的类型
     pub fn promote_type(a: &IntegerValue, b: &IntegerValue) -> IntegerType {
-        let type_order = [
-            IntegerType::I8,
-            IntegerType::I16,
-            IntegerType::I32,
-            IntegerType::I64,
-            IntegerType::I128,
-            IntegerType::BigInt,
-        ];
-
-        let a_type = a.get_type();
-        let b_type = b.get_type();
-
-        let a_idx = type_order.iter().position(|t| *t == a_type).unwrap();
-        let b_idx = type_order.iter().position(|t| *t == b_type).unwrap();
-
-        if a_idx > b_idx {
-            a_type
-        } else {
-            b_type
-        }
+        IntegerType::promote(&a.get_type(), &b.get_type())
     }
 
     // 转换为指定类型
-    pub fn cast_to(&self, target_type: &IntegerType) -> Result<IntegerValue, String> {
+    pub fn cast_to(&self, target_type: &IntegerType) -> Result<IntegerValue, Diagnostic> {
         match target_type {
             IntegerType::I8 => self.to_i8().map(IntegerValue::I8),
             IntegerType::I16 => self.to_i16().map(IntegerValue::I16),
@@ -424,9 +946,23 @@ impl IntegerValue {
     }
 }
 
+// 整数与浮点数混合运算的类型提升：任一侧是浮点数时两侧都转为 f64。
+// 两侧都是整数（交给 promote_type）或含非数值时返回 None
+pub fn promote_float(a: &Value, b: &Value) -> Option<(FloatValue, FloatValue)> {
+    let to_float = |value: &Value| match value {
+        Value::Integer(v) => Some(FloatValue(v.to_f64())),
+        Value::Float(v) => Some(*v),
+        _ => None,
+    };
+    match (a, b) {
+        (Value::Integer(_), Value::Integer(_)) => None,
+        _ => Some((to_float(a)?, to_float(b)?)),
+    }
+}
+
 // 实现加法操作
 impl Add for IntegerValue {
-    type Output = Result<IntegerValue, String>;
+    type Output = Result<IntegerValue, Diagnostic>;
 
     fn add(self, rhs: Self) -> Self::Output {
         let target_type = IntegerValue::promote_type(&self, &rhs);
@@ -437,40 +973,67 @@ impl Add for IntegerValue {
             (IntegerValue::I8(a), IntegerValue::I8(b)) => {
                 a.checked_add(b)
                     .map(IntegerValue::I8)
-                    .ok_or_else(|| format!("Addition overflow for i8: {} + {}", a, b))
+                    .ok_or_else(|| overflow(format!("Addition overflow for i8: {} + {}", a, b)))
             }
             (IntegerValue::I16(a), IntegerValue::I16(b)) => {
                 a.checked_add(b)
                     .map(IntegerValue::I16)
-                    .ok_or_else(|| format!("Addition overflow for i16: {} + {}", a, b))
+                    .ok_or_else(|| overflow(format!("Addition overflow for i16: {} + {}", a, b)))
             }
             (IntegerValue::I32(a), IntegerValue::I32(b)) => {
                 a.checked_add(b)
                     .map(IntegerValue::I32)
-                    .ok_or_else(|| format!("Addition overflow for i32: {} + {}", a, b))
+                    .ok_or_else(|| overflow(format!("Addition overflow for i32: {} + {}", a, b)))
             }
             (IntegerValue::I64(a), IntegerValue::I64(b)) => {
                 a.checked_add(b)
                     .map(IntegerValue::I64)
-                    .ok_or_else(|| format!("Addition overflow for i64: {} + {}", a, b))
+                    .ok_or_else(|| overflow(format!("Addition overflow for i64: {} + {}", a, b)))
             }
             (IntegerValue::I128(a), IntegerValue::I128(b)) => {
                 a.checked_add(b)
                     .map(IntegerValue::I128)
-                    .ok_or_else(|| format!("Addition overflow for i128: {} + {}", a, b))
+                    .ok_or_else(|| overflow(format!("Addition overflow for i128: {} + {}", a, b)))
             }
             (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => {
                 let result = a + b;
                 Ok(IntegerValue::BigInt(result))
             }
-            _ => Err("Type mismatch in addition".to_string()),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in addition")),
+        }
+    }
+}
+
+// 实现取负操作
+impl Neg for IntegerValue {
+    type Output = Result<IntegerValue, Diagnostic>;
+
+    // 保持位宽不变，各类型的最小值取负会溢出
+    fn neg(self) -> Self::Output {
+        match self {
+            IntegerValue::I8(a) => a.checked_neg()
+                .map(IntegerValue::I8)
+                .ok_or_else(|| overflow(format!("Negation overflow for i8: -({})", a))),
+            IntegerValue::I16(a) => a.checked_neg()
+                .map(IntegerValue::I16)
+                .ok_or_else(|| overflow(format!("Negation overflow for i16: -({})", a))),
+            IntegerValue::I32(a) => a.checked_neg()
+                .map(IntegerValue::I32)
+                .ok_or_else(|| overflow(format!("Negation overflow for i32: -({})", a))),
+            IntegerValue::I64(a) => a.checked_neg()
+                .map(IntegerValue::I64)
+                .ok_or_else(|| overflow(format!("Negation overflow for i64: -({})", a))),
+            IntegerValue::I128(a) => a.checked_neg()
+                .map(IntegerValue::I128)
+                .ok_or_else(|| overflow(format!("Negation overflow for i128: -({})", a))),
+            IntegerValue::BigInt(a) => Ok(IntegerValue::BigInt(-a)),
         }
     }
 }
 
 // 实现减法操作
 impl Sub for IntegerValue {
-    type Output = Result<IntegerValue, String>;
+    type Output = Result<IntegerValue, Diagnostic>;
 
     fn sub(self, rhs: Self) -> Self::Output {
         let target_type = IntegerValue::promote_type(&self, &rhs);
@@ -481,40 +1044,40 @@ impl Sub for IntegerValue {
             (IntegerValue::I8(a), IntegerValue::I8(b)) => {
                 a.checked_sub(b)
                     .map(IntegerValue::I8)
-                    .ok_or_else(|| format!("Subtraction overflow for i8: {} - {}", a, b))
+                    .ok_or_else(|| overflow(format!("Subtraction overflow for i8: {} - {}", a, b)))
             }
             (IntegerValue::I16(a), IntegerValue::I16(b)) => {
                 a.checked_sub(b)
                     .map(IntegerValue::I16)
-                    .ok_or_else(|| format!("Subtraction overflow for i16: {} - {}", a, b))
+                    .ok_or_else(|| overflow(format!("Subtraction overflow for i16: {} - {}", a, b)))
             }
             (IntegerValue::I32(a), IntegerValue::I32(b)) => {
                 a.checked_sub(b)
                     .map(IntegerValue::I32)
-                    .ok_or_else(|| format!("Subtraction overflow for i32: {} - {}", a, b))
+                    .ok_or_else(|| overflow(format!("Subtraction overflow for i32: {} - {}", a, b)))
             }
             (IntegerValue::I64(a), IntegerValue::I64(b)) => {
                 a.checked_sub(b)
                     .map(IntegerValue::I64)
-                    .ok_or_else(|| format!("Subtraction overflow for i64: {} - {}", a, b))
+                    .ok_or_else(|| overflow(format!("Subtraction overflow for i64: {} - {}", a, b)))
             }
             (IntegerValue::I128(a), IntegerValue::I128(b)) => {
                 a.checked_sub(b)
                     .map(IntegerValue::I128)
-                    .ok_or_else(|| format!("Subtraction overflow for i128: {} - {}", a, b))
+                    .ok_or_else(|| overflow(format!("Subtraction overflow for i128: {} - {}", a, b)))
             }
             (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => {
                 let result = a - b;
                 Ok(IntegerValue::BigInt(result))
             }
-            _ => Err("Type mismatch in subtraction".to_string()),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in subtraction")),
         }
     }
 }
 
 // 实现乘法操作
 impl Mul for IntegerValue {
-    type Output = Result<IntegerValue, String>;
+    type Output = Result<IntegerValue, Diagnostic>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         let target_type = IntegerValue::promote_type(&self, &rhs);
@@ -581,14 +1144,14 @@ impl Mul for IntegerValue {
                 let result = a * b;
                 Ok(IntegerValue::BigInt(result))
             }
-            _ => Err("Type mismatch in multiplication".to_string()),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in multiplication")),
         }
     }
 }
 
 // 实现除法操作
 impl Div for IntegerValue {
-    type Output = Result<IntegerValue, String>;
+    type Output = Result<IntegerValue, Diagnostic>;
 
     fn div(self, rhs: Self) -> Self::Output {
         let target_type = IntegerValue::promote_type(&self, &rhs);
@@ -598,104 +1161,311 @@ impl Div for IntegerValue {
         match (a, b) {
             (IntegerValue::I8(a), IntegerValue::I8(b)) => {
                 if b == 0 {
-                    return Err("Division by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero"));
                 }
                 a.checked_div(b)
                     .map(IntegerValue::I8)
-                    .ok_or_else(|| format!("Division overflow for i8: {} / {}", a, b))
+                    .ok_or_else(|| overflow(format!("Division overflow for i8: {} / {}", a, b)))
             }
             (IntegerValue::I16(a), IntegerValue::I16(b)) => {
                 if b == 0 {
-                    return Err("Division by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero"));
                 }
                 a.checked_div(b)
                     .map(IntegerValue::I16)
-                    .ok_or_else(|| format!("Division overflow for i16: {} / {}", a, b))
+                    .ok_or_else(|| overflow(format!("Division overflow for i16: {} / {}", a, b)))
             }
             (IntegerValue::I32(a), IntegerValue::I32(b)) => {
                 if b == 0 {
-                    return Err("Division by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero"));
                 }
                 a.checked_div(b)
                     .map(IntegerValue::I32)
-                    .ok_or_else(|| format!("Division overflow for i32: {} / {}", a, b))
+                    .ok_or_else(|| overflow(format!("Division overflow for i32: {} / {}", a, b)))
             }
             (IntegerValue::I64(a), IntegerValue::I64(b)) => {
                 if b == 0 {
-                    return Err("Division by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero"));
                 }
                 a.checked_div(b)
                     .map(IntegerValue::I64)
-                    .ok_or_else(|| format!("Division overflow for i64: {} / {}", a, b))
+                    .ok_or_else(|| overflow(format!("Division overflow for i64: {} / {}", a, b)))
             }
             (IntegerValue::I128(a), IntegerValue::I128(b)) => {
                 if b == 0 {
-                    return Err("Division by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero"));
                 }
                 a.checked_div(b)
                     .map(IntegerValue::I128)
-                    .ok_or_else(|| format!("Division overflow for i128: {} / {}", a, b))
+                    .ok_or_else(|| overflow(format!("Division overflow for i128: {} / {}", a, b)))
             }
             (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => {
                 if b == BigInt::from(0) {
-                    return Err("Division by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero"));
                 }
                 let result = a / b;
                 Ok(IntegerValue::BigInt(result))
             }
-            _ => Err("Type mismatch in division".to_string()),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in division")),
         }
     }
 }
 
 // 实现取模操作
 impl Rem for IntegerValue {
-    type Output = Result<IntegerValue, String>;
+    type Output = Result<IntegerValue, Diagnostic>;
 
     fn rem(self, rhs: Self) -> Self::Output {
         let target_type = IntegerValue::promote_type(&self, &rhs);
         let a = self.cast_to(&target_type)?;
         let b = rhs.cast_to(&target_type)?;
 
+        // MIN % -1 的数学结果是 0，wrapping_rem 正好给出它而不会 panic
         match (a, b) {
             (IntegerValue::I8(a), IntegerValue::I8(b)) => {
                 if b == 0 {
-                    return Err("Modulo by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Modulo by zero"));
                 }
-                Ok(IntegerValue::I8(a % b))
+                Ok(IntegerValue::I8(a.wrapping_rem(b)))
             }
             (IntegerValue::I16(a), IntegerValue::I16(b)) => {
                 if b == 0 {
-                    return Err("Modulo by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Modulo by zero"));
                 }
-                Ok(IntegerValue::I16(a % b))
+                Ok(IntegerValue::I16(a.wrapping_rem(b)))
             }
             (IntegerValue::I32(a), IntegerValue::I32(b)) => {
                 if b == 0 {
-                    return Err("Modulo by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Modulo by zero"));
                 }
-                Ok(IntegerValue::I32(a % b))
+                Ok(IntegerValue::I32(a.wrapping_rem(b)))
             }
             (IntegerValue::I64(a), IntegerValue::I64(b)) => {
                 if b == 0 {
-                    return Err("Modulo by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Modulo by zero"));
                 }
-                Ok(IntegerValue::I64(a % b))
+                Ok(IntegerValue::I64(a.wrapping_rem(b)))
             }
             (IntegerValue::I128(a), IntegerValue::I128(b)) => {
                 if b == 0 {
-                    return Err("Modulo by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Modulo by zero"));
                 }
-                Ok(IntegerValue::I128(a % b))
+                Ok(IntegerValue::I128(a.wrapping_rem(b)))
             }
             (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => {
                 if b == BigInt::from(0) {
-                    return Err("Modulo by zero".to_string());
+                    return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Modulo by zero"));
                 }
                 let result = a % b;
                 Ok(IntegerValue::BigInt(result))
             }
-            _ => Err("Type mismatch in modulo operation".to_string()),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in modulo operation")),
+        }
+    }
+}
+
+// 浮点数运算遵循 IEEE 754，只有除以零与整数一样报错
+impl Add for FloatValue {
+    type Output = FloatValue;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        FloatValue(self.0 + rhs.0)
+    }
+}
+
+impl Sub for FloatValue {
+    type Output = FloatValue;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        FloatValue(self.0 - rhs.0)
+    }
+}
+
+impl Mul for FloatValue {
+    type Output = FloatValue;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        FloatValue(self.0 * rhs.0)
+    }
+}
+
+impl Div for FloatValue {
+    type Output = Result<FloatValue, Diagnostic>;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        if rhs.0 == 0.0 {
+            return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Division by zero"));
+        }
+        Ok(FloatValue(self.0 / rhs.0))
+    }
+}
+
+// 余数与被除数同号，和整数的 % 一致
+impl Rem for FloatValue {
+    type Output = Result<FloatValue, Diagnostic>;
+
+    fn rem(self, rhs: Self) -> Self::Output {
+        if rhs.0 == 0.0 {
+            return Err(Diagnostic::new(Stage::Runtime, ErrorKind::DivisionByZero, "Modulo by zero"));
+        }
+        Ok(FloatValue(self.0 % rhs.0))
+    }
+}
+
+impl Neg for FloatValue {
+    type Output = FloatValue;
+
+    fn neg(self) -> Self::Output {
+        FloatValue(-self.0)
+    }
+}
+
+// 实现按位与操作
+impl BitAnd for IntegerValue {
+    type Output = Result<IntegerValue, Diagnostic>;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        let target_type = IntegerValue::promote_type(&self, &rhs);
+        let a = self.cast_to(&target_type)?;
+        let b = rhs.cast_to(&target_type)?;
+
+        match (a, b) {
+            (IntegerValue::I8(a), IntegerValue::I8(b)) => Ok(IntegerValue::I8(a & b)),
+            (IntegerValue::I16(a), IntegerValue::I16(b)) => Ok(IntegerValue::I16(a & b)),
+            (IntegerValue::I32(a), IntegerValue::I32(b)) => Ok(IntegerValue::I32(a & b)),
+            (IntegerValue::I64(a), IntegerValue::I64(b)) => Ok(IntegerValue::I64(a & b)),
+            (IntegerValue::I128(a), IntegerValue::I128(b)) => Ok(IntegerValue::I128(a & b)),
+            (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => Ok(IntegerValue::BigInt(a & b)),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in bitwise and")),
+        }
+    }
+}
+
+// 实现按位或操作
+impl BitOr for IntegerValue {
+    type Output = Result<IntegerValue, Diagnostic>;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        let target_type = IntegerValue::promote_type(&self, &rhs);
+        let a = self.cast_to(&target_type)?;
+        let b = rhs.cast_to(&target_type)?;
+
+        match (a, b) {
+            (IntegerValue::I8(a), IntegerValue::I8(b)) => Ok(IntegerValue::I8(a | b)),
+            (IntegerValue::I16(a), IntegerValue::I16(b)) => Ok(IntegerValue::I16(a | b)),
+            (IntegerValue::I32(a), IntegerValue::I32(b)) => Ok(IntegerValue::I32(a | b)),
+            (IntegerValue::I64(a), IntegerValue::I64(b)) => Ok(IntegerValue::I64(a | b)),
+            (IntegerValue::I128(a), IntegerValue::I128(b)) => Ok(IntegerValue::I128(a | b)),
+            (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => Ok(IntegerValue::BigInt(a | b)),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in bitwise or")),
+        }
+    }
+}
+
+// 实现按位异或操作
+impl BitXor for IntegerValue {
+    type Output = Result<IntegerValue, Diagnostic>;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        let target_type = IntegerValue::promote_type(&self, &rhs);
+        let a = self.cast_to(&target_type)?;
+        let b = rhs.cast_to(&target_type)?;
+
+        match (a, b) {
+            (IntegerValue::I8(a), IntegerValue::I8(b)) => Ok(IntegerValue::I8(a ^ b)),
+            (IntegerValue::I16(a), IntegerValue::I16(b)) => Ok(IntegerValue::I16(a ^ b)),
+            (IntegerValue::I32(a), IntegerValue::I32(b)) => Ok(IntegerValue::I32(a ^ b)),
+            (IntegerValue::I64(a), IntegerValue::I64(b)) => Ok(IntegerValue::I64(a ^ b)),
+            (IntegerValue::I128(a), IntegerValue::I128(b)) => Ok(IntegerValue::I128(a ^ b)),
+            (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => Ok(IntegerValue::BigInt(a ^ b)),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in bitwise xor")),
+        }
+    }
+}
+
+// 实现按位取反操作，各位宽（包括 BigInt）都满足 !x == -x - 1
+impl Not for IntegerValue {
+    type Output = IntegerValue;
+
+    fn not(self) -> Self::Output {
+        match self {
+            IntegerValue::I8(a) => IntegerValue::I8(!a),
+            IntegerValue::I16(a) => IntegerValue::I16(!a),
+            IntegerValue::I32(a) => IntegerValue::I32(!a),
+            IntegerValue::I64(a) => IntegerValue::I64(!a),
+            IntegerValue::I128(a) => IntegerValue::I128(!a),
+            IntegerValue::BigInt(a) => IntegerValue::BigInt(!a),
+        }
+    }
+}
+
+// 移位量超出范围：定长类型要求 0 <= n < 位宽，BigInt 要求 0 <= n <= u32::MAX
+fn shift_out_of_range(amount: impl fmt::Display, type_name: &str) -> Diagnostic {
+    Diagnostic::new(Stage::Runtime, ErrorKind::OutOfRange, format!("Shift amount {} out of range for {}", amount, type_name))
+}
+
+// 实现左移操作，移出的高位直接丢弃
+impl Shl for IntegerValue {
+    type Output = Result<IntegerValue, Diagnostic>;
+
+    fn shl(self, rhs: Self) -> Self::Output {
+        let target_type = IntegerValue::promote_type(&self, &rhs);
+        let a = self.cast_to(&target_type)?;
+        let b = rhs.cast_to(&target_type)?;
+
+        match (a, b) {
+            (IntegerValue::I8(a), IntegerValue::I8(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shl(n))
+                .map(IntegerValue::I8)
+                .ok_or_else(|| shift_out_of_range(b, "i8")),
+            (IntegerValue::I16(a), IntegerValue::I16(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shl(n))
+                .map(IntegerValue::I16)
+                .ok_or_else(|| shift_out_of_range(b, "i16")),
+            (IntegerValue::I32(a), IntegerValue::I32(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shl(n))
+                .map(IntegerValue::I32)
+                .ok_or_else(|| shift_out_of_range(b, "i32")),
+            (IntegerValue::I64(a), IntegerValue::I64(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shl(n))
+                .map(IntegerValue::I64)
+                .ok_or_else(|| shift_out_of_range(b, "i64")),
+            (IntegerValue::I128(a), IntegerValue::I128(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shl(n))
+                .map(IntegerValue::I128)
+                .ok_or_else(|| shift_out_of_range(b, "i128")),
+            (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => b.to_u32()
+                .map(|n| IntegerValue::BigInt(a << n))
+                .ok_or_else(|| shift_out_of_range(b, "bigint")),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in shift")),
+        }
+    }
+}
+
+// 实现右移操作（算术右移，负数向负无穷取整）
+impl Shr for IntegerValue {
+    type Output = Result<IntegerValue, Diagnostic>;
+
+    fn shr(self, rhs: Self) -> Self::Output {
+        let target_type = IntegerValue::promote_type(&self, &rhs);
+        let a = self.cast_to(&target_type)?;
+        let b = rhs.cast_to(&target_type)?;
+
+        match (a, b) {
+            (IntegerValue::I8(a), IntegerValue::I8(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shr(n))
+                .map(IntegerValue::I8)
+                .ok_or_else(|| shift_out_of_range(b, "i8")),
+            (IntegerValue::I16(a), IntegerValue::I16(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shr(n))
+                .map(IntegerValue::I16)
+                .ok_or_else(|| shift_out_of_range(b, "i16")),
+            (IntegerValue::I32(a), IntegerValue::I32(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shr(n))
+                .map(IntegerValue::I32)
+                .ok_or_else(|| shift_out_of_range(b, "i32")),
+            (IntegerValue::I64(a), IntegerValue::I64(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shr(n))
+                .map(IntegerValue::I64)
+                .ok_or_else(|| shift_out_of_range(b, "i64")),
+            (IntegerValue::I128(a), IntegerValue::I128(b)) => u32::try_from(b).ok().and_then(|n| a.checked_shr(n))
+                .map(IntegerValue::I128)
+                .ok_or_else(|| shift_out_of_range(b, "i128")),
+            (IntegerValue::BigInt(a), IntegerValue::BigInt(b)) => b.to_u32()
+                .map(|n| IntegerValue::BigInt(a >> n))
+                .ok_or_else(|| shift_out_of_range(b, "bigint")),
+            _ => Err(Diagnostic::new(Stage::Runtime, ErrorKind::TypeMismatch, "Type mismatch in shift")),
         }
     }
 }
@@ -781,7 +1551,28 @@ mod tests {
         let a = IntegerValue::from_string(i8::MAX.to_string().as_str(), IntegerType::I8).unwrap();
         let b = IntegerValue::from_string("1", IntegerType::I8).unwrap();
         let result = a + b;
-        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().kind, ErrorKind::Overflow);
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        // 测试除零错误类别
+        let a = IntegerValue::from_string("10", IntegerType::I32).unwrap();
+        let b = IntegerValue::from_string("0", IntegerType::I32).unwrap();
+        let err = (a.clone() / b.clone()).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+        assert_eq!(err.stage, Stage::Runtime);
+        assert_eq!((a % b).unwrap_err().kind, ErrorKind::DivisionByZero);
+
+        // MIN / -1 溢出，MIN % -1 为 0
+        let min = IntegerValue::I8(i8::MIN);
+        let minus_one = IntegerValue::I8(-1);
+        assert_eq!((min.clone() / minus_one.clone()).unwrap_err().kind, ErrorKind::Overflow);
+        assert_eq!((min % minus_one).unwrap(), IntegerValue::I8(0));
+
+        // 测试类型转换越界
+        let big = IntegerValue::from_string("1000", IntegerType::I16).unwrap();
+        assert_eq!(big.to_i8().unwrap_err().kind, ErrorKind::OutOfRange);
     }
 
     #[test]
@@ -805,4 +1596,130 @@ mod tests {
         assert_eq!(result_unwrap.get_type(), IntegerType::I16);
         assert_eq!(result_unwrap.to_i16().unwrap(), 30);
     }
+
+    #[test]
+    fn test_float_promotion_and_equality() {
+        let int = Value::Integer(IntegerValue::I8(3));
+        let float = Value::Float(FloatValue(1.5));
+        let (a, b) = promote_float(&int, &float).unwrap();
+        assert_eq!((a.0, b.0), (3.0, 1.5));
+        assert!(promote_float(&int, &int).is_none());
+        assert!(promote_float(&float, &Value::Bool(true)).is_none());
+
+        assert!(Value::Integer(IntegerValue::I64(2)).equals(&Value::Float(FloatValue(2.0))));
+        assert!(!Value::Float(FloatValue(f64::NAN)).equals(&Value::Float(FloatValue(f64::NAN))));
+        assert!(int > float);
+        let huge = IntegerValue::from_string(&"9".repeat(400), IntegerType::BigInt).unwrap();
+        assert_eq!(huge.to_f64(), f64::INFINITY);
+
+        assert_eq!(Value::Float(FloatValue(1.0)).to_string(), "1.0");
+        assert_eq!(Value::Float(FloatValue(2e-7)).to_string(), "2e-7");
+        assert_eq!((FloatValue(1.0) / FloatValue(0.0)).unwrap_err().kind, ErrorKind::DivisionByZero);
+        assert_eq!((FloatValue(-7.5) % FloatValue(2.0)).unwrap().0, -1.5);
+    }
+
+    #[test]
+    fn test_bitwise_operations() {
+        // 与算术运算一样提升到较宽的类型
+        let result = (IntegerValue::I8(0b1100) & IntegerValue::I32(0b1010)).unwrap();
+        assert_eq!(result, IntegerValue::I32(0b1000));
+        assert_eq!((IntegerValue::I8(0b1100) | IntegerValue::I8(0b1010)).unwrap(), IntegerValue::I8(0b1110));
+        assert_eq!((IntegerValue::I64(-1) ^ IntegerValue::I8(5)).unwrap(), IntegerValue::I64(-6));
+        assert_eq!(!IntegerValue::I16(0), IntegerValue::I16(-1));
+        assert_eq!(!IntegerValue::BigInt(BigInt::from(5)), IntegerValue::BigInt(BigInt::from(-6)));
+
+        assert_eq!((IntegerValue::I8(1) << IntegerValue::I8(7)).unwrap(), IntegerValue::I8(i8::MIN));
+        assert_eq!((IntegerValue::I64(-16) >> IntegerValue::I8(2)).unwrap(), IntegerValue::I64(-4));
+        let big = (IntegerValue::BigInt(BigInt::from(1)) << IntegerValue::I8(100)).unwrap();
+        assert_eq!(big.to_string(), "1267650600228229401496703205376");
+
+        // 移位量必须落在位宽之内
+        let err = (IntegerValue::I8(1) << IntegerValue::I8(8)).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfRange);
+        assert_eq!(err.message, "Shift amount 8 out of range for i8");
+        assert!((IntegerValue::I32(1) >> IntegerValue::I32(-1)).is_err());
+        assert!((IntegerValue::BigInt(BigInt::from(1)) >> IntegerValue::I8(-1)).is_err());
+    }
+
+    #[test]
+    fn test_list_sharing_and_bounds() {
+        let list = Value::List(ListValue::new(vec![Value::Integer(IntegerValue::I8(1)), Value::String(StringValue::new("a".to_string()))]));
+        let alias = list.clone();
+        alias.store_index(&Value::Integer(IntegerValue::I64(0)), Value::Bool(true)).unwrap();
+        assert_eq!(list.to_string(), "[true, \"a\"]");
+
+        // 越界和负数下标都是运行时错误
+        let err = list.index(&Value::Integer(IntegerValue::I8(2))).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::OutOfRange);
+        assert_eq!(err.message, "Index 2 out of range for list of length 2");
+        assert!(list.index(&Value::Integer(IntegerValue::I8(-1))).is_err());
+        assert_eq!(list.index(&Value::Float(FloatValue(0.0))).unwrap_err().kind, ErrorKind::TypeMismatch);
+
+        // 元素按数值比较相等，不区分整数位宽
+        let a = Value::List(ListValue::new(vec![Value::Integer(IntegerValue::I8(1))]));
+        let b = Value::List(ListValue::new(vec![Value::Integer(IntegerValue::I64(1))]));
+        assert!(a.equals(&b));
+    }
+
+    #[test]
+    fn test_self_referencing_lists() {
+        let list = ListValue::new(vec![Value::Integer(IntegerValue::I8(1))]);
+        list.push(Value::List(list.clone()));
+        assert_eq!(list.to_string(), "[1, [...]]");
+        let a = Value::List(list);
+        assert!(a.equals(&a));
+
+        // 结构相同的两个自引用列表相等，长度不同时不相等
+        let other = ListValue::new(vec![Value::Integer(IntegerValue::I64(1))]);
+        other.push(Value::List(other.clone()));
+        let b = Value::List(other.clone());
+        assert!(a.equals(&b));
+        other.push(Value::Bool(true));
+        assert!(!a.equals(&b));
+
+        // 同一个列表出现两次但不构成环时照常输出
+        let inner = Value::List(ListValue::new(vec![Value::Integer(IntegerValue::I8(2))]));
+        assert_eq!(ListValue::new(vec![inner.clone(), inner]).to_string(), "[[2], [2]]");
+    }
+
+    #[test]
+    fn test_map_keys_and_order() {
+        let map = MapValue::new();
+        let key = |s: &str| Value::String(StringValue::new(s.to_string()));
+        map.insert(key("b"), Value::Integer(IntegerValue::I8(1))).unwrap();
+        map.insert(Value::Integer(IntegerValue::I8(7)), Value::Bool(true)).unwrap();
+        map.insert(key("a"), Value::Integer(IntegerValue::I8(2))).unwrap();
+
+        // 整数键按数值查找，不区分位宽
+        assert_eq!(map.get(&Value::Integer(IntegerValue::I64(7))).unwrap(), Value::Bool(true));
+        map.insert(Value::Integer(IntegerValue::I32(7)), Value::Bool(false)).unwrap();
+        assert_eq!(map.len(), 3);
+
+        // 删除后其余的键保持插入顺序
+        assert_eq!(map.remove(&key("b")).unwrap(), Value::Integer(IntegerValue::I8(1)));
+        map.insert(key("b"), Value::Integer(IntegerValue::I8(3))).unwrap();
+        assert_eq!(Value::Map(map.clone()).to_string(), "{7: false, \"a\": 2, \"b\": 3}");
+
+        let err = map.get(&key("z")).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::KeyNotFound);
+        assert_eq!(err.message, "Key \"z\" not found in map");
+        assert_eq!(map.insert(Value::Float(FloatValue(1.0)), Value::Bool(true)).unwrap_err().kind, ErrorKind::TypeMismatch);
+    }
+
+    #[test]
+    fn test_self_referencing_maps() {
+        let map = MapValue::new();
+        map.insert(Value::Integer(IntegerValue::I8(1)), Value::Map(map.clone())).unwrap();
+        map.insert(Value::Integer(IntegerValue::I8(2)), Value::List(ListValue::new(vec![Value::Map(map.clone())]))).unwrap();
+        assert_eq!(map.to_string(), "{1: {...}, 2: [{...}]}");
+        let a = Value::Map(map);
+        assert!(a.equals(&a));
+
+        let other = MapValue::new();
+        other.insert(Value::Integer(IntegerValue::I64(2)), Value::List(ListValue::new(vec![Value::Map(other.clone())]))).unwrap();
+        other.insert(Value::Integer(IntegerValue::I64(1)), Value::Map(other.clone())).unwrap();
+        assert!(a.equals(&Value::Map(other.clone())));
+        other.insert(Value::Integer(IntegerValue::I64(1)), Value::Bool(true)).unwrap();
+        assert!(!a.equals(&Value::Map(other)));
+    }
 }